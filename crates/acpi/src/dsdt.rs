@@ -0,0 +1,231 @@
+//! Minimal scan of the DSDT's AML for the `\_S5_` sleep package.
+//!
+//! Full AML interpretation is out of scope; the `\_S5_` object is a plain
+//! `Name(_S5, Package() { SLP_TYPa, SLP_TYPb, ... })` on every firmware we
+//! run on, which this recognizes byte by byte.
+use crate::{SdtHeader, read, read_bytes};
+
+const NAME_OP: u8 = 0x08;
+const PACKAGE_OP: u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ONES_OP: u8 = 0xff;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const QWORD_PREFIX: u8 = 0x0e;
+
+/// `SLP_TYP` values to write to PM1a/PM1b control to enter a sleep state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SleepType {
+    pub pm1a: u8,
+    pub pm1b: u8,
+}
+
+/// Find the S5 (soft off) sleep type in the DSDT at `dsdt`.
+pub(crate) fn find_s5(dsdt: u64) -> Option<SleepType> {
+    let header: SdtHeader = SdtHeader::read(dsdt);
+    if &header.signature != b"DSDT" || !header.checksum_valid {
+        return None;
+    }
+    let end: u64 = dsdt + header.length as u64;
+
+    let mut addr: u64 = dsdt + SdtHeader::SIZE;
+    while addr + 4 <= end {
+        if &read_bytes::<4>(addr) == b"_S5_"
+            && is_name_op(addr)
+            && let Some(sleep_type) = parse_package(addr + 4, end)
+        {
+            return Some(sleep_type);
+        }
+        addr += 1;
+    }
+    None
+}
+
+/// The name is preceded by `NameOp`, optionally with a root prefix `\`.
+fn is_name_op(name: u64) -> bool {
+    read::<u8>(name - 1) == NAME_OP
+        || (read::<u8>(name - 1) == b'\\' && read::<u8>(name - 2) == NAME_OP)
+}
+
+/// Parse `Package(...) { SLP_TYPa, SLP_TYPb, ... }` at `addr`.
+///
+/// Some firmware packs both values into a single integer (`SLP_TYPa` in the
+/// low byte, `SLP_TYPb` in the next), which is accepted as well.
+fn parse_package(mut addr: u64, end: u64) -> Option<SleepType> {
+    if addr >= end {
+        return None;
+    }
+    let opcode: u8 = read::<u8>(addr);
+    if opcode != PACKAGE_OP && opcode != VAR_PACKAGE_OP {
+        return None;
+    }
+    addr += 1;
+    let package_start: u64 = addr;
+    let end: u64 = end.min(package_start + parse_pkg_length(&mut addr, end)?);
+    // Package has a ByteData element count, VarPackage a TermArg.
+    let elements: u64 = if opcode == PACKAGE_OP {
+        if addr >= end {
+            return None;
+        }
+        addr += 1;
+        read::<u8>(addr - 1) as u64
+    } else {
+        parse_integer(&mut addr, end)?
+    };
+
+    match elements {
+        0 => None,
+        1 => {
+            let packed: u64 = parse_integer(&mut addr, end)?;
+            Some(SleepType {
+                pm1a: packed as u8,
+                pm1b: (packed >> 8) as u8,
+            })
+        }
+        _ => {
+            let pm1a: u64 = parse_integer(&mut addr, end)?;
+            let pm1b: u64 = parse_integer(&mut addr, end)?;
+            Some(SleepType {
+                pm1a: pm1a as u8,
+                pm1b: pm1b as u8,
+            })
+        }
+    }
+}
+
+/// Decode a PkgLength at `addr`, which counts itself and the rest of the
+/// object. Bits 6-7 of the lead byte give the number of bytes that follow;
+/// with follow bytes, only bits 0-3 of the lead byte belong to the length.
+fn parse_pkg_length(addr: &mut u64, end: u64) -> Option<u64> {
+    if *addr >= end {
+        return None;
+    }
+    let lead: u8 = read::<u8>(*addr);
+    let follow: u64 = (lead >> 6) as u64;
+    if *addr + 1 + follow > end {
+        return None;
+    }
+    let length: u64 = if follow == 0 {
+        (lead & 0x3f) as u64
+    } else {
+        (1..=follow).fold((lead & 0x0f) as u64, |length, i| {
+            length | (read::<u8>(*addr + i) as u64) << (4 + 8 * (i - 1))
+        })
+    };
+    *addr += 1 + follow;
+    Some(length)
+}
+
+/// Decode a constant integer (`Zero`, `One`, `Ones` or a Byte/Word/DWord/
+/// QWord constant) that ends before `end`.
+fn parse_integer(addr: &mut u64, end: u64) -> Option<u64> {
+    if *addr >= end {
+        return None;
+    }
+    let (value, size): (u64, u64) = match read::<u8>(*addr) {
+        ZERO_OP => (0, 1),
+        ONE_OP => (1, 1),
+        ONES_OP => (u64::MAX, 1),
+        BYTE_PREFIX if *addr + 2 <= end => (read::<u8>(*addr + 1) as u64, 2),
+        WORD_PREFIX if *addr + 3 <= end => (read::<u16>(*addr + 1) as u64, 3),
+        DWORD_PREFIX if *addr + 5 <= end => (read::<u32>(*addr + 1) as u64, 5),
+        QWORD_PREFIX if *addr + 9 <= end => (read::<u64>(*addr + 1), 9),
+        _ => return None,
+    };
+    *addr += size;
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{SleepType, find_s5};
+    use crate::tests::{phys, table};
+
+    fn s5(aml: &[u8]) -> Option<SleepType> {
+        let bytes: Vec<u8> = table(b"DSDT", aml);
+        find_s5(phys(&bytes))
+    }
+
+    fn sleep_type(pm1a: u8, pm1b: u8) -> Option<SleepType> {
+        Some(SleepType { pm1a, pm1b })
+    }
+
+    #[test]
+    fn byte_prefixed_values() {
+        // Name (_S5, Package (0x04) { 0x05, 0x05, Zero, Zero })
+        let aml: [u8; 14] = [
+            0x08, b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0a, 0x05, 0x0a, 0x05, 0x00, 0x00,
+        ];
+        assert_eq!(s5(&aml), sleep_type(5, 5));
+    }
+
+    #[test]
+    fn root_prefixed_name_with_zero_and_one() {
+        // Name (\_S5, Package (0x02) { Zero, One })
+        let aml: [u8; 11] = [
+            0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x04, 0x02, 0x00, 0x01,
+        ];
+        assert_eq!(s5(&aml), sleep_type(0, 1));
+    }
+
+    #[test]
+    fn multi_byte_package_length() {
+        // PkgLength 0x46 in two bytes: lead 0x46 & 0x0f = 6, follow byte 4.
+        let mut aml: Vec<u8> = Vec::from([0x08, b'_', b'S', b'5', b'_', 0x12, 0x46, 0x04, 0x02]);
+        aml.extend_from_slice(&[0x0a, 0x07, 0x0a, 0x03]);
+        aml.resize(5 + 1 + 0x46, 0x00);
+        assert_eq!(s5(&aml), sleep_type(7, 3));
+    }
+
+    #[test]
+    fn word_constants_and_var_package() {
+        // Name (_S5, VarPackage (0x02) { 0x0005, 0x0006 })
+        let aml: [u8; 15] = [
+            0x08, b'_', b'S', b'5', b'_', 0x13, 0x09, 0x0a, 0x02, 0x0b, 0x05, 0x00, 0x0b, 0x06,
+            0x00,
+        ];
+        assert_eq!(s5(&aml), sleep_type(5, 6));
+    }
+
+    #[test]
+    fn packed_single_element() {
+        // Name (_S5, Package (0x01) { 0x0705 })
+        let aml: [u8; 11] = [
+            0x08, b'_', b'S', b'5', b'_', 0x12, 0x05, 0x01, 0x0b, 0x05, 0x07,
+        ];
+        assert_eq!(s5(&aml), sleep_type(5, 7));
+    }
+
+    #[test]
+    fn skips_references_that_are_not_the_definition() {
+        // A store from \_S5_ (no NameOp), then the real definition.
+        let aml: [u8; 17] = [
+            0x70, b'_', b'S', b'5', b'_', 0x60, 0x08, b'_', b'S', b'5', b'_', 0x12, 0x05, 0x02,
+            0x0a, 0x05, 0x00,
+        ];
+        assert_eq!(s5(&aml), sleep_type(5, 0));
+    }
+
+    #[test]
+    fn truncated_package() {
+        // The package claims two elements but the table ends inside the
+        // second one.
+        let aml: [u8; 11] = [
+            0x08, b'_', b'S', b'5', b'_', 0x12, 0x06, 0x02, 0x0a, 0x05, 0x0a,
+        ];
+        assert_eq!(s5(&aml), None);
+    }
+
+    #[test]
+    fn missing_s5() {
+        assert_eq!(
+            s5(&[0x08, b'_', b'S', b'4', b'_', 0x12, 0x04, 0x02, 0x00, 0x00]),
+            None
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Fixed ACPI Description Table (signature `FACP`).
+//!
+//! Only the fields the kernel uses for power management and timekeeping are
+//! kept. Fields past the end of an older, shorter FADT read as zero.
+use crate::{AcpiError, GenericAddress, SdtHeader, read};
+
+/// FADT flags: the PM timer is 32 bits wide instead of 24.
+const TMR_VAL_EXT: u32 = 1 << 8;
+/// FADT flags: `reset_register` is supported.
+const RESET_REG_SUP: u32 = 1 << 10;
+/// IA-PC boot architecture flags: an 8042 keyboard controller is present.
+const BOOT_ARCH_8042: u16 = 1 << 1;
+
+// Offsets of the 64-bit extended fields added in ACPI 2.0.
+const RESET_REGISTER_OFFSET: u64 = 116;
+const X_DSDT_OFFSET: u64 = 140;
+const X_PM1A_CONTROL_OFFSET: u64 = 172;
+
+#[derive(Debug, Clone)]
+pub struct Fadt {
+    /// Physical address of the DSDT (the extended address when present).
+    pub dsdt: u64,
+    /// ISA IRQ the SCI is wired to.
+    pub sci_interrupt: u16,
+    /// I/O port used to hand ACPI over from SMM (0 if always in ACPI mode).
+    pub smi_command: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    /// PM1a control register I/O port (carries `SLP_TYP`/`SLP_EN`).
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm1_control_length: u8,
+    /// ACPI PM timer I/O port (3.579545 MHz), 0 if absent.
+    pub pm_timer_block: u32,
+    pub pm_timer_length: u8,
+    /// CMOS RTC register holding the century, 0 if not provided.
+    pub century: u8,
+    pub boot_arch_flags: u16,
+    pub flags: u32,
+    pub reset_register: GenericAddress,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    pub(crate) fn parse(address: u64, header: &SdtHeader) -> Result<Self, AcpiError> {
+        let length: u64 = header.length as u64;
+        if length < RESET_REGISTER_OFFSET {
+            return Err(AcpiError::Truncated(header.signature));
+        }
+        let field = |offset: u64, size: u64| offset + size <= length;
+
+        let mut dsdt: u64 = read::<u32>(address + 40) as u64;
+        if field(X_DSDT_OFFSET, 8) && read::<u64>(address + X_DSDT_OFFSET) != 0 {
+            dsdt = read::<u64>(address + X_DSDT_OFFSET);
+        }
+        let mut pm1a_control_block: u32 = read::<u32>(address + 64);
+        if pm1a_control_block == 0 && field(X_PM1A_CONTROL_OFFSET, GenericAddress::SIZE) {
+            pm1a_control_block =
+                GenericAddress::read(address + X_PM1A_CONTROL_OFFSET).address as u32;
+        }
+        let (reset_register, reset_value) = if field(RESET_REGISTER_OFFSET, 13) {
+            (
+                GenericAddress::read(address + RESET_REGISTER_OFFSET),
+                read::<u8>(address + 128),
+            )
+        } else {
+            (GenericAddress::absent(), 0)
+        };
+
+        Ok(Fadt {
+            dsdt,
+            sci_interrupt: read::<u16>(address + 46),
+            smi_command: read::<u32>(address + 48),
+            acpi_enable: read::<u8>(address + 52),
+            acpi_disable: read::<u8>(address + 53),
+            pm1a_event_block: read::<u32>(address + 56),
+            pm1a_control_block,
+            pm1b_control_block: read::<u32>(address + 68),
+            pm1_control_length: read::<u8>(address + 89),
+            pm_timer_block: read::<u32>(address + 76),
+            pm_timer_length: read::<u8>(address + 91),
+            century: read::<u8>(address + 108),
+            boot_arch_flags: read::<u16>(address + 109),
+            flags: read::<u32>(address + 112),
+            reset_register,
+            reset_value,
+        })
+    }
+
+    /// The reset register and the value to write to it, if the firmware
+    /// supports resetting through it.
+    pub fn reset_command(&self) -> Option<(GenericAddress, u8)> {
+        (self.flags & RESET_REG_SUP != 0 && self.reset_register.is_present())
+            .then_some((self.reset_register, self.reset_value))
+    }
+
+    /// True unless the firmware says there is no 8042 keyboard controller.
+    /// ACPI 1.0 FADTs leave the flags at zero, so zero counts as present.
+    pub fn has_8042(&self) -> bool {
+        self.boot_arch_flags == 0 || self.boot_arch_flags & BOOT_ARCH_8042 != 0
+    }
+
+    /// True if the PM timer counts 32 bits instead of 24.
+    pub fn pm_timer_is_32bit(&self) -> bool {
+        self.flags & TMR_VAL_EXT != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::{RESET_REG_SUP, RESET_REGISTER_OFFSET, X_DSDT_OFFSET, X_PM1A_CONTROL_OFFSET};
+    use crate::tests::{phys, table};
+    use crate::{AcpiError, Fadt, SdtHeader};
+
+    /// Length of an ACPI 1.0 FADT.
+    const FADT_V1_LEN: usize = 116;
+    /// Length of an ACPI 2.0 FADT.
+    const FADT_V2_LEN: usize = 244;
+
+    /// Store `value` at table offset `offset` of a FADT body.
+    fn put(body: &mut [u8], offset: u64, value: &[u8]) {
+        let start: usize = (offset - SdtHeader::SIZE) as usize;
+        body[start..start + value.len()].copy_from_slice(value);
+    }
+
+    fn parse(body: &[u8]) -> Result<Fadt, AcpiError> {
+        let bytes: Vec<u8> = table(b"FACP", body);
+        let header: SdtHeader = SdtHeader::read(phys(&bytes));
+        assert!(header.checksum_valid);
+        Fadt::parse(header.address, &header)
+    }
+
+    #[test]
+    fn truncated_fadt_is_rejected() {
+        let body: Vec<u8> = vec![0; FADT_V1_LEN - SdtHeader::SIZE as usize - 1];
+        assert_eq!(parse(&body).unwrap_err(), AcpiError::Truncated(*b"FACP"));
+    }
+
+    #[test]
+    fn acpi_1_fadt_has_no_reset_register() {
+        let mut body: Vec<u8> = vec![0; FADT_V1_LEN - SdtHeader::SIZE as usize];
+        put(&mut body, 40, &0x1234_5678u32.to_le_bytes());
+        put(&mut body, 46, &9u16.to_le_bytes());
+        put(&mut body, 64, &0x604u32.to_le_bytes());
+        put(&mut body, 112, &RESET_REG_SUP.to_le_bytes());
+
+        let fadt: Fadt = parse(&body).unwrap();
+        assert_eq!(fadt.dsdt, 0x1234_5678);
+        assert_eq!(fadt.sci_interrupt, 9);
+        assert_eq!(fadt.pm1a_control_block, 0x604);
+        assert!(fadt.reset_command().is_none());
+        assert!(fadt.has_8042());
+    }
+
+    #[test]
+    fn acpi_2_fadt_prefers_the_extended_fields() {
+        let mut body: Vec<u8> = vec![0; FADT_V2_LEN - SdtHeader::SIZE as usize];
+        put(&mut body, 40, &0x1000u32.to_le_bytes());
+        put(&mut body, X_DSDT_OFFSET, &0x1_0000_2000u64.to_le_bytes());
+        put(
+            &mut body,
+            X_PM1A_CONTROL_OFFSET + 4,
+            &0xb004u64.to_le_bytes(),
+        );
+        put(&mut body, 112, &RESET_REG_SUP.to_le_bytes());
+        // System I/O port 0xcf9, reset value 6.
+        put(&mut body, RESET_REGISTER_OFFSET, &[1, 8, 0, 1]);
+        put(
+            &mut body,
+            RESET_REGISTER_OFFSET + 4,
+            &0xcf9u64.to_le_bytes(),
+        );
+        put(&mut body, 128, &[6]);
+
+        let fadt: Fadt = parse(&body).unwrap();
+        assert_eq!(fadt.dsdt, 0x1_0000_2000);
+        assert_eq!(fadt.pm1a_control_block, 0xb004);
+        let (register, value) = fadt.reset_command().unwrap();
+        assert_eq!(register.space, crate::AddressSpace::SystemIo);
+        assert_eq!(register.address, 0xcf9);
+        assert_eq!(value, 6);
+    }
+}
@@ -0,0 +1,50 @@
+//! High Precision Event Timer description table (signature `HPET`).
+use crate::{AcpiError, GenericAddress, SdtHeader, read};
+
+const COMPARATOR_COUNT_SHIFT: u32 = 8;
+const COMPARATOR_COUNT_MASK: u32 = 0x1f;
+/// Event timer block id: the main counter is 64 bits wide.
+const COUNTER_64BIT: u32 = 1 << 13;
+/// Event timer block id: the HPET can replace the PIT and RTC interrupts.
+const LEGACY_REPLACEMENT: u32 = 1 << 15;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    pub event_timer_block_id: u32,
+    /// Where the HPET registers are mapped (system memory).
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// Minimum periodic tick in main counter clocks.
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+impl Hpet {
+    const LEN: u64 = 56;
+
+    pub(crate) fn parse(address: u64, header: &SdtHeader) -> Result<Self, AcpiError> {
+        if (header.length as u64) < Self::LEN {
+            return Err(AcpiError::Truncated(header.signature));
+        }
+        Ok(Hpet {
+            event_timer_block_id: read::<u32>(address + 36),
+            base_address: GenericAddress::read(address + 40),
+            hpet_number: read::<u8>(address + 52),
+            minimum_tick: read::<u16>(address + 53),
+            page_protection: read::<u8>(address + 55),
+        })
+    }
+
+    /// Number of comparators (timers) in the block.
+    pub fn comparator_count(&self) -> u32 {
+        ((self.event_timer_block_id >> COMPARATOR_COUNT_SHIFT) & COMPARATOR_COUNT_MASK) + 1
+    }
+
+    pub fn counter_is_64bit(&self) -> bool {
+        self.event_timer_block_id & COUNTER_64BIT != 0
+    }
+
+    pub fn supports_legacy_replacement(&self) -> bool {
+        self.event_timer_block_id & LEGACY_REPLACEMENT != 0
+    }
+}
@@ -0,0 +1,322 @@
+#![no_std]
+
+//! ACPI table discovery.
+//!
+//! `init` follows the RSDP handed over by the bootloader to the RSDT/XSDT,
+//! validates checksums and parses the tables the kernel uses (MADT, FADT,
+//! HPET, MCFG). Tables are read through the bootloader's physical memory
+//! mapping.
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::Once;
+
+mod dsdt;
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+mod sdt;
+
+pub use dsdt::SleepType;
+pub use fadt::Fadt;
+pub use hpet::Hpet;
+pub use madt::{
+    InterruptSourceOverride, IoApic, LocalApic, LocalApicNmi, Madt, Polarity, TriggerMode,
+};
+pub use mcfg::{Mcfg, McfgEntry};
+pub use sdt::{AddressSpace, GenericAddress, SdtHeader};
+
+/// Errors reported while locating the ACPI tables.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// The RSDP does not start with `"RSD PTR "`.
+    BadRsdpSignature,
+    /// The bytes of the RSDP do not sum to zero.
+    BadRsdpChecksum,
+    /// The root table does not carry the expected `RSDT`/`XSDT` signature.
+    BadRootSignature,
+    /// The bytes of a table do not sum to zero.
+    BadChecksum([u8; 4]),
+    /// A table is shorter than its own header claims.
+    Truncated([u8; 4]),
+}
+
+/// Tables discovered at boot.
+#[derive(Debug)]
+pub struct AcpiTables {
+    /// ACPI revision from the RSDP (0 for ACPI 1.0, 2 and up otherwise).
+    pub revision: u8,
+    /// OEM id from the RSDP.
+    pub oem_id: [u8; 6],
+    /// The RSDT or XSDT itself.
+    pub root: SdtHeader,
+    /// Every table listed by the root table, including ones that failed
+    /// validation or are not parsed.
+    pub headers: Vec<SdtHeader>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Option<Mcfg>,
+    /// `SLP_TYP` values for soft off, from the DSDT's `\_S5_` object.
+    pub s5_sleep_type: Option<SleepType>,
+}
+
+static TABLES: Once<AcpiTables> = Once::new();
+static PHYSICAL_MEMORY_OFFSET: Once<u64> = Once::new();
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Bytes covered by the ACPI 1.0 RSDP checksum.
+const RSDP_V1_LEN: u64 = 20;
+
+/// Parse the ACPI tables starting at the RSDP at physical address
+/// `rsdp_addr`. `phys_offset` is where the bootloader mapped physical memory.
+///
+/// Tables with a bad checksum or a truncated body are listed in
+/// `AcpiTables::headers` but not parsed; a bad RSDP or root table fails the
+/// whole discovery.
+pub fn init(rsdp_addr: u64, phys_offset: u64) -> Result<&'static AcpiTables, AcpiError> {
+    PHYSICAL_MEMORY_OFFSET.call_once(|| phys_offset);
+
+    if &read_bytes::<8>(rsdp_addr) != RSDP_SIGNATURE {
+        return Err(AcpiError::BadRsdpSignature);
+    }
+    if checksum(rsdp_addr, RSDP_V1_LEN) != 0 {
+        return Err(AcpiError::BadRsdpChecksum);
+    }
+    let revision: u8 = read::<u8>(rsdp_addr + 15);
+    // ACPI 2.0+ RSDPs add the 64-bit XSDT address at offset 24, covered by a
+    // second checksum over the extended length.
+    let use_xsdt: bool = revision >= 2 && read::<u64>(rsdp_addr + 24) != 0;
+    if use_xsdt && checksum(rsdp_addr, read::<u32>(rsdp_addr + 20) as u64) != 0 {
+        return Err(AcpiError::BadRsdpChecksum);
+    }
+    let (root_addr, entry_size): (u64, u64) = if use_xsdt {
+        (read::<u64>(rsdp_addr + 24), 8)
+    } else {
+        (read::<u32>(rsdp_addr + 16) as u64, 4)
+    };
+
+    let root: SdtHeader = SdtHeader::read(root_addr);
+    let expected: &[u8; 4] = if use_xsdt { b"XSDT" } else { b"RSDT" };
+    if &root.signature != expected {
+        return Err(AcpiError::BadRootSignature);
+    }
+    if !root.checksum_valid {
+        return Err(AcpiError::BadChecksum(root.signature));
+    }
+    let entries_len: u64 = root
+        .data_len()
+        .ok_or(AcpiError::Truncated(root.signature))?;
+
+    let mut tables = AcpiTables {
+        revision,
+        oem_id: read_bytes::<6>(rsdp_addr + 9),
+        root,
+        headers: Vec::new(),
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: None,
+        s5_sleep_type: None,
+    };
+    for index in 0..entries_len / entry_size {
+        let entry: u64 = root_addr + SdtHeader::SIZE + index * entry_size;
+        let addr: u64 = if entry_size == 8 {
+            read::<u64>(entry)
+        } else {
+            read::<u32>(entry) as u64
+        };
+        let header: SdtHeader = SdtHeader::read(addr);
+        tables.headers.push(header);
+        if !header.checksum_valid {
+            continue;
+        }
+        match &header.signature {
+            b"APIC" => tables.madt = Madt::parse(addr, &header).ok(),
+            b"FACP" => tables.fadt = Fadt::parse(addr, &header).ok(),
+            b"HPET" => tables.hpet = Hpet::parse(addr, &header).ok(),
+            b"MCFG" => tables.mcfg = Mcfg::parse(addr, &header).ok(),
+            _ => {}
+        }
+    }
+
+    tables.s5_sleep_type = tables
+        .fadt
+        .as_ref()
+        .and_then(|fadt| dsdt::find_s5(fadt.dsdt));
+
+    Ok(TABLES.call_once(|| tables))
+}
+
+/// Tables found by `init`, or `None` if ACPI was not initialized.
+pub fn tables() -> Option<&'static AcpiTables> {
+    TABLES.get()
+}
+
+/// Dump the discovered tables and a summary of the parsed ones.
+pub fn dump_tables(console: &mut impl Write) {
+    let Some(tables) = tables() else {
+        writeln!(console, "ACPI not available").ok();
+        return;
+    };
+
+    writeln!(
+        console,
+        "== ACPI revision {} OEM {} ==",
+        tables.revision,
+        ascii(&tables.oem_id)
+    )
+    .ok();
+    for header in core::iter::once(&tables.root).chain(tables.headers.iter()) {
+        writeln!(
+            console,
+            "{} 0x{:016x} len {:5} rev {} {} {}{}",
+            header.signature_str(),
+            header.address,
+            header.length,
+            header.revision,
+            ascii(&header.oem_id),
+            ascii(&header.oem_table_id),
+            if header.checksum_valid {
+                ""
+            } else {
+                " (bad checksum)"
+            }
+        )
+        .ok();
+    }
+
+    if let Some(madt) = tables.madt.as_ref() {
+        writeln!(
+            console,
+            "MADT: {} CPUs, {} I/O APICs, {} overrides, local APIC 0x{:x}",
+            madt.local_apics.iter().filter(|cpu| cpu.enabled).count(),
+            madt.io_apics.len(),
+            madt.overrides.len(),
+            madt.local_apic_address
+        )
+        .ok();
+    }
+    if let Some(fadt) = tables.fadt.as_ref() {
+        writeln!(
+            console,
+            "FADT: SCI IRQ {}, PM1a control 0x{:x}, PM timer 0x{:x}, reset register {}",
+            fadt.sci_interrupt,
+            fadt.pm1a_control_block,
+            fadt.pm_timer_block,
+            if fadt.reset_command().is_some() {
+                "yes"
+            } else {
+                "no"
+            }
+        )
+        .ok();
+        if let Some(s5) = tables.s5_sleep_type {
+            writeln!(console, "DSDT: S5 SLP_TYP a={} b={}", s5.pm1a, s5.pm1b).ok();
+        }
+    }
+    if let Some(hpet) = tables.hpet.as_ref() {
+        writeln!(
+            console,
+            "HPET: base 0x{:x}, {} comparators, min tick {}",
+            hpet.base_address.address,
+            hpet.comparator_count(),
+            hpet.minimum_tick
+        )
+        .ok();
+    }
+    if let Some(mcfg) = tables.mcfg.as_ref() {
+        for entry in mcfg.entries.iter() {
+            writeln!(
+                console,
+                "MCFG: segment {} buses {:02x}-{:02x} at 0x{:x}",
+                entry.segment, entry.start_bus, entry.end_bus, entry.base_address
+            )
+            .ok();
+        }
+    }
+}
+
+fn ascii(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("?").trim_end()
+}
+
+/// Read a `T` from physical memory (ACPI structures are packed, so the
+/// address may be unaligned).
+pub(crate) fn read<T: Copy>(phys: u64) -> T {
+    let offset: u64 = *PHYSICAL_MEMORY_OFFSET
+        .get()
+        .expect("acpi: physical memory offset not set");
+    unsafe { ((offset + phys) as *const T).read_unaligned() }
+}
+
+pub(crate) fn read_bytes<const N: usize>(phys: u64) -> [u8; N] {
+    read::<[u8; N]>(phys)
+}
+
+/// Byte sum of `[phys, phys + len)`; zero for a valid ACPI structure.
+pub(crate) fn checksum(phys: u64, len: u64) -> u8 {
+    (0..len).fold(0u8, |sum, i| sum.wrapping_add(read::<u8>(phys + i)))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use crate::{PHYSICAL_MEMORY_OFFSET, SdtHeader, checksum};
+
+    /// Physical memory is "mapped" at offset 0 in tests, so the physical
+    /// address of a table is the address of its buffer.
+    pub(crate) fn phys(bytes: &[u8]) -> u64 {
+        PHYSICAL_MEMORY_OFFSET.call_once(|| 0);
+        bytes.as_ptr() as u64
+    }
+
+    /// A table with a valid header and checksum in front of `body`.
+    pub(crate) fn table(signature: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![0; SdtHeader::SIZE as usize];
+        bytes[0..4].copy_from_slice(signature);
+        let length: u32 = (SdtHeader::SIZE as usize + body.len()) as u32;
+        bytes[4..8].copy_from_slice(&length.to_le_bytes());
+        bytes[8] = 1;
+        bytes[10..16].copy_from_slice(b"BEYOND");
+        bytes.extend_from_slice(body);
+        let sum: u8 = bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        bytes[9] = sum.wrapping_neg();
+        bytes
+    }
+
+    #[test]
+    fn good_checksum_is_valid() {
+        let bytes: Vec<u8> = table(b"TEST", &[1, 2, 3, 4]);
+        assert_eq!(checksum(phys(&bytes), bytes.len() as u64), 0);
+
+        let header: SdtHeader = SdtHeader::read(phys(&bytes));
+        assert!(header.checksum_valid);
+        assert_eq!(header.signature_str(), "TEST");
+        assert_eq!(header.length, 40);
+        assert_eq!(header.data_len(), Some(4));
+        assert_eq!(&header.oem_id, b"BEYOND");
+    }
+
+    #[test]
+    fn bad_checksum_is_invalid() {
+        let mut bytes: Vec<u8> = table(b"TEST", &[1, 2, 3, 4]);
+        bytes[SdtHeader::SIZE as usize] ^= 0xff;
+        assert!(!SdtHeader::read(phys(&bytes)).checksum_valid);
+    }
+
+    #[test]
+    fn length_shorter_than_the_header_is_invalid() {
+        let mut bytes: Vec<u8> = table(b"TEST", &[]);
+        // Keep the checksum valid so only the length is wrong.
+        bytes[4] -= 1;
+        bytes[9] = bytes[9].wrapping_add(1);
+        let header: SdtHeader = SdtHeader::read(phys(&bytes));
+        assert!(!header.checksum_valid);
+        assert_eq!(header.data_len(), None);
+    }
+}
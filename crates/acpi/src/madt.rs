@@ -0,0 +1,175 @@
+//! Multiple APIC Description Table (signature `APIC`).
+use alloc::vec::Vec;
+
+use crate::{AcpiError, SdtHeader, read};
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// MADT flags: the system also has dual 8259 PICs.
+const PCAT_COMPAT: u32 = 1 << 0;
+/// Local APIC flags: the processor is usable.
+const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+
+/// Interrupt controllers described by the MADT.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    /// Physical address of the local APIC registers.
+    pub local_apic_address: u64,
+    pub flags: u32,
+    pub local_apics: Vec<LocalApic>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptSourceOverride>,
+    pub nmis: Vec<LocalApicNmi>,
+}
+
+/// One processor's local APIC.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApic {
+    pub processor_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+/// An I/O APIC and the first global system interrupt (GSI) it handles.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// Maps an ISA IRQ to a different GSI and/or signaling mode.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    /// ISA IRQ number.
+    pub source: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// Local APIC pin wired to NMI.
+#[derive(Debug, Clone, Copy)]
+pub struct LocalApicNmi {
+    /// ACPI processor id, or 0xff for all processors.
+    pub processor_id: u8,
+    /// LINT0 or LINT1.
+    pub lint: u8,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+}
+
+/// Interrupt input polarity (MPS INTI flags bits 0-1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    /// Use the default of the bus (active high for ISA, low for PCI).
+    ConformsToBus,
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Interrupt trigger mode (MPS INTI flags bits 2-3).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    /// Use the default of the bus (edge for ISA, level for PCI).
+    ConformsToBus,
+    Edge,
+    Level,
+}
+
+impl Madt {
+    pub(crate) fn parse(address: u64, header: &SdtHeader) -> Result<Self, AcpiError> {
+        let end: u64 = address + header.length as u64;
+        let mut madt = Madt {
+            local_apic_address: read::<u32>(address + SdtHeader::SIZE) as u64,
+            flags: read::<u32>(address + SdtHeader::SIZE + 4),
+            local_apics: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new(),
+        };
+
+        let mut entry: u64 = address + SdtHeader::SIZE + 8;
+        while entry + 2 <= end {
+            let kind: u8 = read::<u8>(entry);
+            let len: u64 = read::<u8>(entry + 1) as u64;
+            if len < 2 || entry + len > end {
+                return Err(AcpiError::Truncated(header.signature));
+            }
+            match kind {
+                ENTRY_LOCAL_APIC => madt.local_apics.push(LocalApic {
+                    processor_id: read::<u8>(entry + 2),
+                    apic_id: read::<u8>(entry + 3),
+                    enabled: read::<u32>(entry + 4) & LOCAL_APIC_ENABLED != 0,
+                }),
+                ENTRY_IO_APIC => madt.io_apics.push(IoApic {
+                    id: read::<u8>(entry + 2),
+                    address: read::<u32>(entry + 4),
+                    gsi_base: read::<u32>(entry + 8),
+                }),
+                ENTRY_INTERRUPT_SOURCE_OVERRIDE => {
+                    let flags: u16 = read::<u16>(entry + 8);
+                    madt.overrides.push(InterruptSourceOverride {
+                        bus: read::<u8>(entry + 2),
+                        source: read::<u8>(entry + 3),
+                        gsi: read::<u32>(entry + 4),
+                        polarity: Polarity::from_flags(flags),
+                        trigger: TriggerMode::from_flags(flags),
+                    });
+                }
+                ENTRY_LOCAL_APIC_NMI => {
+                    let flags: u16 = read::<u16>(entry + 3);
+                    madt.nmis.push(LocalApicNmi {
+                        processor_id: read::<u8>(entry + 2),
+                        lint: read::<u8>(entry + 5),
+                        polarity: Polarity::from_flags(flags),
+                        trigger: TriggerMode::from_flags(flags),
+                    });
+                }
+                ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                    madt.local_apic_address = read::<u64>(entry + 4);
+                }
+                _ => {}
+            }
+            entry += len;
+        }
+        Ok(madt)
+    }
+
+    /// True if the legacy 8259 PICs are present and need to be masked.
+    pub fn has_legacy_pics(&self) -> bool {
+        self.flags & PCAT_COMPAT != 0
+    }
+
+    /// The override for ISA IRQ `irq`, if the firmware reports one.
+    pub fn override_for(&self, irq: u8) -> Option<&InterruptSourceOverride> {
+        self.overrides
+            .iter()
+            .find(|o| o.bus == 0 && o.source == irq)
+    }
+}
+
+impl Polarity {
+    fn from_flags(flags: u16) -> Self {
+        match flags & 0b11 {
+            0b01 => Polarity::ActiveHigh,
+            0b11 => Polarity::ActiveLow,
+            _ => Polarity::ConformsToBus,
+        }
+    }
+}
+
+impl TriggerMode {
+    fn from_flags(flags: u16) -> Self {
+        match (flags >> 2) & 0b11 {
+            0b01 => TriggerMode::Edge,
+            0b11 => TriggerMode::Level,
+            _ => TriggerMode::ConformsToBus,
+        }
+    }
+}
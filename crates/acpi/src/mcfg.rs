@@ -0,0 +1,130 @@
+//! PCI Express memory-mapped configuration table (signature `MCFG`).
+use alloc::vec::Vec;
+
+use crate::{AcpiError, SdtHeader, read};
+
+/// Reserved bytes between the header and the first entry.
+const ENTRIES_OFFSET: u64 = SdtHeader::SIZE + 8;
+const ENTRY_LEN: u64 = 16;
+
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub entries: Vec<McfgEntry>,
+}
+
+/// ECAM window of one PCI segment group.
+#[derive(Debug, Clone, Copy)]
+pub struct McfgEntry {
+    /// Physical address of the configuration space of bus 0 of the segment.
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl Mcfg {
+    pub(crate) fn parse(address: u64, header: &SdtHeader) -> Result<Self, AcpiError> {
+        let length: u64 = header.length as u64;
+        if length < ENTRIES_OFFSET {
+            return Err(AcpiError::Truncated(header.signature));
+        }
+        let entries: Vec<McfgEntry> = (0..(length - ENTRIES_OFFSET) / ENTRY_LEN)
+            .map(|index| {
+                let entry: u64 = address + ENTRIES_OFFSET + index * ENTRY_LEN;
+                McfgEntry {
+                    base_address: read::<u64>(entry),
+                    segment: read::<u16>(entry + 8),
+                    start_bus: read::<u8>(entry + 10),
+                    end_bus: read::<u8>(entry + 11),
+                }
+            })
+            .collect();
+        Ok(Mcfg { entries })
+    }
+}
+
+impl McfgEntry {
+    /// Physical address of the configuration space of a function.
+    pub fn config_address(&self, bus: u8, device: u8, function: u8) -> Option<u64> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+        Some(
+            self.base_address
+                + ((bus as u64) << 20)
+                + ((device as u64) << 15)
+                + ((function as u64) << 12),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use crate::tests::{phys, table};
+    use crate::{AcpiError, Mcfg, McfgEntry, SdtHeader};
+
+    fn entry(base_address: u64, segment: u16, start_bus: u8, end_bus: u8) -> McfgEntry {
+        McfgEntry {
+            base_address,
+            segment,
+            start_bus,
+            end_bus,
+        }
+    }
+
+    #[test]
+    fn parses_every_entry() {
+        let mut body: Vec<u8> = Vec::from([0; 8]);
+        for (base, segment, start, end) in [
+            (0xe000_0000u64, 0u16, 0u8, 0xffu8),
+            (0xf000_0000, 1, 0x10, 0x1f),
+        ] {
+            body.extend_from_slice(&base.to_le_bytes());
+            body.extend_from_slice(&segment.to_le_bytes());
+            body.extend_from_slice(&[start, end, 0, 0, 0, 0]);
+        }
+        let bytes: Vec<u8> = table(b"MCFG", &body);
+        let header: SdtHeader = SdtHeader::read(phys(&bytes));
+
+        let mcfg: Mcfg = Mcfg::parse(header.address, &header).unwrap();
+        assert_eq!(mcfg.entries.len(), 2);
+        let second: &McfgEntry = &mcfg.entries[1];
+        assert_eq!(second.base_address, 0xf000_0000);
+        assert_eq!(second.segment, 1);
+        assert_eq!((second.start_bus, second.end_bus), (0x10, 0x1f));
+    }
+
+    #[test]
+    fn truncated_mcfg_is_rejected() {
+        let bytes: Vec<u8> = table(b"MCFG", &[0; 4]);
+        let header: SdtHeader = SdtHeader::read(phys(&bytes));
+        assert_eq!(
+            Mcfg::parse(header.address, &header).unwrap_err(),
+            AcpiError::Truncated(*b"MCFG")
+        );
+    }
+
+    #[test]
+    fn config_address_math() {
+        let segment: McfgEntry = entry(0xe000_0000, 0, 0, 0xff);
+        assert_eq!(segment.config_address(0, 0, 0), Some(0xe000_0000));
+        assert_eq!(
+            segment.config_address(1, 2, 3),
+            Some(0xe000_0000 + (1 << 20) + (2 << 15) + (3 << 12))
+        );
+        assert_eq!(segment.config_address(0xff, 31, 7), Some(0xefff_f000));
+        assert_eq!(segment.config_address(0, 32, 0), None);
+        assert_eq!(segment.config_address(0, 0, 8), None);
+    }
+
+    #[test]
+    fn config_address_respects_the_bus_range() {
+        let segment: McfgEntry = entry(0xe000_0000, 0, 0x10, 0x1f);
+        assert_eq!(segment.config_address(0x0f, 0, 0), None);
+        assert_eq!(segment.config_address(0x10, 0, 0), Some(0xe100_0000));
+        assert_eq!(segment.config_address(0x1f, 0, 0), Some(0xe1f0_0000));
+        assert_eq!(segment.config_address(0x20, 0, 0), None);
+    }
+}
@@ -0,0 +1,107 @@
+//! Structures shared by every system description table.
+use crate::{checksum, read, read_bytes};
+
+/// Longest table `SdtHeader::read` is willing to checksum; anything larger
+/// is taken to be garbage.
+const MAX_TABLE_LEN: u32 = 1 << 20;
+
+/// System description table header.
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    /// Physical address of the table.
+    pub address: u64,
+    pub signature: [u8; 4],
+    /// Length of the whole table, header included.
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    /// The length is sane and the bytes of the table sum to zero.
+    pub checksum_valid: bool,
+}
+
+impl SdtHeader {
+    /// Size of the header in bytes.
+    pub const SIZE: u64 = 36;
+
+    pub(crate) fn read(address: u64) -> Self {
+        let length: u32 = read::<u32>(address + 4);
+        let checksum_valid: bool = (Self::SIZE as u32..=MAX_TABLE_LEN).contains(&length)
+            && checksum(address, length as u64) == 0;
+        Self {
+            address,
+            signature: read_bytes::<4>(address),
+            length,
+            revision: read::<u8>(address + 8),
+            oem_id: read_bytes::<6>(address + 10),
+            oem_table_id: read_bytes::<8>(address + 16),
+            checksum_valid,
+        }
+    }
+
+    /// Number of bytes after the header, or `None` if the length is too
+    /// short to hold the header itself.
+    pub fn data_len(&self) -> Option<u64> {
+        (self.length as u64).checked_sub(Self::SIZE)
+    }
+
+    /// Signature as a string (`"APIC"`, `"FACP"`, ...).
+    pub fn signature_str(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+}
+
+/// Address space of a `GenericAddress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// ACPI Generic Address Structure: where a register lives and how wide it is.
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    /// Size of the structure in bytes.
+    pub const SIZE: u64 = 12;
+
+    pub(crate) fn read(phys: u64) -> Self {
+        Self {
+            space: match read::<u8>(phys) {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfig,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: read::<u8>(phys + 1),
+            bit_offset: read::<u8>(phys + 2),
+            access_size: read::<u8>(phys + 3),
+            address: read::<u64>(phys + 4),
+        }
+    }
+
+    /// The all-zero structure firmware uses for "not present".
+    pub(crate) fn absent() -> Self {
+        Self {
+            space: AddressSpace::SystemMemory,
+            bit_width: 0,
+            bit_offset: 0,
+            access_size: 0,
+            address: 0,
+        }
+    }
+
+    /// False for the all-zero structure firmware uses for "not present".
+    pub fn is_present(&self) -> bool {
+        self.address != 0
+    }
+}
@@ -0,0 +1,253 @@
+//! Local APIC and I/O APIC interrupt controller.
+//!
+//! Selected instead of the 8259 PICs when the CPU has an APIC and the ACPI
+//! MADT lists at least one I/O APIC. Legacy IRQs keep the vectors they have
+//! under the PIC (`PIC_1_OFFSET + irq`), so the IDT is the same for both
+//! controllers. All IRQs are delivered to the boot processor.
+use alloc::vec::Vec;
+use core::arch::x86_64::__cpuid;
+
+use acpi::{Madt, Polarity, TriggerMode};
+use console::serial_println;
+use memory::{MmioRegion, map_mmio};
+use spin::{Mutex, Once};
+use x86_64::registers::model_specific::Msr;
+
+use crate::idt::InterruptIndex;
+use crate::interrupts::IrqError;
+use crate::pic::{self, InterruptController, IrqKind, PIC_1_OFFSET};
+
+/// Vector the local APIC uses for spurious interrupts. Needs no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// CPUID.1:EDX bit for an on-chip APIC.
+const CPUID_APIC: u32 = 1 << 9;
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC register offsets.
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TPR: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SVR: u64 = 0xf0;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_LVT_LINT0: u64 = 0x350;
+const LAPIC_LVT_LINT1: u64 = 0x360;
+const LAPIC_LVT_ERROR: u64 = 0x370;
+const LAPIC_REGION_SIZE: u64 = 0x400;
+
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_LEVEL: u32 = 1 << 15;
+
+// I/O APIC registers: an index register and a data window.
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WINDOW: u64 = 0x10;
+const IOAPIC_REGION_SIZE: u64 = 0x20;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+const REDIRECTION_DESTINATION_SHIFT: u32 = 56;
+
+struct IoApic {
+    registers: MmioRegion,
+    gsi_base: u32,
+    /// Number of redirection table entries.
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        self.registers.write::<u32>(IOAPIC_REGSEL, register);
+        self.registers.read::<u32>(IOAPIC_WINDOW)
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        self.registers.write::<u32>(IOAPIC_REGSEL, register);
+        self.registers.write::<u32>(IOAPIC_WINDOW, value);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.entries
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register: u32 = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        // Mask first so the entry never fires half written.
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+pub struct ApicController {
+    local_apic: MmioRegion,
+    /// The index/window pair of each I/O APIC must not be interleaved.
+    io_apics: Mutex<Vec<IoApic>>,
+    madt: &'static Madt,
+}
+
+impl InterruptController for ApicController {
+    fn init(&self) {
+        if self.madt.has_legacy_pics() {
+            pic::disable_legacy_pics();
+        }
+
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        unsafe { apic_base.write(apic_base.read() | APIC_BASE_ENABLE) };
+
+        let lapic: &MmioRegion = &self.local_apic;
+        lapic.write::<u32>(LAPIC_TPR, 0);
+        lapic.write::<u32>(LAPIC_LVT_TIMER, LVT_MASKED);
+        lapic.write::<u32>(LAPIC_LVT_ERROR, LVT_MASKED);
+        lapic.write::<u32>(LAPIC_LVT_LINT0, LVT_MASKED);
+        lapic.write::<u32>(LAPIC_LVT_LINT1, LVT_MASKED);
+        for nmi in self.madt.nmis.iter() {
+            let mut lvt: u32 = LVT_DELIVERY_NMI;
+            if nmi.polarity == Polarity::ActiveLow {
+                lvt |= LVT_ACTIVE_LOW;
+            }
+            if nmi.trigger == TriggerMode::Level {
+                lvt |= LVT_LEVEL;
+            }
+            match nmi.lint {
+                0 => lapic.write::<u32>(LAPIC_LVT_LINT0, lvt),
+                1 => lapic.write::<u32>(LAPIC_LVT_LINT1, lvt),
+                _ => {}
+            }
+        }
+        lapic.write::<u32>(LAPIC_SVR, SVR_APIC_ENABLE | SPURIOUS_VECTOR as u32);
+
+        for io_apic in self.io_apics.lock().iter() {
+            for index in 0..io_apic.entries {
+                io_apic.set_redirection(io_apic.gsi_base + index, REDIRECTION_MASKED);
+            }
+        }
+
+        for index in [InterruptIndex::Timer, InterruptIndex::Keyboard] {
+            if let Err(e) = self.enable_irq(index.as_u8() - PIC_1_OFFSET, IrqKind::Isa) {
+                serial_println!("apic: cannot route {:?}: {:?}", index, e);
+            }
+        }
+    }
+
+    fn end_of_interrupt(&self, _irq: InterruptIndex) {
+        self.local_apic.write::<u32>(LAPIC_EOI, 0);
+    }
+
+    /// Only ISA lines can be routed: the GSI of a PCI INTx line comes from
+    /// the ACPI `_PRT`, which is not parsed, so its drivers have to poll.
+    fn enable_irq(&self, irq: u8, kind: IrqKind) -> Result<(), IrqError> {
+        if kind == IrqKind::Pci {
+            return Err(IrqError::Unroutable(irq));
+        }
+        // ISA IRQs are identity mapped to GSIs unless the MADT overrides them.
+        let (gsi, polarity, trigger) = match self.madt.override_for(irq) {
+            Some(o) => (o.gsi, o.polarity, o.trigger),
+            None => (
+                irq as u32,
+                Polarity::ConformsToBus,
+                TriggerMode::ConformsToBus,
+            ),
+        };
+        // The ISA bus is edge triggered and active high.
+        let active_low: bool = polarity == Polarity::ActiveLow;
+        let level: bool = trigger == TriggerMode::Level;
+
+        let mut entry: u64 = (PIC_1_OFFSET + irq) as u64
+            | (self.local_apic_id() as u64) << REDIRECTION_DESTINATION_SHIFT;
+        if active_low {
+            entry |= REDIRECTION_ACTIVE_LOW;
+        }
+        if level {
+            entry |= REDIRECTION_LEVEL;
+        }
+
+        match self.io_apics.lock().iter().find(|io| io.handles(gsi)) {
+            Some(io_apic) => {
+                io_apic.set_redirection(gsi, entry);
+                Ok(())
+            }
+            None => Err(IrqError::Unroutable(irq)),
+        }
+    }
+
+    fn end_of_irq(&self, _irq: u8) {
+        self.local_apic.write::<u32>(LAPIC_EOI, 0);
+    }
+
+    /// The local APIC reports spurious interrupts on its own vector.
+    fn handle_spurious(&self, _irq: u8) -> bool {
+        false
+    }
+
+    fn name(&self) -> &'static str {
+        "APIC"
+    }
+}
+
+impl ApicController {
+    fn local_apic_id(&self) -> u8 {
+        (self.local_apic.read::<u32>(LAPIC_ID) >> 24) as u8
+    }
+}
+
+static APIC_CONTROLLER: Once<Option<ApicController>> = Once::new();
+
+/// The APIC controller, or `None` if the CPU has no APIC, ACPI did not
+/// report an MADT with an I/O APIC, or the registers cannot be mapped.
+pub fn apic_controller() -> Option<&'static (dyn InterruptController + Sync)> {
+    let controller = APIC_CONTROLLER.call_once(|| {
+        if __cpuid(1).edx & CPUID_APIC == 0 {
+            serial_println!("apic: not supported by the CPU");
+            return None;
+        }
+        let Some(madt) = acpi::tables().and_then(|tables| tables.madt.as_ref()) else {
+            serial_println!("apic: no ACPI MADT");
+            return None;
+        };
+        if madt.io_apics.is_empty() {
+            serial_println!("apic: MADT lists no I/O APIC");
+            return None;
+        }
+
+        let local_apic = match map_mmio(madt.local_apic_address, LAPIC_REGION_SIZE) {
+            Ok(region) => region,
+            Err(e) => {
+                serial_println!("apic: cannot map local APIC: {:?}", e);
+                return None;
+            }
+        };
+        let mut io_apics: Vec<IoApic> = Vec::new();
+        for io_apic in madt.io_apics.iter() {
+            let registers = match map_mmio(io_apic.address as u64, IOAPIC_REGION_SIZE) {
+                Ok(region) => region,
+                Err(e) => {
+                    serial_println!("apic: cannot map I/O APIC {}: {:?}", io_apic.id, e);
+                    return None;
+                }
+            };
+            let mut io_apic = IoApic {
+                registers,
+                gsi_base: io_apic.gsi_base,
+                entries: 0,
+            };
+            io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+            io_apics.push(io_apic);
+        }
+
+        Some(ApicController {
+            local_apic,
+            io_apics: Mutex::new(io_apics),
+            madt,
+        })
+    });
+    controller
+        .as_ref()
+        .map(|controller| controller as &'static (dyn InterruptController + Sync))
+}
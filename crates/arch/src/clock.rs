@@ -0,0 +1,182 @@
+//! High-resolution monotonic clock based on the TSC.
+//!
+//! `init` measures the TSC frequency against the HPET main counter when ACPI
+//! describes one, or against a one-shot countdown on PIT channel 2 otherwise.
+//! `now_ns` then converts TSC deltas to nanoseconds. Without an invariant TSC
+//! the frequency may change with power states, so the result is only as good
+//! as the CPU allows; `TscInfo::invariant` says which case applies.
+use console::serial_println;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use memory::{MmioRegion, map_mmio};
+use spin::Once;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::timer::{self, PIT_FREQUENCY_HZ};
+
+/// Length of the calibration window.
+const CALIBRATION_MS: u64 = 10;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_ADVANCED_POWER: u32 = 0x8000_0007;
+/// CPUID 0x8000_0007 EDX: the TSC runs at a constant rate in all states.
+const CPUID_INVARIANT_TSC: u32 = 1 << 8;
+
+const HPET_REGION_SIZE: u64 = 0x100;
+const HPET_CAPABILITIES: u64 = 0x00;
+const HPET_CONFIGURATION: u64 = 0x10;
+const HPET_MAIN_COUNTER: u64 = 0xf0;
+const HPET_PERIOD_SHIFT: u32 = 32;
+const HPET_ENABLE: u64 = 1 << 0;
+
+const PIT_CHANNEL2_PORT: u16 = 0x42;
+const PIT_COMMAND_PORT: u16 = 0x43;
+/// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count).
+const PIT_CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+/// Port 0x61 controls the channel 2 gate and the speaker, and reads back
+/// the channel 2 output.
+const PIT_CHANNEL2_CONTROL_PORT: u16 = 0x61;
+const CHANNEL2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL2_OUTPUT: u8 = 1 << 5;
+
+/// Polls of the reference counter before giving up on it.
+const POLL_LIMIT: u64 = 100_000_000;
+
+/// Reference used to calibrate the TSC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationSource {
+    Hpet,
+    Pit,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TscInfo {
+    pub frequency_hz: u64,
+    /// The TSC rate does not depend on P-, C- or T-states.
+    pub invariant: bool,
+    pub source: CalibrationSource,
+    /// TSC value taken as time `base_ns`.
+    base_tsc: u64,
+    /// Tick uptime at calibration, so `now_ns` continues `timer::uptime_ns`.
+    base_ns: u64,
+}
+
+static TSC: Once<TscInfo> = Once::new();
+
+/// Calibrate the TSC. Returns `None` if neither the HPET nor the PIT gave
+/// a usable measurement, in which case `now_ns` stays at tick resolution.
+pub fn init() -> Option<&'static TscInfo> {
+    let measured: Option<(u64, CalibrationSource)> = interrupts::without_interrupts(|| {
+        calibrate_with_hpet()
+            .map(|hz| (hz, CalibrationSource::Hpet))
+            .or_else(|| calibrate_with_pit().map(|hz| (hz, CalibrationSource::Pit)))
+    });
+    let Some((frequency_hz, source)) = measured else {
+        serial_println!("clock: TSC calibration failed, using the system tick");
+        return None;
+    };
+    let info: &TscInfo = TSC.call_once(|| TscInfo {
+        frequency_hz,
+        invariant: has_invariant_tsc(),
+        source,
+        base_tsc: rdtsc(),
+        base_ns: timer::uptime_ns(),
+    });
+    if !info.invariant {
+        serial_println!("clock: TSC is not invariant, timings may drift");
+    }
+    Some(info)
+}
+
+/// Calibration result, if `init` succeeded.
+pub fn tsc_info() -> Option<&'static TscInfo> {
+    TSC.get()
+}
+
+/// Nanoseconds since boot. Uses the TSC once calibrated and the system tick
+/// before that (or if calibration failed).
+pub fn now_ns() -> u64 {
+    match TSC.get() {
+        Some(info) => {
+            let cycles: u64 = rdtsc().wrapping_sub(info.base_tsc);
+            info.base_ns
+                + (cycles as u128 * NANOS_PER_SECOND as u128 / info.frequency_hz as u128) as u64
+        }
+        None => timer::uptime_ns(),
+    }
+}
+
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn has_invariant_tsc() -> bool {
+    __cpuid(CPUID_EXTENDED_MAX).eax >= CPUID_ADVANCED_POWER
+        && __cpuid(CPUID_ADVANCED_POWER).edx & CPUID_INVARIANT_TSC != 0
+}
+
+/// Count TSC cycles over `CALIBRATION_MS` of HPET main counter time.
+fn calibrate_with_hpet() -> Option<u64> {
+    let hpet = acpi::tables()?.hpet.as_ref()?;
+    let registers: MmioRegion = map_mmio(hpet.base_address.address, HPET_REGION_SIZE).ok()?;
+
+    let period_fs: u64 = registers.read::<u64>(HPET_CAPABILITIES) >> HPET_PERIOD_SHIFT;
+    if period_fs == 0 {
+        return None;
+    }
+    let configuration: u64 = registers.read::<u64>(HPET_CONFIGURATION);
+    if configuration & HPET_ENABLE == 0 {
+        registers.write::<u64>(HPET_CONFIGURATION, configuration | HPET_ENABLE);
+    }
+
+    let window: u64 = CALIBRATION_MS * 1_000_000 * FEMTOS_PER_NANO / period_fs;
+    let start_counter: u64 = registers.read::<u64>(HPET_MAIN_COUNTER);
+    let start_tsc: u64 = rdtsc();
+    let mut measured: Option<(u64, u64)> = None;
+    for _ in 0..POLL_LIMIT {
+        let elapsed: u64 = registers
+            .read::<u64>(HPET_MAIN_COUNTER)
+            .wrapping_sub(start_counter);
+        if elapsed >= window {
+            measured = Some((elapsed, rdtsc() - start_tsc));
+            break;
+        }
+    }
+    let (elapsed, cycles): (u64, u64) = measured?;
+
+    let elapsed_fs: u128 = elapsed as u128 * period_fs as u128;
+    let hz: u128 = cycles as u128 * NANOS_PER_SECOND as u128 * FEMTOS_PER_NANO as u128 / elapsed_fs;
+    Some(hz as u64)
+}
+
+/// Count TSC cycles while PIT channel 2 counts down `CALIBRATION_MS`.
+fn calibrate_with_pit() -> Option<u64> {
+    let count: u64 = PIT_FREQUENCY_HZ * CALIBRATION_MS / 1000;
+    let mut control: Port<u8> = Port::new(PIT_CHANNEL2_CONTROL_PORT);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut channel2: Port<u8> = Port::new(PIT_CHANNEL2_PORT);
+
+    let cycles: Option<u64> = unsafe {
+        let saved: u8 = control.read();
+        control.write((saved & !SPEAKER_ENABLE) | CHANNEL2_GATE);
+        command.write(PIT_CHANNEL2_ONE_SHOT);
+        channel2.write(count as u8);
+        channel2.write((count >> 8) as u8);
+
+        // The count starts on the next PIT clock after it is written; the
+        // output goes high once it reaches zero.
+        let start_tsc: u64 = rdtsc();
+        let mut cycles: Option<u64> = None;
+        for _ in 0..POLL_LIMIT {
+            if control.read() & CHANNEL2_OUTPUT != 0 {
+                cycles = Some(rdtsc() - start_tsc);
+                break;
+            }
+        }
+        control.write(saved);
+        cycles
+    };
+    Some(cycles? * PIT_FREQUENCY_HZ / count)
+}
@@ -0,0 +1,471 @@
+//! Uniform crash reports for CPU exceptions and kernel panics.
+//!
+//! A report names the exception vector, decodes its error code and dumps the
+//! interrupted register state, the control registers and a frame pointer
+//! backtrace symbolized through `symbols`. It is written to the serial port
+//! and to the framebuffer. Fatal reports take the console over as a panic
+//! screen; reports the kernel resumes from only print when the console is
+//! not busy.
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use console::{console::PanicScreen, serial::SerialPort};
+use memory::paging::{self, vmm};
+use spin::MutexGuard;
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        mxcsr,
+    },
+    structures::{
+        idt::{ExceptionVector, InterruptStackFrameValue, PageFaultErrorCode},
+        paging::OffsetPageTable,
+    },
+};
+
+use crate::symbols;
+
+/// Selector error code: the event came from outside the CPU.
+const SELECTOR_EXTERNAL: u64 = 1 << 0;
+/// Selector error code: the index refers to the IDT.
+const SELECTOR_IDT: u64 = 1 << 1;
+/// Selector error code: the index refers to the LDT (when not in the IDT).
+const SELECTOR_LDT: u64 = 1 << 2;
+const SELECTOR_INDEX_SHIFT: u32 = 3;
+const SELECTOR_INDEX_MASK: u64 = 0x1fff;
+
+/// Maximum number of frames printed in a backtrace.
+const MAX_BACKTRACE_FRAMES: usize = 32;
+
+/// Set by the first fatal report; a crash while reporting only uses serial.
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// General-purpose registers saved by the exception entry stubs.
+///
+/// Field order matches the stack layout built by `interrupt_handlers`, lowest
+/// address first.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+/// Error code pushed by an exception, tagged by its layout.
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorCode {
+    /// The exception does not push an error code.
+    None,
+    /// Segment selector error code (#TS, #NP, #SS, #GP).
+    Selector(u64),
+    /// Page fault error code; the faulting address is read from CR2.
+    PageFault(PageFaultErrorCode),
+    /// Error code without a decodable layout (#DF, #AC, #CP, ...).
+    Raw(u64),
+}
+
+impl ErrorCode {
+    /// Tag the raw error code pushed for `vector` with its layout.
+    pub fn for_vector(vector: ExceptionVector, code: u64) -> Self {
+        match vector {
+            ExceptionVector::InvalidTss
+            | ExceptionVector::SegmentNotPresent
+            | ExceptionVector::Stack
+            | ExceptionVector::GeneralProtection => ErrorCode::Selector(code),
+            ExceptionVector::Page => {
+                ErrorCode::PageFault(PageFaultErrorCode::from_bits_retain(code))
+            }
+            ExceptionVector::Double
+            | ExceptionVector::AlignmentCheck
+            | ExceptionVector::ControlProtection
+            | ExceptionVector::VmmCommunication
+            | ExceptionVector::Security => ErrorCode::Raw(code),
+            _ => ErrorCode::None,
+        }
+    }
+}
+
+/// Write a report for an exception the kernel resumes from (breakpoints,
+/// debug traps) to serial and, if it is free, the console.
+pub fn report(
+    vector: ExceptionVector,
+    stack_frame: &InterruptStackFrameValue,
+    error_code: ErrorCode,
+    registers: Option<&Registers>,
+) {
+    let _ = write_report(&mut CrashWriter, vector, stack_frame, error_code, registers);
+}
+
+/// Write a report for an exception the kernel cannot recover from to serial
+/// and the panic screen.
+///
+/// `registers` are the general-purpose registers at the time of the
+/// exception; without them no register dump or backtrace is printed.
+pub fn report_fatal(
+    vector: ExceptionVector,
+    stack_frame: &InterruptStackFrameValue,
+    error_code: ErrorCode,
+    registers: Option<&Registers>,
+) {
+    let mut out = PanicWriter::begin();
+    let _ = writeln!(out, "KERNEL PANIC: unhandled CPU exception");
+    let _ = write_report(&mut out, vector, stack_frame, error_code, registers);
+    let _ = writeln!(out, "system halted");
+}
+
+/// Write a panic report with the control registers and a backtrace of the
+/// panicking code to serial and the panic screen.
+pub fn report_panic(info: &PanicInfo) {
+    let (rip, rbp) = current_frame();
+    let mut out = PanicWriter::begin();
+    let _ = write_panic(&mut out, info, rip, rbp);
+    let _ = writeln!(out, "system halted");
+}
+
+fn write_panic(out: &mut impl Write, info: &PanicInfo, rip: u64, rbp: u64) -> fmt::Result {
+    writeln!(out, "KERNEL PANIC: {}", info.message())?;
+    if let Some(location) = info.location() {
+        writeln!(
+            out,
+            "at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )?;
+    }
+    write_control_registers(out)?;
+    write_backtrace(out, rip, rbp)
+}
+
+fn write_report(
+    out: &mut impl Write,
+    vector: ExceptionVector,
+    stack_frame: &InterruptStackFrameValue,
+    error_code: ErrorCode,
+    registers: Option<&Registers>,
+) -> fmt::Result {
+    let (mnemonic, name) = vector_name(vector);
+    writeln!(
+        out,
+        "EXCEPTION: {} ({}, vector {})",
+        name, mnemonic, vector as u8
+    )?;
+
+    match error_code {
+        ErrorCode::None => {}
+        ErrorCode::Selector(code) => {
+            write!(out, "error code: {:#x}", code)?;
+            if code == 0 {
+                writeln!(out, " (no selector)")?;
+            } else {
+                let table = if code & SELECTOR_IDT != 0 {
+                    "IDT"
+                } else if code & SELECTOR_LDT != 0 {
+                    "LDT"
+                } else {
+                    "GDT"
+                };
+                let index = (code >> SELECTOR_INDEX_SHIFT) & SELECTOR_INDEX_MASK;
+                let source = if code & SELECTOR_EXTERNAL != 0 {
+                    ", external event"
+                } else {
+                    ""
+                };
+                writeln!(out, " ({} index {}{})", table, index, source)?;
+            }
+        }
+        ErrorCode::PageFault(code) => {
+            writeln!(out, "error code: {:#x}", code.bits())?;
+            write_page_fault_cause(out, code)?;
+            match Cr2::read() {
+                Ok(addr) => writeln!(out, "address: {:#x}", addr.as_u64())?,
+                Err(e) => writeln!(out, "address: {:#x} (non-canonical)", e.0)?,
+            }
+        }
+        ErrorCode::Raw(code) => writeln!(out, "error code: {:#x}", code)?,
+    }
+
+    if vector == ExceptionVector::SimdFloatingPoint {
+        writeln!(out, "mxcsr: {:?}", mxcsr::read())?;
+    }
+    if matches!(vector, ExceptionVector::Page | ExceptionVector::Double)
+        && let Ok(addr) = Cr2::read()
+        && let Some(stack) = vmm::stack_guard_hit(addr)
+    {
+        writeln!(out, "stack overflow in {}", stack)?;
+    }
+
+    writeln!(
+        out,
+        "rip={:#018x} cs={:#06x} rflags={:#010x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment.0,
+        stack_frame.cpu_flags.bits()
+    )?;
+    writeln!(
+        out,
+        "rsp={:#018x} ss={:#06x}",
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment.0
+    )?;
+    write_symbol(out, "at", stack_frame.instruction_pointer.as_u64())?;
+
+    if let Some(registers) = registers {
+        write_registers(out, registers)?;
+        write_control_registers(out)?;
+        write_backtrace(out, stack_frame.instruction_pointer.as_u64(), registers.rbp)?;
+    }
+    Ok(())
+}
+
+/// Spell out the page fault error code bits.
+fn write_page_fault_cause(out: &mut impl Write, code: PageFaultErrorCode) -> fmt::Result {
+    let cause = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "protection violation"
+    } else {
+        "page not present"
+    };
+    let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "instruction fetch"
+    } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write"
+    } else {
+        "read"
+    };
+    let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+        "user"
+    } else {
+        "kernel"
+    };
+    writeln!(out, "cause: {} on {} in {} mode", cause, access, mode)?;
+
+    let notes = [
+        (
+            PageFaultErrorCode::MALFORMED_TABLE,
+            "reserved bit set in a page table entry",
+        ),
+        (
+            PageFaultErrorCode::PROTECTION_KEY,
+            "protection key violation",
+        ),
+        (PageFaultErrorCode::SHADOW_STACK, "shadow stack access"),
+        (PageFaultErrorCode::HLAT, "HLAT paging"),
+        (PageFaultErrorCode::SGX, "SGX access control violation"),
+        (PageFaultErrorCode::RMP, "RMP violation"),
+    ];
+    for (flag, note) in notes {
+        if code.contains(flag) {
+            writeln!(out, "       {}", note)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_registers(out: &mut impl Write, r: &Registers) -> fmt::Result {
+    writeln!(
+        out,
+        "rax={:#018x} rbx={:#018x} rcx={:#018x}",
+        r.rax, r.rbx, r.rcx
+    )?;
+    writeln!(
+        out,
+        "rdx={:#018x} rsi={:#018x} rdi={:#018x}",
+        r.rdx, r.rsi, r.rdi
+    )?;
+    writeln!(
+        out,
+        "rbp={:#018x} r8 ={:#018x} r9 ={:#018x}",
+        r.rbp, r.r8, r.r9
+    )?;
+    writeln!(
+        out,
+        "r10={:#018x} r11={:#018x} r12={:#018x}",
+        r.r10, r.r11, r.r12
+    )?;
+    writeln!(
+        out,
+        "r13={:#018x} r14={:#018x} r15={:#018x}",
+        r.r13, r.r14, r.r15
+    )
+}
+
+fn write_control_registers(out: &mut impl Write) -> fmt::Result {
+    let (cr3_frame, cr3_flags) = Cr3::read_raw();
+    writeln!(
+        out,
+        "cr0={:#018x} cr2={:#018x}",
+        Cr0::read_raw(),
+        Cr2::read_raw()
+    )?;
+    writeln!(
+        out,
+        "cr3={:#018x} cr4={:#018x}",
+        cr3_frame.start_address().as_u64() | cr3_flags as u64,
+        Cr4::read_raw()
+    )
+}
+
+/// Walk the saved frame pointer chain starting at `rbp`.
+///
+/// The kernel is built with frame pointers, so every frame starts with the
+/// caller's `rbp` followed by the return address. The walk stops at the first
+/// frame pointer that is not a mapped, aligned kernel address. The kernel
+/// mapper is only tried, so a crash while it is held reports no callers.
+fn write_backtrace(out: &mut impl Write, rip: u64, mut rbp: u64) -> fmt::Result {
+    writeln!(out, "backtrace:")?;
+    write_frame(out, 0, rip)?;
+    for depth in 1..=MAX_BACKTRACE_FRAMES {
+        let Ok(frame) = VirtAddr::try_new(rbp) else {
+            break;
+        };
+        let mapped = |mapper: &mut OffsetPageTable<'static>| {
+            paging::translate(frame, mapper).is_ok()
+                && paging::translate(frame + 8u64, mapper).is_ok()
+        };
+        if rbp == 0 || !rbp.is_multiple_of(8) || !paging::try_with_mapper(mapped).unwrap_or(false) {
+            break;
+        }
+        let (next, return_address) = unsafe {
+            let ptr: *const u64 = frame.as_ptr();
+            (ptr.read(), ptr.add(1).read())
+        };
+        if return_address == 0 {
+            break;
+        }
+        // Return addresses point after the call; look up the call itself.
+        write_frame(out, depth, return_address - 1)?;
+        // Frames grow towards lower addresses, so callers sit above.
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    Ok(())
+}
+
+fn write_frame(out: &mut impl Write, depth: usize, addr: u64) -> fmt::Result {
+    write!(out, "  #{:<2} ", depth)?;
+    write_symbol(out, "", addr)
+}
+
+fn write_symbol(out: &mut impl Write, prefix: &str, addr: u64) -> fmt::Result {
+    if !prefix.is_empty() {
+        write!(out, "{} ", prefix)?;
+    }
+    match symbols::lookup(addr) {
+        Some(symbol) => writeln!(out, "{:#018x} {}+{:#x}", addr, symbol.name, symbol.offset),
+        None => writeln!(out, "{:#018x} <unknown>", addr),
+    }
+}
+
+/// Instruction pointer and frame pointer of the caller.
+#[inline(always)]
+fn current_frame() -> (u64, u64) {
+    let rip: u64;
+    let rbp: u64;
+    unsafe {
+        asm!(
+            "lea {rip}, [rip]",
+            "mov {rbp}, rbp",
+            rip = out(reg) rip,
+            rbp = out(reg) rbp,
+            options(nomem, nostack, preserves_flags)
+        );
+    }
+    (rip, rbp)
+}
+
+/// Mnemonic and descriptive name of an exception vector.
+fn vector_name(vector: ExceptionVector) -> (&'static str, &'static str) {
+    match vector {
+        ExceptionVector::Division => ("#DE", "DIVIDE ERROR"),
+        ExceptionVector::Debug => ("#DB", "DEBUG"),
+        ExceptionVector::NonMaskableInterrupt => ("NMI", "NON-MASKABLE INTERRUPT"),
+        ExceptionVector::Breakpoint => ("#BP", "BREAKPOINT"),
+        ExceptionVector::Overflow => ("#OF", "OVERFLOW"),
+        ExceptionVector::BoundRange => ("#BR", "BOUND RANGE EXCEEDED"),
+        ExceptionVector::InvalidOpcode => ("#UD", "INVALID OPCODE"),
+        ExceptionVector::DeviceNotAvailable => ("#NM", "DEVICE NOT AVAILABLE"),
+        ExceptionVector::Double => ("#DF", "DOUBLE FAULT"),
+        ExceptionVector::InvalidTss => ("#TS", "INVALID TSS"),
+        ExceptionVector::SegmentNotPresent => ("#NP", "SEGMENT NOT PRESENT"),
+        ExceptionVector::Stack => ("#SS", "STACK-SEGMENT FAULT"),
+        ExceptionVector::GeneralProtection => ("#GP", "GENERAL PROTECTION FAULT"),
+        ExceptionVector::Page => ("#PF", "PAGE FAULT"),
+        ExceptionVector::X87FloatingPoint => ("#MF", "X87 FLOATING-POINT ERROR"),
+        ExceptionVector::AlignmentCheck => ("#AC", "ALIGNMENT CHECK"),
+        ExceptionVector::MachineCheck => ("#MC", "MACHINE CHECK"),
+        ExceptionVector::SimdFloatingPoint => ("#XM", "SIMD FLOATING-POINT EXCEPTION"),
+        ExceptionVector::Virtualization => ("#VE", "VIRTUALIZATION EXCEPTION"),
+        ExceptionVector::ControlProtection => ("#CP", "CONTROL PROTECTION EXCEPTION"),
+        ExceptionVector::HypervisorInjection => ("#HV", "HYPERVISOR INJECTION EXCEPTION"),
+        ExceptionVector::VmmCommunication => ("#VC", "VMM COMMUNICATION EXCEPTION"),
+        ExceptionVector::Security => ("#SX", "SECURITY EXCEPTION"),
+        _ => ("#??", "UNKNOWN EXCEPTION"),
+    }
+}
+
+/// Writes to serial and the global console, each only if it can be locked
+/// right now.
+struct CrashWriter;
+
+impl Write for CrashWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        console::serial::try_print(format_args!("{}", s));
+        console::console::try_print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+/// Writes to the panic screen and serial, both taken over from whoever
+/// held them.
+struct PanicWriter {
+    screen: Option<PanicScreen>,
+    serial: Option<MutexGuard<'static, SerialPort>>,
+}
+
+impl PanicWriter {
+    /// Take over the console, unless this is a crash inside an earlier
+    /// report; the console may be what failed, so that one stays on serial.
+    fn begin() -> Self {
+        interrupts::disable();
+        let screen = if PANICKING.swap(true, Ordering::SeqCst) {
+            None
+        } else {
+            console::console::take_over_for_panic()
+        };
+        PanicWriter {
+            screen,
+            serial: console::serial::take_over_for_panic(),
+        }
+    }
+}
+
+impl Write for PanicWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if let Some(screen) = self.screen.as_mut() {
+            screen.write_str(s)?;
+        }
+        if let Some(serial) = self.serial.as_mut() {
+            serial.write_str(s)?;
+        }
+        Ok(())
+    }
+}
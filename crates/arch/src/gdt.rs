@@ -0,0 +1,104 @@
+//! Global descriptor table and task state segment.
+//!
+//! The GDT holds flat kernel and user code/data segments plus the TSS. It
+//! replaces the table left by the bootloader and must be loaded before
+//! `idt::init_idt`.
+//!
+//! - Segment order is kernel code, kernel data, user data, user code, which
+//!   is the layout `syscall`/`sysret` expect.
+//! - The TSS provides interrupt stack table (IST) entries so that the double
+//!   fault and page fault handlers run on stacks of their own. That keeps
+//!   them working when a kernel stack overflows into its guard page.
+//! - Privilege stack 0 is the stack the CPU switches to when an interrupt
+//!   arrives in ring 3.
+use memory::paging::vmm;
+use spin::Once;
+use x86_64::{
+    VirtAddr,
+    instructions::{
+        segmentation::{CS, DS, ES, SS, Segment},
+        tables::load_tss,
+    },
+    structures::{
+        gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector},
+        tss::TaskStateSegment,
+    },
+};
+
+/// IST slot used by the double fault handler.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// IST slot used by the page fault handler.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+/// Size of each IST stack (a guard page sits below every one).
+const IST_STACK_SIZE: u64 = 16 * 1024;
+/// Size of the ring 0 stack used on interrupts from user mode.
+const PRIVILEGE_STACK_SIZE: u64 = 64 * 1024;
+
+/// Segment selectors of the kernel GDT.
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    pub tss: SegmentSelector,
+}
+
+static TSS: Once<TaskStateSegment> = Once::new();
+static GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
+
+/// Load the kernel GDT and TSS and reload the segment registers.
+///
+/// The TSS stacks come from the kernel VMM, so paging must be initialized.
+/// Must run before `idt::init_idt`.
+pub fn init() {
+    let tss: &'static TaskStateSegment = TSS.call_once(|| {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            allocate_stack(IST_STACK_SIZE, "double fault stack");
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] =
+            allocate_stack(IST_STACK_SIZE, "page fault stack");
+        tss.privilege_stack_table[0] = allocate_stack(PRIVILEGE_STACK_SIZE, "ring 0 stack");
+        tss
+    });
+
+    let (gdt, selectors) = GDT.call_once(|| {
+        let mut gdt = GlobalDescriptorTable::new();
+        let kernel_code = gdt.append(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.append(Descriptor::kernel_data_segment());
+        let user_data = gdt.append(Descriptor::user_data_segment());
+        let user_code = gdt.append(Descriptor::user_code_segment());
+        let tss = gdt.append(Descriptor::tss_segment(tss));
+        (
+            gdt,
+            Selectors {
+                kernel_code,
+                kernel_data,
+                user_data,
+                user_code,
+                tss,
+            },
+        )
+    });
+
+    gdt.load();
+    unsafe {
+        CS::set_reg(selectors.kernel_code);
+        SS::set_reg(selectors.kernel_data);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
+    }
+}
+
+/// Selectors of the loaded GDT, or `None` before `init`.
+pub fn selectors() -> Option<&'static Selectors> {
+    GDT.get().map(|(_, selectors)| selectors)
+}
+
+fn allocate_stack(size: u64, name: &'static str) -> VirtAddr {
+    match vmm::allocate_stack(size, name) {
+        Ok(stack) => stack.top,
+        Err(e) => panic!("cannot allocate {}: {:?}", name, e),
+    }
+}
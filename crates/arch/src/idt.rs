@@ -1,10 +1,21 @@
+use crate::apic::SPURIOUS_VECTOR;
+use crate::gdt::{DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX};
 use crate::interrupt_handlers::{
-    general_protection_fault_handler, invalid_opcode_handler, keyboard_interrupt_handler,
-    page_fault_handler, timer_interrupt_handler,
+    alignment_check_entry, bound_range_exceeded_entry, breakpoint_handler, cp_protection_entry,
+    debug_handler, device_not_available_entry, divide_error_entry, double_fault_entry,
+    general_protection_fault_entry, hv_injection_entry, invalid_opcode_entry, invalid_tss_entry,
+    irq3_handler, irq4_handler, irq5_handler, irq6_handler, irq7_handler, irq8_handler,
+    irq9_handler, irq10_handler, irq11_handler, irq12_handler, irq13_handler, irq14_handler,
+    irq15_handler, keyboard_interrupt_handler, machine_check_entry, non_maskable_interrupt_entry,
+    overflow_entry, page_fault_entry, security_exception_entry, segment_not_present_entry,
+    simd_floating_point_entry, spurious_interrupt_handler, stack_segment_fault_entry,
+    timer_interrupt_handler, virtualization_entry, vmm_communication_entry,
+    x87_floating_point_entry,
 };
 use crate::pic::PIC_1_OFFSET;
 use spin::once::Once;
-use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::VirtAddr;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
 static IDT: Once<InterruptDescriptorTable> = Once::new();
 
@@ -21,14 +32,87 @@ impl InterruptIndex {
     }
 }
 
+/// Address of a naked exception entry stub.
+fn entry_addr(entry: extern "C" fn() -> !) -> VirtAddr {
+    VirtAddr::new(entry as usize as u64)
+}
+
+/// Build and load the IDT. `gdt::init` must run first, since the double
+/// fault and page fault handlers use its IST stacks.
+///
+/// Fatal exceptions go through the register-saving entry stubs in
+/// `interrupt_handlers`; breakpoints, debug traps and IRQs use ordinary
+/// `x86-interrupt` handlers.
 pub fn init_idt() {
     let mut idt: InterruptDescriptorTable = InterruptDescriptorTable::new();
-    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
-    idt.general_protection_fault
-        .set_handler_fn(general_protection_fault_handler);
-    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.breakpoint.set_handler_fn(breakpoint_handler);
+    unsafe {
+        idt.divide_error
+            .set_handler_addr(entry_addr(divide_error_entry));
+        idt.non_maskable_interrupt
+            .set_handler_addr(entry_addr(non_maskable_interrupt_entry));
+        idt.overflow.set_handler_addr(entry_addr(overflow_entry));
+        idt.bound_range_exceeded
+            .set_handler_addr(entry_addr(bound_range_exceeded_entry));
+        idt.invalid_opcode
+            .set_handler_addr(entry_addr(invalid_opcode_entry));
+        idt.device_not_available
+            .set_handler_addr(entry_addr(device_not_available_entry));
+        idt.invalid_tss
+            .set_handler_addr(entry_addr(invalid_tss_entry));
+        idt.segment_not_present
+            .set_handler_addr(entry_addr(segment_not_present_entry));
+        idt.stack_segment_fault
+            .set_handler_addr(entry_addr(stack_segment_fault_entry));
+        idt.general_protection_fault
+            .set_handler_addr(entry_addr(general_protection_fault_entry));
+        idt.x87_floating_point
+            .set_handler_addr(entry_addr(x87_floating_point_entry));
+        idt.alignment_check
+            .set_handler_addr(entry_addr(alignment_check_entry));
+        idt.machine_check
+            .set_handler_addr(entry_addr(machine_check_entry));
+        idt.simd_floating_point
+            .set_handler_addr(entry_addr(simd_floating_point_entry));
+        idt.virtualization
+            .set_handler_addr(entry_addr(virtualization_entry));
+        idt.cp_protection_exception
+            .set_handler_addr(entry_addr(cp_protection_entry));
+        idt.hv_injection_exception
+            .set_handler_addr(entry_addr(hv_injection_entry));
+        idt.vmm_communication_exception
+            .set_handler_addr(entry_addr(vmm_communication_entry));
+        idt.security_exception
+            .set_handler_addr(entry_addr(security_exception_entry));
+        idt.double_fault
+            .set_handler_addr(entry_addr(double_fault_entry))
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+        idt.page_fault
+            .set_handler_addr(entry_addr(page_fault_entry))
+            .set_stack_index(PAGE_FAULT_IST_INDEX);
+    }
     idt[InterruptIndex::Timer.as_u8()].set_handler_fn(timer_interrupt_handler);
     idt[InterruptIndex::Keyboard.as_u8()].set_handler_fn(keyboard_interrupt_handler);
+    let irq_handlers: [(u8, extern "x86-interrupt" fn(InterruptStackFrame)); 13] = [
+        (3, irq3_handler),
+        (4, irq4_handler),
+        (5, irq5_handler),
+        (6, irq6_handler),
+        (7, irq7_handler),
+        (8, irq8_handler),
+        (9, irq9_handler),
+        (10, irq10_handler),
+        (11, irq11_handler),
+        (12, irq12_handler),
+        (13, irq13_handler),
+        (14, irq14_handler),
+        (15, irq15_handler),
+    ];
+    for (irq, handler) in irq_handlers {
+        idt[PIC_1_OFFSET + irq].set_handler_fn(handler);
+    }
+    idt[SPURIOUS_VECTOR].set_handler_fn(spurious_interrupt_handler);
 
     let idt_ref: &InterruptDescriptorTable = IDT.call_once(|| idt);
     idt_ref.load();
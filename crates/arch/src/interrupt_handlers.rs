@@ -1,14 +1,12 @@
-use crate::{idt::InterruptIndex, interrupts};
-use console::serial_println;
+use crate::crash::{self, ErrorCode, Registers};
+use crate::{idt::InterruptIndex, interrupts, timer};
+use core::arch::naked_asm;
 use x86_64::{
-    VirtAddr,
-    addr::VirtAddrNotValid,
     instructions::{
         hlt,
         port::{Port, PortGeneric, ReadWriteAccess},
     },
-    registers::control::Cr2,
-    structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    structures::idt::{ExceptionVector, InterruptStackFrame, InterruptStackFrameValue},
 };
 
 fn halt_loop() -> ! {
@@ -17,7 +15,98 @@ fn halt_loop() -> ! {
     }
 }
 
+/// Stack contents when an entry stub calls `exception_dispatch`.
+#[repr(C)]
+struct ExceptionContext {
+    registers: Registers,
+    vector: u64,
+    /// Error code pushed by the CPU, or 0 for vectors without one.
+    error_code: u64,
+    stack_frame: InterruptStackFrameValue,
+}
+
+/// Define an entry stub for a fatal exception.
+///
+/// Fatal exceptions enter through naked stubs instead of `x86-interrupt`
+/// handlers so the crash report sees the general-purpose registers exactly
+/// as they were when the exception hit. Each stub pushes a dummy error code
+/// (unless the CPU pushed one) and its vector number, then jumps to
+/// `exception_common`. Install the stubs with `set_handler_addr`.
+macro_rules! exception_entry {
+    ($entry:ident, $vector:literal) => {
+        #[unsafe(naked)]
+        pub extern "C" fn $entry() -> ! {
+            naked_asm!(
+                "push 0",
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym exception_common,
+            )
+        }
+    };
+    ($entry:ident, $vector:literal, error_code) => {
+        #[unsafe(naked)]
+        pub extern "C" fn $entry() -> ! {
+            naked_asm!(
+                "push {vector}",
+                "jmp {common}",
+                vector = const $vector,
+                common = sym exception_common,
+            )
+        }
+    };
+}
+
+/// Save the general-purpose registers and call `exception_dispatch` with a
+/// pointer to the resulting `ExceptionContext`.
+///
+/// The CPU aligns the stack to 16 bytes before pushing its frame; the frame,
+/// error code, vector and 15 registers add up to 176 bytes, so the stack is
+/// still aligned at the call.
+#[unsafe(naked)]
+extern "C" fn exception_common() -> ! {
+    naked_asm!(
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push r11",
+        "push r10",
+        "push r9",
+        "push r8",
+        "push rbp",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push rcx",
+        "push rbx",
+        "push rax",
+        "cld",
+        "mov rdi, rsp",
+        "call {dispatch}",
+        "ud2",
+        dispatch = sym exception_dispatch,
+    )
+}
+
+extern "C" fn exception_dispatch(context: &ExceptionContext) -> ! {
+    match ExceptionVector::try_from(context.vector as u8) {
+        Ok(vector) => crash::report_fatal(
+            vector,
+            &context.stack_frame,
+            ErrorCode::for_vector(vector, context.error_code),
+            Some(&context.registers),
+        ),
+        Err(_) => {
+            console::serial_println!("EXCEPTION: unknown vector {}", context.vector);
+        }
+    }
+    halt_loop();
+}
+
 pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    timer::on_tick();
     interrupts::end_of_interrupt(InterruptIndex::Timer);
 }
 
@@ -29,33 +118,72 @@ pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: Interrupt
     interrupts::end_of_interrupt(InterruptIndex::Keyboard);
 }
 
-pub extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
-    serial_println!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
-    halt_loop();
+/// Define an `x86-interrupt` entry point for legacy IRQ `$irq` that hands
+/// it to `interrupts::dispatch_irq`.
+macro_rules! irq_entry {
+    ($($handler:ident => $irq:literal),* $(,)?) => {
+        $(
+            pub extern "x86-interrupt" fn $handler(_stack_frame: InterruptStackFrame) {
+                interrupts::dispatch_irq($irq);
+            }
+        )*
+    };
 }
 
-pub extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: u64,
-) {
-    serial_println!(
-        "EXCEPTION: GENERAL PROTECTION FAULT (code={:#x})\n{:#?}",
-        error_code,
-        stack_frame
+irq_entry!(
+    irq3_handler => 3,
+    irq4_handler => 4,
+    irq5_handler => 5,
+    irq6_handler => 6,
+    irq7_handler => 7,
+    irq8_handler => 8,
+    irq9_handler => 9,
+    irq10_handler => 10,
+    irq11_handler => 11,
+    irq12_handler => 12,
+    irq13_handler => 13,
+    irq14_handler => 14,
+    irq15_handler => 15,
+);
+
+/// Spurious local APIC interrupts are dropped without an EOI.
+pub extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// `int3` only reports and then resumes execution.
+pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    crash::report(
+        ExceptionVector::Breakpoint,
+        &stack_frame,
+        ErrorCode::None,
+        None,
     );
-    halt_loop();
 }
 
-pub extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
-) {
-    let addr: Result<VirtAddr, VirtAddrNotValid> = Cr2::read();
-    serial_println!(
-        "EXCEPTION: PAGE FAULT\naddr={:#x} error={:?}\n{:#?}",
-        addr.expect("why").as_u64(),
-        error_code,
-        stack_frame
-    );
-    halt_loop();
+/// Debug traps (single step, hardware breakpoints) report and resume.
+pub extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    crash::report(ExceptionVector::Debug, &stack_frame, ErrorCode::None, None);
 }
+
+exception_entry!(divide_error_entry, 0);
+exception_entry!(non_maskable_interrupt_entry, 2);
+exception_entry!(overflow_entry, 4);
+exception_entry!(bound_range_exceeded_entry, 5);
+exception_entry!(invalid_opcode_entry, 6);
+exception_entry!(device_not_available_entry, 7);
+exception_entry!(double_fault_entry, 8, error_code);
+exception_entry!(invalid_tss_entry, 10, error_code);
+exception_entry!(segment_not_present_entry, 11, error_code);
+exception_entry!(stack_segment_fault_entry, 12, error_code);
+exception_entry!(general_protection_fault_entry, 13, error_code);
+// Runs on its own IST stack (see `gdt`), so a fault on a guard page can be
+// reported even though the faulting stack is exhausted.
+exception_entry!(page_fault_entry, 14, error_code);
+exception_entry!(x87_floating_point_entry, 16);
+exception_entry!(alignment_check_entry, 17, error_code);
+exception_entry!(machine_check_entry, 18);
+exception_entry!(simd_floating_point_entry, 19);
+exception_entry!(virtualization_entry, 20);
+exception_entry!(cp_protection_entry, 21, error_code);
+exception_entry!(hv_injection_entry, 28);
+exception_entry!(vmm_communication_entry, 29, error_code);
+exception_entry!(security_exception_entry, 30, error_code);
@@ -1,12 +1,40 @@
+use crate::apic;
 use crate::idt::InterruptIndex;
-use crate::pic::{self, InterruptController};
-use spin::Once;
+use crate::pic::{self, InterruptController, IrqKind};
+use console::serial_println;
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+
+/// Number of legacy IRQ lines.
+pub const IRQ_COUNT: usize = 16;
+
+/// Driver callback for a legacy IRQ line, called with the line that fired.
+pub type IrqHandler = fn(u8);
 
 static CONTROLLER: Once<&'static (dyn InterruptController + Sync)> = Once::new();
+/// Driver handlers for legacy IRQ lines, run by `dispatch_irq`.
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+/// Errors returned by `register_irq_handler`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The line is not a legacy IRQ or is owned by the kernel (timer,
+    /// keyboard, cascade).
+    Reserved(u8),
+    /// Another driver already handles the line.
+    InUse(u8),
+    /// The active controller cannot deliver the line, e.g. a PCI INTx line
+    /// under the I/O APIC, which would need the ACPI `_PRT` routing.
+    Unroutable(u8),
+}
 
+/// Pick and initialize the interrupt controller: the APIC when ACPI
+/// describes one (see `apic::apic_controller`), the 8259 PIC otherwise.
+/// `acpi::init` must run first for the APIC to be found.
 pub fn init_interrupts() {
-    CONTROLLER.call_once(|| pic::pic_controller());
+    CONTROLLER.call_once(|| apic::apic_controller().unwrap_or_else(pic::pic_controller));
     controller().init();
+    serial_println!("interrupts: using {}", controller().name());
 }
 
 fn controller() -> &'static dyn InterruptController {
@@ -18,3 +46,64 @@ fn controller() -> &'static dyn InterruptController {
 pub fn end_of_interrupt(index: InterruptIndex) {
     controller().end_of_interrupt(index);
 }
+
+/// Route legacy IRQ `irq` to vector `PIC_1_OFFSET + irq` and unmask it.
+pub fn enable_irq(irq: u8, kind: IrqKind) -> Result<(), IrqError> {
+    controller().enable_irq(irq, kind)
+}
+
+/// Acknowledge legacy IRQ `irq` at the active controller.
+pub fn end_of_irq(irq: u8) {
+    controller().end_of_irq(irq);
+}
+
+/// Install `handler` for legacy IRQ `irq`, then route and unmask the line.
+/// If the line cannot be routed the handler is removed again.
+///
+/// The handler runs in interrupt context and must quiet the device (for a
+/// level-triggered PCI line, acknowledge it at the device) before returning;
+/// `dispatch_irq` sends the EOI afterwards.
+pub fn register_irq_handler(irq: u8, kind: IrqKind, handler: IrqHandler) -> Result<(), IrqError> {
+    if !is_driver_irq(irq) {
+        return Err(IrqError::Reserved(irq));
+    }
+    interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot: &mut Option<IrqHandler> = &mut handlers[irq as usize];
+        if slot.is_some() {
+            return Err(IrqError::InUse(irq));
+        }
+        *slot = Some(handler);
+        Ok(())
+    })?;
+    enable_irq(irq, kind).inspect_err(|_| unregister_irq_handler(irq))
+}
+
+/// Remove the handler for `irq`. The line stays unmasked, so the device must
+/// already have stopped interrupting (e.g. by being reset).
+pub fn unregister_irq_handler(irq: u8) {
+    if is_driver_irq(irq) {
+        interrupts::without_interrupts(|| IRQ_HANDLERS.lock()[irq as usize] = None);
+    }
+}
+
+/// Run the handler registered for `irq` and acknowledge it. Called by the
+/// IRQ entry points in `interrupt_handlers`. Spurious IRQs are dropped.
+pub fn dispatch_irq(irq: u8) {
+    if controller().handle_spurious(irq) {
+        return;
+    }
+    // Handlers are only registered with interrupts disabled, so the lock is
+    // never held here.
+    let handler: Option<IrqHandler> = IRQ_HANDLERS.lock()[irq as usize];
+    if let Some(handler) = handler {
+        handler(irq);
+    }
+    end_of_irq(irq);
+}
+
+/// IRQ lines drivers may claim: everything except the timer (0), the
+/// keyboard (1) and the PIC cascade (2).
+fn is_driver_irq(irq: u8) -> bool {
+    (3..IRQ_COUNT as u8).contains(&irq)
+}
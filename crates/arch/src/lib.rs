@@ -1,9 +1,20 @@
 #![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 #![feature(abi_x86_interrupt)]
 
+extern crate alloc;
+
+pub mod apic;
+pub mod clock;
+pub mod crash;
+pub mod gdt;
 pub mod idt;
 pub mod interrupt_handlers;
 pub mod interrupts;
 pub mod pic;
 pub mod pci;
+pub mod power;
+pub mod rtc;
+pub mod stack;
+pub mod symbols;
+pub mod timer;
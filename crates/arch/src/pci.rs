@@ -13,6 +13,11 @@
 //! - If header_type bit7 is set, the device is multi-function; scan 0..7.
 //! - class/subclass/prog-if/revision are packed into the dword at offset 0x08.
 //! - virtio devices use vendor_id 0x1AF4.
+//! - Memory BARs are mapped uncached with `map_bar` (see `memory::map_mmio`).
+//! - Status bit 4 marks a capability list, linked from the pointer at 0x34
+//!   (see `capabilities`).
+use alloc::vec::Vec;
+use memory::{MmioRegion, map_mmio, paging::VmmError};
 use x86_64::instructions::port::Port;
 
 const CONFIG_ADDRESS: u16 = 0x0cf8;
@@ -45,6 +50,7 @@ const WORD_SHIFT_STEP: u8 = 8;
 
 const STATUS_MASK: u32 = 0xffff_0000;
 const COMMAND_IO_SPACE: u16 = 0x0001;
+const COMMAND_MEMORY_SPACE: u16 = 0x0002;
 const COMMAND_BUS_MASTER: u16 = 0x0004;
 
 const MAX_BUS: u16 = 255;
@@ -53,6 +59,20 @@ const FUNCTION_COUNT_MULTIFUNCTION: u8 = 8;
 const FUNCTION_COUNT_SINGLE: u8 = 1;
 const INVALID_VENDOR_ID: u16 = 0xffff;
 
+const STATUS_CAPABILITIES_LIST: u32 = 1 << 20;
+const CAPABILITIES_POINTER_OFFSET: u8 = 0x34;
+const CAPABILITY_POINTER_MASK: u8 = 0xfc;
+const CAPABILITY_NEXT_SHIFT: u32 = 8;
+const CAPABILITY_LIST_END: u8 = 0;
+/// Upper bound on list entries, so a looping list cannot hang the scan.
+const MAX_CAPABILITIES: usize = 48;
+
+const INTERRUPT_OFFSET: u8 = 0x3c;
+const INTERRUPT_PIN_SHIFT: u32 = 8;
+const INTERRUPT_PIN_NONE: u8 = 0;
+const INTERRUPT_LINE_UNROUTED: u8 = 0xff;
+const LEGACY_IRQ_COUNT: u8 = 16;
+
 const BAR_UPPER_OFFSET: u8 = 4;
 const LAST_BAR_INDEX: u8 = 5;
 
@@ -100,6 +120,34 @@ pub struct Bar {
     pub size: Option<u64>,
 }
 
+impl Bar {
+    /// Returns true for memory BARs (32- or 64-bit).
+    pub fn is_mmio(&self) -> bool {
+        matches!(self.kind, BarKind::Mmio32 | BarKind::Mmio64)
+    }
+}
+
+/// Entry in a function's capability list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capability {
+    pub id: u8,
+    /// Config space offset of the capability header.
+    pub offset: u8,
+}
+
+/// Errors returned by `map_bar`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarMapError {
+    /// The BAR is unimplemented or the index is out of range.
+    Missing,
+    /// The BAR decodes I/O ports, not memory.
+    NotMmio,
+    /// The BAR size could not be determined.
+    UnknownSize,
+    /// Mapping the BAR into kernel virtual space failed.
+    Map(VmmError),
+}
+
 /// Read a 32-bit value from PCI config space via 0xCF8/0xCFC.
 /// Encodes BDF and a dword-aligned offset into 0xCF8, then reads from 0xCFC.
 fn read_config_dword(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
@@ -153,6 +201,64 @@ pub fn enable_io_bus_master(bus: u8, device: u8, function: u8) {
     );
 }
 
+/// Enable PCI command bits (memory space and bus mastering).
+pub fn enable_memory_bus_master(bus: u8, device: u8, function: u8) {
+    let value = read_config_dword(bus, device, function, COMMAND_STATUS_OFFSET);
+    let cmd = (value & WORD_MASK) as u16;
+    let status = value & STATUS_MASK;
+    let new_cmd = cmd | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER;
+    write_config_dword(
+        bus,
+        device,
+        function,
+        COMMAND_STATUS_OFFSET,
+        status | (new_cmd as u32),
+    );
+}
+
+/// Legacy IRQ line the firmware routed the device's INTx pin to, from the
+/// interrupt line register. `None` if the device has no interrupt pin or
+/// the pin was left unrouted.
+pub fn interrupt_line(bus: u8, device: u8, function: u8) -> Option<u8> {
+    let value = read_config_dword(bus, device, function, INTERRUPT_OFFSET);
+    let line = value as u8;
+    let pin = (value >> INTERRUPT_PIN_SHIFT) as u8;
+    if pin == INTERRUPT_PIN_NONE || line == INTERRUPT_LINE_UNROUTED || line >= LEGACY_IRQ_COUNT {
+        None
+    } else {
+        Some(line)
+    }
+}
+
+/// Read the config space dword containing `offset`, for registers this
+/// module does not decode (e.g. vendor-specific capabilities).
+pub fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    read_config_dword(bus, device, function, offset)
+}
+
+/// Walk the capability list of a function. Empty if the status register
+/// says there is none.
+pub fn capabilities(bus: u8, device: u8, function: u8) -> Vec<Capability> {
+    let mut found: Vec<Capability> = Vec::new();
+    if read_config_dword(bus, device, function, COMMAND_STATUS_OFFSET) & STATUS_CAPABILITIES_LIST
+        == 0
+    {
+        return found;
+    }
+    let mut offset: u8 = read_config_dword(bus, device, function, CAPABILITIES_POINTER_OFFSET)
+        as u8
+        & CAPABILITY_POINTER_MASK;
+    while offset != CAPABILITY_LIST_END && found.len() < MAX_CAPABILITIES {
+        let header: u32 = read_config_dword(bus, device, function, offset);
+        found.push(Capability {
+            id: header as u8,
+            offset,
+        });
+        offset = (header >> CAPABILITY_NEXT_SHIFT) as u8 & CAPABILITY_POINTER_MASK;
+    }
+    found
+}
+
 /// Read a 16-bit value from PCI config space.
 /// Reads the containing dword, then selects lower/upper 16 bits by offset bit 1.
 fn read_config_word(bus: u8, device: u8, function: u8, offset: u8) -> u16 {
@@ -278,6 +384,19 @@ pub fn read_bar(bus: u8, device: u8, function: u8, index: u8) -> Option<Bar> {
     })
 }
 
+/// Map a memory BAR uncached into kernel virtual space.
+///
+/// Memory decoding is not enabled here; call `enable_memory_bus_master`
+/// before touching the registers.
+pub fn map_bar(bus: u8, device: u8, function: u8, index: u8) -> Result<MmioRegion, BarMapError> {
+    let bar = read_bar(bus, device, function, index).ok_or(BarMapError::Missing)?;
+    if !bar.is_mmio() {
+        return Err(BarMapError::NotMmio);
+    }
+    let size = bar.size.ok_or(BarMapError::UnknownSize)?;
+    map_mmio(bar.base, size).map_err(BarMapError::Map)
+}
+
 fn bar_size_io(bus: u8, device: u8, function: u8, offset: u8, original: u32) -> Option<u64> {
     // Write all 1s, read back the size mask, then restore original value.
     write_config_dword(bus, device, function, offset, BAR_SIZE_PROBE_VALUE);
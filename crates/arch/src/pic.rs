@@ -1,12 +1,47 @@
 use pic8259::ChainedPics;
 use spin::{Mutex, Once};
+use x86_64::instructions::port::Port;
+
+use crate::interrupts::IrqError;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+const PIC_1_COMMAND_PORT: u16 = 0x20;
+const PIC_2_COMMAND_PORT: u16 = 0xa0;
+/// OCW3: the next read of the command port returns the in-service register.
+const OCW3_READ_ISR: u8 = 0x0b;
+/// IRQ line of the master the slave PIC is cascaded on.
+const CASCADE_IRQ: u8 = 2;
+/// Lowest-priority line of each PIC, where it reports spurious interrupts.
+const MASTER_SPURIOUS_IRQ: u8 = 7;
+const SLAVE_SPURIOUS_IRQ: u8 = 15;
+/// In-service bit of the lowest-priority line, on either PIC.
+const ISR_LOWEST_PRIORITY: u8 = 1 << 7;
+
+/// How a legacy IRQ line is signaled when the firmware does not say.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqKind {
+    /// ISA device: edge triggered, active high.
+    Isa,
+    /// PCI INTx: level triggered, active low.
+    Pci,
+}
+
 pub trait InterruptController {
     fn init(&self);
     fn end_of_interrupt(&self, irq: crate::idt::InterruptIndex);
+    /// Unmask legacy IRQ line `irq`. It is delivered at vector
+    /// `PIC_1_OFFSET + irq` whichever controller is active.
+    fn enable_irq(&self, irq: u8, kind: IrqKind) -> Result<(), IrqError>;
+    /// Acknowledge legacy IRQ line `irq`.
+    fn end_of_irq(&self, irq: u8);
+    /// Check whether legacy IRQ `irq` is spurious, and if so acknowledge
+    /// whatever the controller requires. A spurious IRQ must be neither
+    /// dispatched nor passed to `end_of_irq`.
+    fn handle_spurious(&self, irq: u8) -> bool;
+    /// Controller name for boot logs.
+    fn name(&self) -> &'static str;
 }
 
 pub struct Pic8259Controller {
@@ -25,6 +60,57 @@ impl InterruptController for Pic8259Controller {
             self.pics.lock().notify_end_of_interrupt(irq.as_u8());
         }
     }
+
+    fn enable_irq(&self, irq: u8, _kind: IrqKind) -> Result<(), IrqError> {
+        let mut pics = self.pics.lock();
+        unsafe {
+            let [mut mask1, mut mask2] = pics.read_masks();
+            if irq < 8 {
+                mask1 &= !(1 << irq);
+            } else {
+                mask2 &= !(1 << (irq - 8));
+                // The slave PIC is cascaded on IRQ 2.
+                mask1 &= !(1 << 2);
+            }
+            pics.write_masks(mask1, mask2);
+        }
+        Ok(())
+    }
+
+    fn end_of_irq(&self, irq: u8) {
+        unsafe {
+            self.pics.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+        }
+    }
+
+    /// A PIC raises its lowest-priority line (IRQ 7 or 15) when a request
+    /// goes away before it is acknowledged; the in-service bit is then
+    /// clear. The master did see a real request on the cascade line for a
+    /// spurious IRQ 15, so it still needs its EOI.
+    fn handle_spurious(&self, irq: u8) -> bool {
+        let command_port: u16 = match irq {
+            MASTER_SPURIOUS_IRQ => PIC_1_COMMAND_PORT,
+            SLAVE_SPURIOUS_IRQ => PIC_2_COMMAND_PORT,
+            _ => return false,
+        };
+        let mut pics = self.pics.lock();
+        let mut command: Port<u8> = Port::new(command_port);
+        let in_service: u8 = unsafe {
+            command.write(OCW3_READ_ISR);
+            command.read()
+        };
+        if in_service & ISR_LOWEST_PRIORITY != 0 {
+            return false;
+        }
+        if irq == SLAVE_SPURIOUS_IRQ {
+            unsafe { pics.notify_end_of_interrupt(PIC_1_OFFSET + CASCADE_IRQ) };
+        }
+        true
+    }
+
+    fn name(&self) -> &'static str {
+        "8259 PIC"
+    }
 }
 
 impl Pic8259Controller {
@@ -48,3 +134,13 @@ pub fn pic_controller() -> &'static (dyn InterruptController + Sync) {
     PIC8259_CONTROLLER.call_once(Pic8259Controller::new);
     PIC8259_CONTROLLER.get().unwrap()
 }
+
+/// Remap the 8259 PICs away from the exception vectors and mask every line,
+/// for when the APIC takes over.
+pub fn disable_legacy_pics() {
+    let mut pics = unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) };
+    unsafe {
+        pics.initialize();
+        pics.disable();
+    }
+}
@@ -0,0 +1,158 @@
+//! Power off and reboot.
+//!
+//! Both go through ACPI first (FADT PM1 control for S5, the FADT reset
+//! register for reboot) and fall back to legacy mechanisms: the 8042
+//! keyboard controller reset line and, as a last resort, a triple fault.
+//! `qemu_exit` leaves QEMU through its `isa-debug-exit` device for test runs.
+use acpi::{AcpiTables, AddressSpace, Fadt, GenericAddress};
+use console::serial_println;
+use memory::paging;
+use x86_64::{
+    instructions::{hlt, interrupts, port::Port},
+    structures::idt::InterruptDescriptorTable,
+};
+
+/// PM1 control: enable the SCI (set once the OS owns ACPI).
+const PM1_SCI_EN: u16 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u16 = 10;
+/// PM1 control: enter the sleep state selected by `SLP_TYP`.
+const PM1_SLP_EN: u16 = 1 << 13;
+/// Polls of a status register before giving up on the hardware.
+const POLL_LIMIT: usize = 1_000_000;
+
+const KBC_STATUS_PORT: u16 = 0x64;
+const KBC_INPUT_FULL: u8 = 1 << 1;
+/// 8042 command: pulse the CPU reset line.
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+/// I/O port of QEMU's `-device isa-debug-exit,iobase=0xf4,iosize=0x04`.
+const QEMU_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+/// Exit codes for `qemu_exit`. QEMU exits with status `(code << 1) | 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+/// Power the machine off, halting forever if every method fails.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    if let Some(tables) = acpi::tables() {
+        acpi_soft_off(tables);
+    }
+    serial_println!("power: shutdown failed, halting");
+    loop {
+        hlt();
+    }
+}
+
+/// Restart the machine.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Some(fadt) = acpi::tables().and_then(|tables| tables.fadt.as_ref())
+        && let Some((register, value)) = fadt.reset_command()
+    {
+        write_reset_register(register, value);
+    }
+    if acpi::tables()
+        .and_then(|tables| tables.fadt.as_ref())
+        .is_none_or(Fadt::has_8042)
+    {
+        keyboard_controller_reset();
+    }
+    serial_println!("power: reset failed, forcing a triple fault");
+    triple_fault()
+}
+
+/// Exit QEMU with `code` through the `isa-debug-exit` device. Returns only if
+/// the device is absent.
+pub fn qemu_exit(code: QemuExitCode) {
+    let mut port: Port<u32> = Port::new(QEMU_DEBUG_EXIT_PORT);
+    unsafe { port.write(code as u32) };
+}
+
+/// Enter S5 through PM1a/PM1b control. Returns if the firmware gave no S5
+/// sleep type or the write had no effect.
+fn acpi_soft_off(tables: &AcpiTables) {
+    let (Some(fadt), Some(s5)) = (tables.fadt.as_ref(), tables.s5_sleep_type) else {
+        serial_println!("power: no ACPI S5 information");
+        return;
+    };
+    if fadt.pm1a_control_block == 0 {
+        return;
+    }
+    enable_acpi_mode(fadt);
+
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    unsafe {
+        let value: u16 = pm1a.read() & !(0b111 << PM1_SLP_TYP_SHIFT);
+        pm1a.write(value | (s5.pm1a as u16) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+        if fadt.pm1b_control_block != 0 {
+            let mut pm1b: Port<u16> = Port::new(fadt.pm1b_control_block as u16);
+            let value: u16 = pm1b.read() & !(0b111 << PM1_SLP_TYP_SHIFT);
+            pm1b.write(value | (s5.pm1b as u16) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN);
+        }
+    }
+}
+
+/// Ask the firmware to hand ACPI over from SMM if it has not already.
+fn enable_acpi_mode(fadt: &Fadt) {
+    let mut pm1a: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if unsafe { pm1a.read() } & PM1_SCI_EN != 0 || fadt.smi_command == 0 || fadt.acpi_enable == 0 {
+        return;
+    }
+    let mut smi: Port<u8> = Port::new(fadt.smi_command as u16);
+    unsafe { smi.write(fadt.acpi_enable) };
+    for _ in 0..POLL_LIMIT {
+        if unsafe { pm1a.read() } & PM1_SCI_EN != 0 {
+            return;
+        }
+        core::hint::spin_loop();
+    }
+    serial_println!("power: firmware did not enable ACPI mode");
+}
+
+fn write_reset_register(register: GenericAddress, value: u8) {
+    match register.space {
+        AddressSpace::SystemIo => {
+            let mut port: Port<u8> = Port::new(register.address as u16);
+            unsafe { port.write(value) };
+        }
+        AddressSpace::SystemMemory => {
+            if let Some(offset) = paging::physical_memory_offset() {
+                let ptr: *mut u8 = (offset + register.address).as_mut_ptr();
+                unsafe { ptr.write_volatile(value) };
+            }
+        }
+        space => {
+            serial_println!("power: reset register in unsupported space {:?}", space);
+        }
+    }
+}
+
+fn keyboard_controller_reset() {
+    let mut status: Port<u8> = Port::new(KBC_STATUS_PORT);
+    for _ in 0..POLL_LIMIT {
+        if unsafe { status.read() } & KBC_INPUT_FULL == 0 {
+            break;
+        }
+        core::hint::spin_loop();
+    }
+    unsafe { status.write(KBC_PULSE_RESET) };
+    // The reset line takes a moment to act.
+    for _ in 0..POLL_LIMIT {
+        core::hint::spin_loop();
+    }
+}
+
+/// Load an empty IDT and raise an exception: with no handler for the
+/// exception or the resulting double fault, the CPU resets.
+fn triple_fault() -> ! {
+    static EMPTY_IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
+    unsafe {
+        EMPTY_IDT.load_unsafe();
+        core::arch::asm!("int3", options(noreturn));
+    }
+}
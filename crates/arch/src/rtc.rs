@@ -0,0 +1,353 @@
+//! CMOS real-time clock and wall-clock time.
+//!
+//! The RTC is read once by `init`; afterwards `now` adds the uptime since
+//! then to that reading instead of going back to the slow CMOS ports.
+//! Times are whatever the firmware keeps in the RTC, which is UTC on QEMU.
+use core::fmt;
+use spin::Once;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::timer;
+
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+/// Century register on most PCs when the FADT does not name one.
+const REG_CENTURY_DEFAULT: u8 = 0x32;
+
+/// Status A: an update is in progress, the time registers are unstable.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: hours are in 24-hour format.
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Status B: values are binary rather than BCD.
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Set in the hours register for PM in 12-hour format.
+const HOUR_PM: u8 = 1 << 7;
+
+/// Polls of the update-in-progress flag before reading anyway.
+const POLL_LIMIT: usize = 100_000;
+/// Attempts at getting two identical consecutive readings.
+const READ_ATTEMPTS: usize = 8;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+/// Days from 0000-03-01 to 1970-01-01.
+const UNIX_EPOCH_DAYS: i64 = 719_468;
+const DAYS_PER_ERA: i64 = 146_097;
+
+/// Calendar date and time of day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00, or `None` for an invalid date or
+    /// one before 1970.
+    pub fn to_unix(&self) -> Option<u64> {
+        if !self.is_valid() {
+            return None;
+        }
+        let days: i64 = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+        let days: u64 = u64::try_from(days).ok()?;
+        Some(
+            days * SECONDS_PER_DAY
+                + self.hour as u64 * 3600
+                + self.minute as u64 * 60
+                + self.second as u64,
+        )
+    }
+
+    /// True if every field is in range, including the day for the month.
+    pub fn is_valid(&self) -> bool {
+        (1..=12).contains(&self.month)
+            && (1..=days_in_month(self.year, self.month)).contains(&self.day)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    pub fn from_unix(seconds: u64) -> Self {
+        let (year, month, day): (u64, u64, u64) = civil_from_days(seconds / SECONDS_PER_DAY);
+        let time: u64 = seconds % SECONDS_PER_DAY;
+        Self {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// RTC reading at `init`, as Unix seconds, and the uptime in nanoseconds at
+/// that time.
+static BOOT_TIME: Once<(u64, u64)> = Once::new();
+
+/// Read the RTC and anchor the wall clock to the current uptime.
+/// `timer::init` must run first.
+///
+/// An RTC holding an invalid date or one before 1970 reads as the epoch.
+pub fn init() -> DateTime {
+    let time: DateTime = read();
+    BOOT_TIME.call_once(|| (time.to_unix().unwrap_or(0), timer::uptime_ns()));
+    time
+}
+
+/// Current wall-clock time in Unix seconds.
+pub fn unix_time() -> u64 {
+    match BOOT_TIME.get() {
+        Some(&(seconds, uptime_ns)) => {
+            seconds + (timer::uptime_ns() - uptime_ns) / NANOS_PER_SECOND
+        }
+        None => read().to_unix().unwrap_or(0),
+    }
+}
+
+/// Current wall-clock time.
+pub fn now() -> DateTime {
+    DateTime::from_unix(unix_time())
+}
+
+/// Read the date and time straight from the CMOS registers.
+///
+/// The registers are read until two consecutive readings agree, so a
+/// reading that straddles an RTC update is discarded.
+pub fn read() -> DateTime {
+    interrupts::without_interrupts(|| {
+        let mut last: RawTime = RawTime::read();
+        for _ in 0..READ_ATTEMPTS {
+            let current: RawTime = RawTime::read();
+            if current == last {
+                break;
+            }
+            last = current;
+        }
+        last.decode(read_register(REG_STATUS_B))
+    })
+}
+
+/// Register values as stored, before BCD and 12-hour decoding.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+impl RawTime {
+    fn read() -> Self {
+        for _ in 0..POLL_LIMIT {
+            if read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        Self {
+            second: read_register(REG_SECONDS),
+            minute: read_register(REG_MINUTES),
+            hour: read_register(REG_HOURS),
+            day: read_register(REG_DAY),
+            month: read_register(REG_MONTH),
+            year: read_register(REG_YEAR),
+            century: century_register().map(read_register),
+        }
+    }
+
+    fn decode(self, status_b: u8) -> DateTime {
+        let binary: bool = status_b & STATUS_B_BINARY != 0;
+        let value = |raw: u8| if binary { raw } else { from_bcd(raw) };
+
+        let pm: bool = self.hour & HOUR_PM != 0;
+        let mut hour: u8 = value(self.hour & !HOUR_PM);
+        if status_b & STATUS_B_24_HOUR == 0 {
+            // 12-hour clock: 12 AM is midnight, 12 PM is noon.
+            hour %= 12;
+            if pm {
+                hour += 12;
+            }
+        }
+
+        let year: u16 = value(self.year) as u16;
+        let century: u16 = match self.century.map(value) {
+            Some(century) if (19..=99).contains(&century) => century as u16,
+            _ => 20,
+        };
+        DateTime {
+            year: century * 100 + year,
+            month: value(self.month),
+            day: value(self.day),
+            hour,
+            minute: value(self.minute),
+            second: value(self.second),
+        }
+    }
+}
+
+/// Century register named by the FADT, or the conventional one. ACPI
+/// machines without a FADT entry have no century register at all.
+fn century_register() -> Option<u8> {
+    match acpi::tables().and_then(|tables| tables.fadt.as_ref()) {
+        Some(fadt) if fadt.century != 0 => Some(fadt.century),
+        Some(_) => None,
+        None => Some(REG_CENTURY_DEFAULT),
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    unsafe {
+        address.write(register);
+        data.read()
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400))
+}
+
+/// Length of `month` (1-12) in `year`.
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 (negative before) for a valid proleptic Gregorian
+/// date.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Count from 0000-03-01 so the leap day ends the year.
+    let year: i64 = if month <= 2 { year - 1 } else { year };
+    let era: i64 = year.div_euclid(400);
+    let year_of_era: i64 = year.rem_euclid(400);
+    let day_of_year: i64 = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era: i64 = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS
+}
+
+/// Inverse of `days_from_civil` for days on or after 1970-01-01.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days: u64 = days + UNIX_EPOCH_DAYS as u64;
+    let era: u64 = days / DAYS_PER_ERA as u64;
+    let day_of_era: u64 = days % DAYS_PER_ERA as u64;
+    let year_of_era: u64 =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year: u64 = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp: u64 = (5 * day_of_year + 2) / 153;
+    let day: u64 = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month: u64 = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year: u64 = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DateTime, civil_from_days, days_from_civil};
+
+    fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(date(1970, 1, 1, 0, 0, 0).to_unix(), Some(0));
+        assert_eq!(DateTime::from_unix(0), date(1970, 1, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn known_dates() {
+        assert_eq!(date(2000, 2, 29, 12, 0, 0).to_unix(), Some(951_825_600));
+        assert_eq!(date(2038, 1, 19, 3, 14, 8).to_unix(), Some(1 << 31));
+        assert_eq!(
+            DateTime::from_unix(1_700_000_000),
+            date(2023, 11, 14, 22, 13, 20)
+        );
+    }
+
+    #[test]
+    fn round_trips_across_leap_years_and_centuries() {
+        for days in (0..150_000).step_by(97) {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(
+                days_from_civil(year as i64, month as i64, day as i64),
+                days as i64
+            );
+        }
+        for seconds in [
+            0,
+            59,
+            86_399,
+            86_400,
+            951_782_400,
+            4_107_542_399,
+            4_107_542_400,
+        ] {
+            assert_eq!(DateTime::from_unix(seconds).to_unix(), Some(seconds));
+        }
+    }
+
+    #[test]
+    fn dates_before_1970_have_no_unix_time() {
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(1900, 3, 1), -25_508);
+        assert_eq!(date(1969, 12, 31, 23, 59, 59).to_unix(), None);
+        assert_eq!(date(1925, 6, 1, 0, 0, 0).to_unix(), None);
+    }
+
+    #[test]
+    fn out_of_range_fields_are_invalid() {
+        assert_eq!(date(2024, 0, 1, 0, 0, 0).to_unix(), None);
+        assert_eq!(date(2024, 13, 1, 0, 0, 0).to_unix(), None);
+        assert_eq!(date(2024, 1, 0, 0, 0, 0).to_unix(), None);
+        assert_eq!(date(2023, 2, 29, 0, 0, 0).to_unix(), None);
+        assert_eq!(date(2100, 2, 29, 0, 0, 0).to_unix(), None);
+        assert_eq!(date(2024, 4, 31, 0, 0, 0).to_unix(), None);
+        assert_eq!(date(2024, 1, 1, 24, 0, 0).to_unix(), None);
+        assert_eq!(date(2024, 1, 1, 0, 60, 0).to_unix(), None);
+        assert_eq!(date(2024, 1, 1, 0, 0, 60).to_unix(), None);
+        assert!(date(2024, 2, 29, 23, 59, 59).is_valid());
+    }
+}
@@ -0,0 +1,27 @@
+//! Switching the CPU onto a freshly allocated stack.
+use core::arch::asm;
+
+use x86_64::VirtAddr;
+
+/// Switch to the stack ending at `top` and call `entry(arg)` on it.
+///
+/// The current stack is abandoned. The frame pointer is cleared so that
+/// backtraces stop at `entry`.
+///
+/// # Safety
+/// `top` must be the 16-byte aligned top of a mapped stack that nothing else
+/// uses, and nothing on the current stack may be referenced afterwards.
+pub unsafe fn switch_stack(top: VirtAddr, entry: extern "C" fn(u64) -> !, arg: u64) -> ! {
+    unsafe {
+        asm!(
+            "mov rsp, {top}",
+            "xor ebp, ebp",
+            "call {entry}",
+            "ud2",
+            top = in(reg) top.as_u64(),
+            entry = in(reg) entry,
+            in("rdi") arg,
+            options(noreturn)
+        )
+    }
+}
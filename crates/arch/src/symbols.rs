@@ -0,0 +1,128 @@
+//! Kernel symbol table used to symbolize backtraces.
+//!
+//! The table is generated from the kernel ELF when `os-runner` builds the
+//! boot image and is handed to the kernel as the bootloader ramdisk.
+//! Layout (all integers little endian):
+//! - header: magic `BSYM`, version (u32), symbol count (u32), reserved (u32)
+//! - `count` entries of { address: u64, size: u64, name_offset: u32,
+//!   name_len: u32 }, sorted by address; addresses are link-time addresses
+//! - UTF-8 names, with offsets relative to the end of the entry array
+use spin::Once;
+
+const MAGIC: &[u8; 4] = b"BSYM";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 16;
+const ENTRY_LEN: usize = 24;
+
+/// Errors reported by `init`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolError {
+    /// The data does not start with the `BSYM` magic.
+    BadMagic,
+    /// The table was written by an incompatible `os-runner`.
+    UnsupportedVersion(u32),
+    /// The entry array or a name lies outside the data.
+    Truncated,
+}
+
+/// A symbol covering a looked-up address.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol {
+    pub name: &'static str,
+    /// Distance of the address from the start of the symbol.
+    pub offset: u64,
+}
+
+struct SymbolTable {
+    entries: &'static [u8],
+    names: &'static [u8],
+    count: usize,
+    /// Added to link-time addresses to get run-time addresses.
+    load_offset: u64,
+}
+
+impl SymbolTable {
+    fn entry(&self, index: usize) -> (u64, u64, usize, usize) {
+        let raw = &self.entries[index * ENTRY_LEN..(index + 1) * ENTRY_LEN];
+        (
+            read_u64(raw, 0),
+            read_u64(raw, 8),
+            read_u32(raw, 16) as usize,
+            read_u32(raw, 20) as usize,
+        )
+    }
+
+    fn lookup(&self, addr: u64) -> Option<Symbol> {
+        let addr = addr.checked_sub(self.load_offset)?;
+        // Last entry starting at or below `addr`.
+        let index = partition_point(self.count, |i| self.entry(i).0 <= addr).checked_sub(1)?;
+        let (start, size, name_offset, name_len) = self.entry(index);
+        if size != 0 && addr - start >= size {
+            return None;
+        }
+        let name = self.names.get(name_offset..name_offset + name_len)?;
+        Some(Symbol {
+            name: core::str::from_utf8(name).unwrap_or("<invalid symbol name>"),
+            offset: addr - start,
+        })
+    }
+}
+
+static SYMBOLS: Once<SymbolTable> = Once::new();
+
+/// Install the symbol table. `load_offset` is the kernel's relocation
+/// offset (`BootInfo::kernel_image_offset`). Returns the number of symbols.
+pub fn init(data: &'static [u8], load_offset: u64) -> Result<usize, SymbolError> {
+    if data.len() < HEADER_LEN || &data[..4] != MAGIC {
+        return Err(SymbolError::BadMagic);
+    }
+    let version = read_u32(data, 4);
+    if version != VERSION {
+        return Err(SymbolError::UnsupportedVersion(version));
+    }
+    let count = read_u32(data, 8) as usize;
+    let entries_end = count
+        .checked_mul(ENTRY_LEN)
+        .and_then(|len| len.checked_add(HEADER_LEN))
+        .filter(|end| *end <= data.len())
+        .ok_or(SymbolError::Truncated)?;
+
+    let table = SYMBOLS.call_once(|| SymbolTable {
+        entries: &data[HEADER_LEN..entries_end],
+        names: &data[entries_end..],
+        count,
+        load_offset,
+    });
+    Ok(table.count)
+}
+
+/// The symbol containing the run-time address `addr`, if a table is loaded.
+pub fn lookup(addr: u64) -> Option<Symbol> {
+    SYMBOLS.get()?.lookup(addr)
+}
+
+/// Index of the first `i` in `0..len` for which `pred(i)` is false.
+fn partition_point(len: usize, pred: impl Fn(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if pred(mid) {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
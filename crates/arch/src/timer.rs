@@ -0,0 +1,187 @@
+//! System tick and timer callbacks.
+//!
+//! The PIT (channel 0) is programmed to interrupt `TICK_HZ` times per second
+//! and every timer interrupt advances a monotonic tick counter. Uptime,
+//! `sleep_ms` and the timer callbacks are all derived from that counter, so
+//! another tick source (the local APIC timer or the HPET) only has to call
+//! `on_tick` at the same rate.
+//!
+//! Callbacks run in interrupt context with interrupts disabled: they must be
+//! short, must not block and must not allocate.
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use spin::Mutex;
+use x86_64::instructions::{hlt, interrupts, port::Port};
+
+/// Input clock of the PIT in Hz.
+pub const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+/// Timer interrupts per second.
+pub const TICK_HZ: u64 = 1000;
+/// Reload value giving `TICK_HZ` (rounded to the nearest divisor).
+const PIT_DIVISOR: u16 = ((PIT_FREQUENCY_HZ + TICK_HZ / 2) / TICK_HZ) as u16;
+
+const PIT_CHANNEL0_PORT: u16 = 0x40;
+const PIT_COMMAND_PORT: u16 = 0x43;
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary.
+const PIT_CHANNEL0_RATE_GENERATOR: u8 = 0b0011_0100;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+/// Maximum number of pending timer callbacks.
+const MAX_TIMERS: usize = 16;
+
+/// Identifies a pending callback for `cancel`.
+///
+/// Carries the generation of the registration as well as its slot, so an id
+/// kept after its timer finished cannot cancel a later timer in the same
+/// slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    index: usize,
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// All `MAX_TIMERS` slots are in use.
+    NoFreeSlot,
+    /// Periodic timers need a period of at least one tick.
+    ZeroPeriod,
+}
+
+#[derive(Clone, Copy)]
+struct Timer {
+    /// Tick at which the callback runs next.
+    deadline: u64,
+    /// Reload interval in ticks for periodic timers.
+    period: Option<u64>,
+    callback: fn(),
+    /// Distinguishes this registration from earlier ones in the same slot.
+    generation: u64,
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static TIMERS: Mutex<[Option<Timer>; MAX_TIMERS]> = Mutex::new([None; MAX_TIMERS]);
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Program PIT channel 0 to fire `TICK_HZ` times per second.
+pub fn init() {
+    let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut channel0: Port<u8> = Port::new(PIT_CHANNEL0_PORT);
+    unsafe {
+        command.write(PIT_CHANNEL0_RATE_GENERATOR);
+        channel0.write(PIT_DIVISOR as u8);
+        channel0.write((PIT_DIVISOR >> 8) as u8);
+    }
+}
+
+/// Advance the tick counter and run due callbacks. Called from the timer
+/// interrupt handler.
+pub fn on_tick() {
+    let now: u64 = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+
+    // Collect due callbacks first so they run without the table locked and
+    // may schedule or cancel timers themselves. The table is only ever
+    // locked with interrupts disabled, so `try_lock` fails only if a
+    // callback is being registered from another context.
+    let mut due: [Option<fn()>; MAX_TIMERS] = [None; MAX_TIMERS];
+    if let Some(mut timers) = TIMERS.try_lock() {
+        for (slot, due) in timers.iter_mut().zip(due.iter_mut()) {
+            if let Some(timer) = slot
+                && timer.deadline <= now
+            {
+                *due = Some(timer.callback);
+                match timer.period {
+                    Some(period) => timer.deadline = now.saturating_add(period),
+                    None => *slot = None,
+                }
+            }
+        }
+    }
+    for callback in due.into_iter().flatten() {
+        callback();
+    }
+}
+
+/// Timer interrupts since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since `init`, at tick resolution.
+pub fn uptime_ns() -> u64 {
+    ticks_to_ns(ticks())
+}
+
+/// Milliseconds since `init`, at tick resolution.
+pub fn uptime_ms() -> u64 {
+    uptime_ns() / 1_000_000
+}
+
+/// Sleep for at least `ms` milliseconds, halting between ticks.
+///
+/// Interrupts must be enabled, otherwise the tick never advances.
+pub fn sleep_ms(ms: u64) {
+    let target: u64 = ticks().saturating_add(ms_to_ticks(ms).max(1));
+    while ticks() < target {
+        hlt();
+    }
+}
+
+/// Run `callback` once, `ms` milliseconds from now.
+pub fn call_after(ms: u64, callback: fn()) -> Result<TimerId, TimerError> {
+    add_timer(ms_to_ticks(ms).max(1), None, callback)
+}
+
+/// Run `callback` every `ms` milliseconds until cancelled.
+pub fn call_every(ms: u64, callback: fn()) -> Result<TimerId, TimerError> {
+    let period: u64 = ms_to_ticks(ms);
+    if period == 0 {
+        return Err(TimerError::ZeroPeriod);
+    }
+    add_timer(period, Some(period), callback)
+}
+
+/// Cancel a pending callback. Returns false if it already ran (one-shot) or
+/// was cancelled before.
+pub fn cancel(id: TimerId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut timers = TIMERS.lock();
+        let slot: &mut Option<Timer> = &mut timers[id.index];
+        if slot.is_some_and(|timer| timer.generation == id.generation) {
+            *slot = None;
+            true
+        } else {
+            false
+        }
+    })
+}
+
+fn add_timer(delay: u64, period: Option<u64>, callback: fn()) -> Result<TimerId, TimerError> {
+    interrupts::without_interrupts(|| {
+        let deadline: u64 = ticks().saturating_add(delay);
+        let mut timers = TIMERS.lock();
+        let index: usize = timers
+            .iter()
+            .position(Option::is_none)
+            .ok_or(TimerError::NoFreeSlot)?;
+        let generation: u64 = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
+        timers[index] = Some(Timer {
+            deadline,
+            period,
+            callback,
+            generation,
+        });
+        Ok(TimerId { index, generation })
+    })
+}
+
+/// Ticks covering at least `ms` milliseconds.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(TICK_HZ).div_ceil(1000)
+}
+
+fn ticks_to_ns(ticks: u64) -> u64 {
+    // One tick lasts PIT_DIVISOR / PIT_FREQUENCY_HZ seconds.
+    (ticks as u128 * PIT_DIVISOR as u128 * NANOS_PER_SECOND as u128 / PIT_FREQUENCY_HZ as u128)
+        as u64
+}
@@ -0,0 +1,236 @@
+#![no_std]
+
+//! Block devices and the registry disks are opened from.
+//!
+//! Drivers implement `BlockDevice` and hand their devices to `register`,
+//! which names them after a prefix (`vda`, `vdb`, ...). Anything that needs
+//! a disk after boot (filesystems, partition tables, the shell) looks it up
+//! with `open`.
+
+extern crate alloc;
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use spin::Mutex;
+
+/// Errors reported by block devices and the registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the last block.
+    OutOfRange,
+    /// The buffer is not a whole number of blocks.
+    UnalignedBuffer,
+    /// The device does not accept writes.
+    ReadOnly,
+    /// The driver or the device failed the request.
+    Io(&'static str),
+    /// No device is registered under the name.
+    NotFound,
+    /// Every name for the prefix is taken.
+    NoFreeName,
+}
+
+/// A disk-like device addressed in fixed-size blocks.
+pub trait BlockDevice: Send {
+    /// Size of one block in bytes.
+    fn block_size(&self) -> usize;
+
+    /// Number of blocks on the device.
+    fn block_count(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Read blocks starting at `lba` into `buf`, whose length must be a
+    /// multiple of the block size.
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Write `buf` to the blocks starting at `lba`.
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Make completed writes durable.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Check that a transfer of `len` bytes at `lba` fits `device`. Drivers
+/// call this before touching the hardware.
+pub fn check_request(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<(), BlockError> {
+    let block_size: usize = device.block_size();
+    if !len.is_multiple_of(block_size) {
+        return Err(BlockError::UnalignedBuffer);
+    }
+    let end: Option<u64> = lba.checked_add((len / block_size) as u64);
+    if end.is_none_or(|end| end > device.block_count()) {
+        return Err(BlockError::OutOfRange);
+    }
+    Ok(())
+}
+
+/// A registered device, shared between everyone who opened it.
+pub type SharedDevice = Arc<Mutex<dyn BlockDevice>>;
+
+struct Entry {
+    name: String,
+    device: SharedDevice,
+}
+
+static DEVICES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+/// Register `device` under the first free name `prefix` + letter (`vda`,
+/// `vdb`, ...) and return that name.
+pub fn register<D>(prefix: &str, device: D) -> Result<String, BlockError>
+where
+    D: BlockDevice + 'static,
+{
+    let mut devices = DEVICES.lock();
+    let name: String = (b'a'..=b'z')
+        .map(|letter| format!("{}{}", prefix, letter as char))
+        .find(|name| devices.iter().all(|entry| entry.name != *name))
+        .ok_or(BlockError::NoFreeName)?;
+    devices.push(Entry {
+        name: name.clone(),
+        device: Arc::new(Mutex::new(device)),
+    });
+    Ok(name)
+}
+
+/// Open the device registered as `name`.
+pub fn open(name: &str) -> Result<SharedDevice, BlockError> {
+    DEVICES
+        .lock()
+        .iter()
+        .find(|entry| entry.name == name)
+        .map(|entry| entry.device.clone())
+        .ok_or(BlockError::NotFound)
+}
+
+/// Names of all registered devices, in registration order.
+pub fn names() -> Vec<String> {
+    DEVICES
+        .lock()
+        .iter()
+        .map(|entry| entry.name.clone())
+        .collect()
+}
+
+/// List the registered devices with their geometry.
+pub fn dump_devices(console: &mut impl Write) {
+    let devices = DEVICES.lock();
+    if devices.is_empty() {
+        writeln!(console, "no block devices").ok();
+        return;
+    }
+    for entry in devices.iter() {
+        let device = entry.device.lock();
+        let bytes: u64 = device.block_count() * device.block_size() as u64;
+        writeln!(
+            console,
+            "{:6} {:10} blocks x {:4} bytes ({} MiB){}",
+            entry.name,
+            device.block_count(),
+            device.block_size(),
+            bytes / (1024 * 1024),
+            if device.read_only() { " ro" } else { "" }
+        )
+        .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use crate::{BlockDevice, BlockError, check_request, open, register};
+
+    /// A device with a fixed geometry that ignores transfers. The registry
+    /// is shared by every test, so each test registers under its own prefix.
+    struct NullDevice {
+        block_count: u64,
+    }
+
+    impl BlockDevice for NullDevice {
+        fn block_size(&self) -> usize {
+            512
+        }
+
+        fn block_count(&self) -> u64 {
+            self.block_count
+        }
+
+        fn read_blocks(&mut self, _lba: u64, _buf: &mut [u8]) -> Result<(), BlockError> {
+            Ok(())
+        }
+
+        fn write_blocks(&mut self, _lba: u64, _buf: &[u8]) -> Result<(), BlockError> {
+            Ok(())
+        }
+    }
+
+    fn device(block_count: u64) -> NullDevice {
+        NullDevice { block_count }
+    }
+
+    #[test]
+    fn names_count_up_from_a() {
+        assert_eq!(register("na", device(1)).unwrap(), "naa");
+        assert_eq!(register("na", device(1)).unwrap(), "nab");
+        for _ in b'c'..=b'z' {
+            register("na", device(1)).unwrap();
+        }
+        assert_eq!(
+            register("na", device(1)).unwrap_err(),
+            BlockError::NoFreeName
+        );
+    }
+
+    #[test]
+    fn open_finds_the_registered_device() {
+        let name: String = register("op", device(8)).unwrap();
+        assert_eq!(open(&name).unwrap().lock().block_count(), 8);
+        assert!(matches!(open("opz"), Err(BlockError::NotFound)));
+    }
+
+    #[test]
+    fn requests_must_be_whole_blocks() {
+        assert_eq!(check_request(&device(8), 0, 512), Ok(()));
+        assert_eq!(check_request(&device(8), 0, 0), Ok(()));
+        assert_eq!(
+            check_request(&device(8), 0, 100),
+            Err(BlockError::UnalignedBuffer)
+        );
+        assert_eq!(
+            check_request(&device(8), 0, 513),
+            Err(BlockError::UnalignedBuffer)
+        );
+    }
+
+    #[test]
+    fn requests_must_end_on_the_device() {
+        assert_eq!(check_request(&device(8), 7, 512), Ok(()));
+        assert_eq!(check_request(&device(8), 0, 8 * 512), Ok(()));
+        assert_eq!(
+            check_request(&device(8), 7, 2 * 512),
+            Err(BlockError::OutOfRange)
+        );
+        assert_eq!(
+            check_request(&device(8), 8, 512),
+            Err(BlockError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn lba_overflow_is_out_of_range() {
+        let huge: NullDevice = device(u64::MAX);
+        assert_eq!(check_request(&huge, u64::MAX - 1, 512), Ok(()));
+        assert_eq!(
+            check_request(&huge, u64::MAX, 512),
+            Err(BlockError::OutOfRange)
+        );
+    }
+}
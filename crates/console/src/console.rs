@@ -6,7 +6,7 @@ use graphics::{
     graphics_trait::FrameBuffer,
     renderer::{self, Renderer},
 };
-use spin::{Mutex, Once};
+use spin::{Mutex, MutexGuard, Once};
 
 pub type KernelConsole = TextConsole<'static, BeyondFramebuffer<'static>>;
 
@@ -43,6 +43,63 @@ pub fn _print(args: core::fmt::Arguments) {
     }
 }
 
+/// Print to the global console unless it is currently locked.
+///
+/// For fault handlers, which must not wait on a lock the interrupted code
+/// may hold. Returns false if nothing was printed.
+pub fn try_print(args: core::fmt::Arguments) -> bool {
+    match CONSOLE.get().and_then(|console| console.try_lock()) {
+        Some(mut locked) => locked.write_fmt(args).is_ok(),
+        None => false,
+    }
+}
+
+/// Full-screen crash output that owns the global console.
+///
+/// Output stops at the last row instead of scrolling, so the first lines
+/// (message and fault details) stay visible.
+pub struct PanicScreen {
+    console: MutexGuard<'static, KernelConsole>,
+    full: bool,
+}
+
+/// Forcibly take over the global console and clear it to the panic screen.
+///
+/// The console lock is broken if it is held: the holder was interrupted by
+/// the crash and never runs again. Returns `None` before `init_console`.
+pub fn take_over_for_panic() -> Option<PanicScreen> {
+    let console: &'static Mutex<KernelConsole> = CONSOLE.get()?;
+    if console.is_locked() {
+        unsafe { console.force_unlock() };
+    }
+    let mut locked = console.lock();
+    locked.fg = Color::white();
+    locked.bg = Color::panic_red();
+    locked.clear();
+    Some(PanicScreen {
+        console: locked,
+        full: false,
+    })
+}
+
+impl Write for PanicScreen {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for ch in s.chars() {
+            if self.full {
+                break;
+            }
+            let console: &mut KernelConsole = &mut self.console;
+            let wraps: bool = ch == '\n' || console.cursor_col + 1 >= console.cols;
+            if wraps && console.cursor_row + 1 >= console.rows {
+                self.full = true;
+                continue;
+            }
+            ConsoleOut::write_charactor(console, ch);
+        }
+        Ok(())
+    }
+}
+
 fn with_console<R>(f: impl FnOnce(&mut KernelConsole) -> R) -> Option<R> {
     CONSOLE.get().map(|c| {
         let mut guard = c.lock();
@@ -33,3 +33,12 @@ macro_rules! serial_println {
         $crate::serial_print!("\n");
     };
 }
+
+/// Like `serial_println!`, but the line is dropped if the port is locked.
+/// For code that may run while the port is held, such as the allocator.
+#[macro_export]
+macro_rules! serial_try_println {
+    ($($arg:tt)*) => {
+        $crate::serial::try_print(core::format_args!("{}\n", core::format_args!($($arg)*)));
+    };
+}
@@ -1,5 +1,5 @@
 use core::{arch::asm, fmt::Write};
-use spin::{Mutex, Once};
+use spin::{Mutex, MutexGuard, Once};
 
 /// Base I/O port address for COM1.
 const COM1: u16 = 0x3F8;
@@ -76,6 +76,29 @@ pub fn _print(args: core::fmt::Arguments) {
     }
 }
 
+/// Print only if the port can be locked right now.
+///
+/// For code that must not wait on a lock the interrupted code may hold
+/// (fault handlers, the allocator). Returns false if nothing was printed.
+pub fn try_print(args: core::fmt::Arguments) -> bool {
+    match SERIAL1.get().and_then(|serial| serial.try_lock()) {
+        Some(mut port) => port.write_fmt(args).is_ok(),
+        None => false,
+    }
+}
+
+/// Forcibly take COM1 for a panic report.
+///
+/// The lock is broken if it is held: the holder was interrupted by the
+/// crash and never runs again. Returns `None` before `init_serial`.
+pub fn take_over_for_panic() -> Option<MutexGuard<'static, SerialPort>> {
+    let serial: &'static Mutex<SerialPort> = SERIAL1.get()?;
+    if serial.is_locked() {
+        unsafe { serial.force_unlock() };
+    }
+    Some(serial.lock())
+}
+
 #[inline]
 /// Write an 8-bit value to an I/O port using the x86 `out` instruction.
 ///
@@ -0,0 +1,601 @@
+//! virtio-blk: block devices with one request queue.
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::mem::{offset_of, size_of};
+use core::ptr;
+
+use arch::timer;
+use block::{BlockDevice, BlockError};
+use memory::{align_up_usize, paging};
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts as cpu_int;
+
+use crate::virtqueue::Buffer;
+use crate::{STATUS_FAILED, STATUS_RESET, Transport, Virtqueue};
+
+/// PCI device ID of a legacy (transitional) virtio-blk device.
+pub const DEVICE_ID_LEGACY: u16 = 0x1001;
+/// PCI device ID of a virtio 1.0 only virtio-blk device.
+pub const DEVICE_ID_MODERN: u16 = 0x1042;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+/// Feature bit: the device is read-only.
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// Features the driver understands.
+const SUPPORTED_FEATURES: u64 = VIRTIO_BLK_F_RO;
+
+const QUEUE_INDEX: u16 = 0;
+/// Queue size used when the transport lets the driver choose; each
+/// request needs at least three descriptors, so this covers
+/// `MAX_IN_FLIGHT` of them with room for multi-segment chains.
+const PREFERRED_QUEUE_SIZE: u16 = 128;
+/// Capacity in 512-byte sectors, at the start of the device configuration.
+const CONFIG_CAPACITY: u16 = 0;
+
+/// Descriptors in a one-segment request chain: header, data, status.
+const DESCS_PER_REQUEST: u16 = 3;
+/// Upper bound on requests in flight at once, each with its own header,
+/// status byte and sector buffer.
+const MAX_IN_FLIGHT: usize = 32;
+/// Largest transfer sent as one request. Its pages need at most
+/// `MAX_REQUEST_BYTES / PAGE_SIZE + 1` data descriptors.
+const MAX_REQUEST_BYTES: usize = 64 * 1024;
+/// Longest descriptor chain: header, data segments and status.
+const MAX_CHAIN_LEN: usize = MAX_REQUEST_BYTES / memory::PAGE_SIZE as usize + 3;
+const DESC_STATUS_LEN: u32 = 1;
+
+const REQUEST_STATUS_PENDING: u8 = 0xff;
+const REQUEST_STATUS_OK: u8 = 0x00;
+const REQUEST_TIMEOUT_MS: u64 = 1000;
+
+#[repr(C)]
+struct VirtioBlkReq {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+/// Device-readable header and device-writable status of one request.
+#[repr(C)]
+struct RequestHeader {
+    req: VirtioBlkReq,
+    status: u8,
+}
+
+const REQ_RESERVED: u32 = 0;
+/// Request area layout: `MAX_IN_FLIGHT` sector buffers, then the headers.
+const REQ_DATA_SIZE: usize = MAX_IN_FLIGHT * SECTOR_SIZE;
+const REQ_AREA_SIZE: usize = REQ_DATA_SIZE + MAX_IN_FLIGHT * size_of::<RequestHeader>();
+const ZERO_FILL: u8 = 0;
+
+/// Handle for a submitted request, passed to `VirtioBlk::wait`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    Free,
+    /// Submitted as the chain starting at descriptor `head`.
+    Pending {
+        head: u16,
+    },
+    /// Completed with the given virtio-blk status.
+    Done(u8),
+    /// Still owned by the device but no longer waited for; released when
+    /// it completes.
+    Abandoned {
+        head: u16,
+    },
+}
+
+/// One physically contiguous piece of a transfer buffer.
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    addr: u64,
+    len: u32,
+}
+
+pub struct VirtioBlk {
+    transport: Box<dyn Transport>,
+    queue: Virtqueue,
+    /// Request area: one sector buffer and one header per slot.
+    req_paddr: u64,
+    req_vaddr: *mut u8,
+    req_pages: usize,
+    slots: Vec<SlotState>,
+    capacity_sectors: u64,
+    read_only: bool,
+    /// Legacy IRQ the device interrupts on, or `None` when completions are
+    /// only noticed on timer ticks.
+    irq: Option<u8>,
+    /// The device was reset because a request into a caller's buffer timed
+    /// out; it takes no further requests.
+    reset: bool,
+}
+
+// The raw pointers only point into the queue and request area this driver
+// owns, so the device may move to another thread with its owner.
+unsafe impl Send for VirtioBlk {}
+
+impl VirtioBlk {
+    /// Initialize the virtio-blk device behind `transport`. `irq` is the line
+    /// from the PCI interrupt line register; without it (or if it cannot be
+    /// claimed) requests complete on timer ticks.
+    pub fn new(
+        mut transport: Box<dyn Transport>,
+        irq: Option<u8>,
+        phys_offset: u64,
+    ) -> Result<Self, &'static str> {
+        let features = crate::negotiate_features(transport.as_mut(), SUPPORTED_FEATURES)?;
+        let (queue, req_paddr, req_pages) = Self::setup(transport.as_mut(), phys_offset)
+            .inspect_err(|_| transport.set_status(STATUS_FAILED))?;
+        let slot_count = MAX_IN_FLIGHT.min((queue.size() / DESCS_PER_REQUEST) as usize);
+        let capacity_sectors = transport.read_config_u64(CONFIG_CAPACITY);
+        let irq = irq.and_then(|irq| claim_irq(irq, transport.as_ref()));
+        crate::finish_init(transport.as_mut());
+
+        Ok(VirtioBlk {
+            transport,
+            queue,
+            req_paddr,
+            req_vaddr: (phys_offset + req_paddr) as *mut u8,
+            req_pages,
+            slots: alloc::vec![SlotState::Free; slot_count],
+            capacity_sectors,
+            read_only: features & VIRTIO_BLK_F_RO != 0,
+            irq,
+            reset: false,
+        })
+    }
+
+    /// Create the request queue and the request area: a sector buffer and a
+    /// header per slot.
+    fn setup(
+        transport: &mut dyn Transport,
+        phys_offset: u64,
+    ) -> Result<(Virtqueue, u64, usize), &'static str> {
+        let max_queue_size = transport.max_queue_size(QUEUE_INDEX);
+        if max_queue_size == 0 {
+            return Err("virtio-blk: queue 0 not available");
+        }
+        let queue_size = if transport.supports_queue_resize() {
+            max_queue_size.min(PREFERRED_QUEUE_SIZE)
+        } else {
+            max_queue_size
+        };
+        let queue = Virtqueue::new(queue_size, phys_offset)?;
+        transport.setup_queue(QUEUE_INDEX, &queue)?;
+
+        let req_pages =
+            align_up_usize(REQ_AREA_SIZE, memory::PAGE_SIZE as usize) / memory::PAGE_SIZE as usize;
+        let req_paddr = memory::alloc_frames(req_pages, memory::PAGE_SIZE).ok_or_else(|| {
+            // The device knows the queue; stop it before the frames go back.
+            transport.set_status(STATUS_RESET);
+            "virtio-blk: no frames"
+        })?;
+        let req_vaddr = (phys_offset + req_paddr) as *mut u8;
+        unsafe { ptr::write_bytes(req_vaddr, ZERO_FILL, req_pages * memory::PAGE_SIZE as usize) };
+        Ok((queue, req_paddr, req_pages))
+    }
+
+    pub fn capacity_sectors(&self) -> u64 {
+        self.capacity_sectors
+    }
+
+    pub fn irq(&self) -> Option<u8> {
+        self.irq
+    }
+
+    pub fn read_sector(
+        &mut self,
+        sector: u64,
+        out: &mut [u8; SECTOR_SIZE],
+    ) -> Result<(), &'static str> {
+        let id = self.submit_read(sector)?;
+        out.copy_from_slice(self.wait(id)?);
+        Ok(())
+    }
+
+    pub fn write_sector(
+        &mut self,
+        sector: u64,
+        data: &[u8; SECTOR_SIZE],
+    ) -> Result<(), &'static str> {
+        let id = self.submit_write(sector, data)?;
+        self.wait(id)?;
+        Ok(())
+    }
+
+    /// Read `out.len()` bytes starting at sector `lba` straight into `out`,
+    /// whose length must be a multiple of the sector size.
+    ///
+    /// The buffer is split into requests of up to `MAX_REQUEST_BYTES`, each
+    /// a descriptor chain over the physical pages backing that part of the
+    /// buffer, and as many requests as possible are kept in flight.
+    pub fn read_blocks(&mut self, lba: u64, out: &mut [u8]) -> Result<(), &'static str> {
+        self.transfer(
+            VIRTIO_BLK_T_IN,
+            lba,
+            VirtAddr::from_ptr(out.as_mut_ptr()),
+            out.len(),
+        )
+    }
+
+    /// Write `data` starting at sector `lba`; see `read_blocks`.
+    pub fn write_blocks(&mut self, lba: u64, data: &[u8]) -> Result<(), &'static str> {
+        self.transfer(
+            VIRTIO_BLK_T_OUT,
+            lba,
+            VirtAddr::from_ptr(data.as_ptr()),
+            data.len(),
+        )
+    }
+
+    /// Queue a read of `sector` into the slot's own buffer without waiting
+    /// for it.
+    pub fn submit_read(&mut self, sector: u64) -> Result<RequestId, &'static str> {
+        self.submit_buffered(VIRTIO_BLK_T_IN, sector, None)
+    }
+
+    /// Queue a write of `data` to `sector` without waiting for it.
+    pub fn submit_write(
+        &mut self,
+        sector: u64,
+        data: &[u8; SECTOR_SIZE],
+    ) -> Result<RequestId, &'static str> {
+        self.submit_buffered(VIRTIO_BLK_T_OUT, sector, Some(data))
+    }
+
+    /// Wait for request `id` and release it. For reads the returned buffer
+    /// holds the sector; it is reused by the next submitted request.
+    pub fn wait(&mut self, id: RequestId) -> Result<&[u8; SECTOR_SIZE], &'static str> {
+        self.wait_status(id)?;
+        Ok(unsafe { &*(self.data_ptr(id.0) as *const [u8; SECTOR_SIZE]) })
+    }
+
+    /// Move completed requests from the used ring into their slots. Returns
+    /// how many completed.
+    pub fn poll(&mut self) -> usize {
+        let mut completed = 0;
+        while let Some(used) = self.queue.pop_used() {
+            let Some(slot) = self.slots.iter().position(|state| {
+                matches!(
+                    state,
+                    SlotState::Pending { head } | SlotState::Abandoned { head } if *head == used.head
+                )
+            }) else {
+                continue;
+            };
+            self.slots[slot] = match self.slots[slot] {
+                SlotState::Abandoned { .. } => SlotState::Free,
+                _ => {
+                    SlotState::Done(unsafe { ptr::read_volatile(&(*self.header_ptr(slot)).status) })
+                }
+            };
+            completed += 1;
+        }
+        completed
+    }
+
+    fn transfer(
+        &mut self,
+        req_type: u32,
+        lba: u64,
+        start: VirtAddr,
+        len: usize,
+    ) -> Result<(), &'static str> {
+        if !len.is_multiple_of(SECTOR_SIZE) {
+            return Err("virtio-blk: buffer is not a whole number of sectors");
+        }
+        if lba.saturating_add((len / SECTOR_SIZE) as u64) > self.capacity_sectors {
+            return Err("virtio-blk: transfer past the end of the disk");
+        }
+        // Waiting needs interrupts; find out before the device owns the buffer.
+        if !cpu_int::are_enabled() {
+            return Err("virtio-blk: requests need interrupts enabled");
+        }
+        let mut in_flight: VecDeque<RequestId> = VecDeque::new();
+        let mut offset = 0;
+        let mut stuck = false;
+        let result = 'transfer: loop {
+            while offset < len && self.free_slot().is_some() {
+                let chunk = (len - offset).min(MAX_REQUEST_BYTES);
+                let segments = match dma_segments(start + offset as u64, chunk) {
+                    Ok(segments) => segments,
+                    Err(e) => break 'transfer Err(e),
+                };
+                // Wait for earlier requests to free descriptors first.
+                if !in_flight.is_empty() && (self.queue.num_free() as usize) < segments.len() + 2 {
+                    break;
+                }
+                let sector = lba + (offset / SECTOR_SIZE) as u64;
+                match self.submit(req_type, sector, &segments) {
+                    Ok(id) => in_flight.push_back(id),
+                    Err(e) => break 'transfer Err(e),
+                }
+                offset += chunk;
+            }
+            let Some(id) = in_flight.pop_front() else {
+                break Ok(());
+            };
+            if let Err(e) = self.wait_status(id) {
+                stuck |= self.is_abandoned(id);
+                break Err(e);
+            }
+        };
+
+        // The device must be done with the buffer before the borrow ends.
+        for id in in_flight {
+            if self.wait_status(id).is_err() {
+                stuck |= self.is_abandoned(id);
+            }
+        }
+        if stuck {
+            self.reset_device();
+        }
+        result
+    }
+
+    /// Submit a one-sector request using the slot's own sector buffer.
+    fn submit_buffered(
+        &mut self,
+        req_type: u32,
+        sector: u64,
+        data: Option<&[u8; SECTOR_SIZE]>,
+    ) -> Result<RequestId, &'static str> {
+        let slot = self
+            .free_slot()
+            .ok_or("virtio-blk: too many requests in flight")?;
+        let data_ptr = self.data_ptr(slot);
+        if let Some(data) = data {
+            unsafe { ptr::copy_nonoverlapping(data.as_ptr(), data_ptr, SECTOR_SIZE) };
+        }
+        let segment = Segment {
+            addr: self.req_paddr + (data_ptr as u64 - self.req_vaddr as u64),
+            len: SECTOR_SIZE as u32,
+        };
+        self.submit(req_type, sector, &[segment])
+    }
+
+    /// Submit a request whose data is `segments`, in order, as one chain:
+    /// header, one descriptor per segment, status.
+    fn submit(
+        &mut self,
+        req_type: u32,
+        sector: u64,
+        segments: &[Segment],
+    ) -> Result<RequestId, &'static str> {
+        if self.reset {
+            return Err("virtio-blk: device was reset after a timeout");
+        }
+        let slot = self
+            .free_slot()
+            .ok_or("virtio-blk: too many requests in flight")?;
+        let chain_len = segments.len() + 2;
+        if chain_len > MAX_CHAIN_LEN || chain_len > self.queue.size() as usize {
+            return Err("virtio-blk: request has too many segments");
+        }
+
+        let header_ptr = self.header_ptr(slot);
+        unsafe {
+            header_ptr.write(RequestHeader {
+                req: VirtioBlkReq {
+                    req_type,
+                    reserved: REQ_RESERVED,
+                    sector,
+                },
+                status: REQUEST_STATUS_PENDING,
+            });
+        }
+        let header_paddr = self.req_paddr + (header_ptr as u64 - self.req_vaddr as u64);
+
+        let mut chain = [Buffer {
+            addr: header_paddr,
+            len: size_of::<VirtioBlkReq>() as u32,
+            device_writable: false,
+        }; MAX_CHAIN_LEN];
+        for (index, segment) in segments.iter().enumerate() {
+            chain[index + 1] = Buffer {
+                addr: segment.addr,
+                len: segment.len,
+                device_writable: req_type == VIRTIO_BLK_T_IN,
+            };
+        }
+        chain[chain_len - 1] = Buffer {
+            addr: header_paddr + offset_of!(RequestHeader, status) as u64,
+            len: DESC_STATUS_LEN,
+            device_writable: true,
+        };
+
+        let head = self
+            .queue
+            .add_chain(&chain[..chain_len])
+            .map_err(|_| "virtio-blk: queue full")?;
+        self.slots[slot] = SlotState::Pending { head };
+        self.transport.notify(QUEUE_INDEX);
+        Ok(RequestId(slot))
+    }
+
+    /// Wait for request `id` to complete and release its slot. A request
+    /// that times out is abandoned.
+    fn wait_status(&mut self, id: RequestId) -> Result<(), &'static str> {
+        if !cpu_int::are_enabled() {
+            return Err("virtio-blk: requests need interrupts enabled");
+        }
+        let deadline = timer::ticks().saturating_add(timer::ms_to_ticks(REQUEST_TIMEOUT_MS));
+        loop {
+            self.poll();
+            match self.slots.get(id.0).copied() {
+                Some(SlotState::Done(status)) => {
+                    self.slots[id.0] = SlotState::Free;
+                    if status != REQUEST_STATUS_OK {
+                        return Err("virtio-blk request failed");
+                    }
+                    return Ok(());
+                }
+                Some(SlotState::Pending { .. }) => {}
+                _ => return Err("virtio-blk: no such request"),
+            }
+            if !self.sleep_until_used(deadline) {
+                self.abandon(id);
+                return Err("virtio-blk request timed out");
+            }
+        }
+    }
+
+    /// Stop waiting for request `id`: a completed one is released now, a
+    /// pending one once the device is done with its buffers.
+    fn abandon(&mut self, id: RequestId) {
+        match self.slots[id.0] {
+            SlotState::Pending { head } => self.slots[id.0] = SlotState::Abandoned { head },
+            SlotState::Done(_) => self.slots[id.0] = SlotState::Free,
+            _ => {}
+        }
+    }
+
+    fn is_abandoned(&self, id: RequestId) -> bool {
+        matches!(self.slots[id.0], SlotState::Abandoned { .. })
+    }
+
+    /// Reset the device so it stops all DMA. Used when a request into a
+    /// caller's buffer never completed; the driver is unusable afterwards.
+    fn reset_device(&mut self) {
+        self.transport.set_status(STATUS_RESET);
+        self.reset = true;
+    }
+
+    fn free_slot(&self) -> Option<usize> {
+        self.slots
+            .iter()
+            .position(|state| *state == SlotState::Free)
+    }
+
+    fn header_ptr(&self, slot: usize) -> *mut RequestHeader {
+        unsafe { (self.req_vaddr.add(REQ_DATA_SIZE) as *mut RequestHeader).add(slot) }
+    }
+
+    fn data_ptr(&self, slot: usize) -> *mut u8 {
+        unsafe { self.req_vaddr.add(slot * SECTOR_SIZE) }
+    }
+
+    /// Sleep until the device adds to the used ring or the tick count
+    /// reaches `deadline`. Returns false on timeout.
+    ///
+    /// The device interrupt (or, without one, the next timer tick) wakes the
+    /// CPU from `hlt`. Interrupts stay disabled from the check until `hlt`,
+    /// so a completion cannot slip in after the check and leave the CPU
+    /// asleep until the next tick.
+    fn sleep_until_used(&self, deadline: u64) -> bool {
+        loop {
+            cpu_int::disable();
+            if self.queue.has_used() {
+                cpu_int::enable();
+                return true;
+            }
+            if timer::ticks() >= deadline {
+                cpu_int::enable();
+                return false;
+            }
+            cpu_int::enable_and_hlt();
+        }
+    }
+}
+
+/// Acknowledge the device's interrupts on legacy IRQ `irq`. Returns the
+/// IRQ if the handler was installed and the line is routed, otherwise
+/// `None` so completions are polled on timer ticks.
+fn claim_irq(irq: u8, transport: &dyn Transport) -> Option<u8> {
+    match crate::register_interrupt(irq, transport.isr()) {
+        Ok(()) => Some(irq),
+        Err(e) => {
+            console::serial_println!(
+                "virtio-blk: IRQ {} not usable ({:?}), polling on timer ticks",
+                irq,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Physical segments backing `[start, start + len)`, merging neighbouring
+/// pages that are physically contiguous.
+fn dma_segments(start: VirtAddr, len: usize) -> Result<Vec<Segment>, &'static str> {
+    // At most one segment per 4 KiB page. The vector is sized up front since
+    // nothing may allocate while the kernel mapper is locked.
+    let page_size = memory::PAGE_SIZE as usize;
+    let pages = (start.as_u64() as usize % page_size + len).div_ceil(page_size);
+    let mut segments: Vec<Segment> = Vec::with_capacity(pages);
+    paging::with_mapper(|mapper| {
+        let mut offset = 0;
+        while offset < len {
+            let virt = start + offset as u64;
+            let translation =
+                paging::translate(virt, mapper).map_err(|_| "virtio-blk: buffer is not mapped")?;
+            let page_size = translation.page_size as usize;
+            let in_page = page_size - (translation.phys.as_u64() as usize & (page_size - 1));
+            let chunk = (len - offset).min(in_page);
+            let phys = translation.phys.as_u64();
+            match segments.last_mut() {
+                Some(last) if last.addr + last.len as u64 == phys => last.len += chunk as u32,
+                _ => segments.push(Segment {
+                    addr: phys,
+                    len: chunk as u32,
+                }),
+            }
+            offset += chunk;
+        }
+        Ok(())
+    })
+    .ok_or("virtio-blk: no page tables")??;
+    Ok(segments)
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity_sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        block::check_request(self, lba, buf.len())?;
+        VirtioBlk::read_blocks(self, lba, buf).map_err(BlockError::Io)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_request(self, lba, buf.len())?;
+        VirtioBlk::write_blocks(self, lba, buf).map_err(BlockError::Io)
+    }
+
+    /// Writes are complete once the device reports them; VIRTIO_BLK_F_FLUSH
+    /// is not negotiated, so there is no cache to flush.
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+impl Drop for VirtioBlk {
+    fn drop(&mut self) {
+        // Stop the device from touching the queue before its pages are reused.
+        self.transport.set_status(STATUS_RESET);
+        if let Some(irq) = self.irq {
+            crate::unregister_interrupt(irq, self.transport.isr());
+        }
+        let _ = memory::free_frames(self.req_paddr, self.req_pages);
+    }
+}
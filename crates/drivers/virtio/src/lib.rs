@@ -0,0 +1,135 @@
+#![no_std]
+
+//! Virtio devices over PCI.
+//!
+//! A `Transport` hides how the device's registers are reached, `Virtqueue`
+//! implements the split virtqueue shared by every device type, and
+//! `negotiate_features` / `finish_init` walk the status handshake from the
+//! specification. Device drivers (`blk`) are built from these pieces.
+//!
+//! Errors are reported as `&'static str` prefixed with the device type.
+
+extern crate alloc;
+
+use arch::interrupts::{self, IRQ_COUNT, IrqError};
+use arch::pic::IrqKind;
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_int;
+
+pub mod blk;
+pub mod transport;
+pub mod virtqueue;
+
+pub use transport::{IsrRegister, Transport};
+pub use virtqueue::Virtqueue;
+
+/// Vendor ID of all virtio PCI devices.
+pub const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+pub const STATUS_RESET: u8 = 0x00;
+pub const STATUS_ACKNOWLEDGE: u8 = 0x01;
+pub const STATUS_DRIVER: u8 = 0x02;
+pub const STATUS_DRIVER_OK: u8 = 0x04;
+pub const STATUS_FEATURES_OK: u8 = 0x08;
+pub const STATUS_FAILED: u8 = 0x80;
+
+/// Feature bit: the device follows virtio 1.0 rather than the legacy
+/// interface.
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Reset the device, announce the driver and agree on features: the
+/// result is the subset of `supported` (plus the transport's required
+/// features) the device offers, which is also what the driver
+/// acknowledges. The driver must then set up its queues
+/// and call `finish_init`.
+pub fn negotiate_features(
+    transport: &mut dyn Transport,
+    supported: u64,
+) -> Result<u64, &'static str> {
+    transport.set_status(STATUS_RESET);
+    transport.set_status(STATUS_ACKNOWLEDGE);
+    transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+    let offered: u64 = transport.device_features();
+    let required: u64 = transport.required_features();
+    if offered & required != required {
+        transport.set_status(STATUS_FAILED);
+        return Err("virtio: device lacks features the transport needs");
+    }
+    let features: u64 = offered & (supported | required);
+    transport.set_driver_features(features);
+    if transport.requires_features_ok() {
+        transport.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK);
+        if transport.status() & STATUS_FEATURES_OK == 0 {
+            transport.set_status(STATUS_FAILED);
+            return Err("virtio: device rejected the features");
+        }
+    }
+    Ok(features)
+}
+
+/// Tell the device the driver is ready; queues must be set up.
+pub fn finish_init(transport: &mut dyn Transport) {
+    let status: u8 = transport.status();
+    transport.set_status(status | STATUS_DRIVER_OK);
+}
+
+/// ISR registers of the devices interrupting on each legacy IRQ line.
+/// Several virtio devices may share a line.
+const DEVICES_PER_IRQ: usize = 4;
+static IRQ_DEVICES: Mutex<[[Option<IsrRegister>; DEVICES_PER_IRQ]; IRQ_COUNT]> =
+    Mutex::new([[None; DEVICES_PER_IRQ]; IRQ_COUNT]);
+
+/// Acknowledge interrupts from the device whose ISR register is `isr`
+/// arriving on legacy IRQ `irq`. Waiters notice completions in the used
+/// ring; the handler only deasserts the line. Fails if the interrupt
+/// controller cannot route the line, e.g. PCI lines under the I/O APIC.
+pub fn register_interrupt(irq: u8, isr: IsrRegister) -> Result<(), IrqError> {
+    let first: bool = cpu_int::without_interrupts(|| {
+        let mut devices = IRQ_DEVICES.lock();
+        let line = devices
+            .get_mut(irq as usize)
+            .ok_or(IrqError::Reserved(irq))?;
+        let first: bool = line.iter().all(Option::is_none);
+        let slot = line
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::InUse(irq))?;
+        *slot = Some(isr);
+        Ok(first)
+    })?;
+    if first && let Err(e) = interrupts::register_irq_handler(irq, IrqKind::Pci, handle_interrupt) {
+        unregister_interrupt(irq, isr);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Stop acknowledging `isr` on `irq`. The device must already be reset.
+pub fn unregister_interrupt(irq: u8, isr: IsrRegister) {
+    let last: bool = cpu_int::without_interrupts(|| {
+        let mut devices = IRQ_DEVICES.lock();
+        let Some(line) = devices.get_mut(irq as usize) else {
+            return false;
+        };
+        if let Some(slot) = line.iter_mut().find(|slot| **slot == Some(isr)) {
+            *slot = None;
+        }
+        line.iter().all(Option::is_none)
+    });
+    if last {
+        interrupts::unregister_irq_handler(irq);
+    }
+}
+
+/// Reading the ISR acknowledges the interrupt and deasserts the line. Every
+/// device sharing `irq` is read, since any of them may have raised it;
+/// reading an idle device's ISR has no effect.
+fn handle_interrupt(irq: u8) {
+    // Devices are only registered with interrupts disabled, so the lock is
+    // never held here.
+    let devices = IRQ_DEVICES.lock();
+    for isr in devices[irq as usize].iter().flatten() {
+        isr.read();
+    }
+}
@@ -0,0 +1,79 @@
+//! Access to a virtio device's registers.
+//!
+//! The legacy PCI interface (virtio 0.9.5) puts every register in one I/O
+//! port BAR; the virtio 1.0 interface spreads them over memory BARs named
+//! by vendor-specific PCI capabilities. Drivers only go through
+//! `Transport` and work with either.
+use x86_64::instructions::port::Port;
+
+use crate::Virtqueue;
+
+pub mod legacy;
+pub mod modern;
+
+pub use legacy::LegacyTransport;
+pub use modern::ModernTransport;
+
+pub trait Transport: Send {
+    /// Feature bits the device offers.
+    fn device_features(&mut self) -> u64;
+
+    /// Acknowledge `features`; only bits the device offered may be set.
+    fn set_driver_features(&mut self, features: u64);
+
+    /// Feature bits the transport itself depends on; the device must
+    /// offer all of them.
+    fn required_features(&self) -> u64 {
+        0
+    }
+
+    /// Whether the device must confirm the features with
+    /// `STATUS_FEATURES_OK` before queues are set up.
+    fn requires_features_ok(&self) -> bool;
+
+    fn status(&mut self) -> u8;
+
+    fn set_status(&mut self, status: u8);
+
+    /// Largest size of queue `index`, or 0 if the queue does not exist.
+    fn max_queue_size(&mut self, index: u16) -> u16;
+
+    /// Whether the driver may use a queue smaller than `max_queue_size`.
+    fn supports_queue_resize(&self) -> bool;
+
+    /// Hand `queue` to the device as queue `index`.
+    fn setup_queue(&mut self, index: u16, queue: &Virtqueue) -> Result<(), &'static str>;
+
+    /// Tell the device queue `index` has new available buffers.
+    fn notify(&mut self, index: u16);
+
+    /// Where the interrupt handler acknowledges the device.
+    fn isr(&self) -> IsrRegister;
+
+    fn read_config_u32(&mut self, offset: u16) -> u32;
+
+    /// Read a 64-bit field of the device-specific configuration.
+    fn read_config_u64(&mut self, offset: u16) -> u64 {
+        let low: u32 = self.read_config_u32(offset);
+        let high: u32 = self.read_config_u32(offset + 4);
+        ((high as u64) << 32) | low as u64
+    }
+}
+
+/// A device's ISR status register. Reading it returns and clears the
+/// interrupt causes, deasserting the INTx line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsrRegister {
+    Port(u16),
+    /// Virtual address of a mapped register.
+    Mmio(u64),
+}
+
+impl IsrRegister {
+    pub fn read(self) -> u8 {
+        match self {
+            IsrRegister::Port(port) => unsafe { Port::<u8>::new(port).read() },
+            IsrRegister::Mmio(addr) => unsafe { (addr as *const u8).read_volatile() },
+        }
+    }
+}
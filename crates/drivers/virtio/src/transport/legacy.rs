@@ -0,0 +1,99 @@
+//! Legacy virtio PCI interface: all registers in the I/O port BAR 0.
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+
+use super::{IsrRegister, Transport};
+use crate::Virtqueue;
+
+const REG_HOST_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_PFN: u16 = 0x08;
+const REG_QUEUE_NUM: u16 = 0x0c;
+const REG_QUEUE_SEL: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_STATUS: u16 = 0x12;
+const REG_ISR: u16 = 0x13;
+/// Device-specific configuration, when MSI-X is disabled.
+const REG_CONFIG: u16 = 0x14;
+
+/// Queue addresses are given as page frame numbers of this size.
+const QUEUE_PFN_SIZE: u64 = 4096;
+
+/// The legacy interface only has 32 feature bits.
+const FEATURES_MASK: u64 = 0xffff_ffff;
+
+pub struct LegacyTransport {
+    io_base: u16,
+}
+
+impl LegacyTransport {
+    /// Use the registers at I/O port `io_base` (BAR 0). The caller must
+    /// have enabled I/O decoding and bus mastering.
+    pub fn new(io_base: u16) -> Self {
+        Self { io_base }
+    }
+
+    fn read<T: PortRead>(&self, offset: u16) -> T {
+        unsafe { Port::<T>::new(self.io_base + offset).read() }
+    }
+
+    fn write<T: PortWrite>(&self, offset: u16, value: T) {
+        unsafe { Port::<T>::new(self.io_base + offset).write(value) }
+    }
+}
+
+impl Transport for LegacyTransport {
+    fn device_features(&mut self) -> u64 {
+        self.read::<u32>(REG_HOST_FEATURES) as u64
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        self.write::<u32>(REG_GUEST_FEATURES, (features & FEATURES_MASK) as u32);
+    }
+
+    fn requires_features_ok(&self) -> bool {
+        false
+    }
+
+    fn status(&mut self) -> u8 {
+        self.read::<u8>(REG_STATUS)
+    }
+
+    fn set_status(&mut self, status: u8) {
+        self.write::<u8>(REG_STATUS, status);
+    }
+
+    fn max_queue_size(&mut self, index: u16) -> u16 {
+        self.write::<u16>(REG_QUEUE_SEL, index);
+        self.read::<u16>(REG_QUEUE_NUM)
+    }
+
+    fn supports_queue_resize(&self) -> bool {
+        false
+    }
+
+    /// The legacy interface only takes the page of the descriptor table and
+    /// expects the rest of the queue at fixed offsets from it.
+    fn setup_queue(&mut self, index: u16, queue: &Virtqueue) -> Result<(), &'static str> {
+        if !queue.desc_paddr().is_multiple_of(QUEUE_PFN_SIZE) {
+            return Err("virtio: legacy queue is not page aligned");
+        }
+        self.write::<u16>(REG_QUEUE_SEL, index);
+        if self.read::<u16>(REG_QUEUE_NUM) != queue.size() {
+            return Err("virtio: legacy queue size cannot be changed");
+        }
+        self.write::<u32>(REG_QUEUE_PFN, (queue.desc_paddr() / QUEUE_PFN_SIZE) as u32);
+        Ok(())
+    }
+
+    fn notify(&mut self, index: u16) {
+        self.write::<u16>(REG_QUEUE_NOTIFY, index);
+    }
+
+    fn isr(&self) -> IsrRegister {
+        IsrRegister::Port(self.io_base + REG_ISR)
+    }
+
+    fn read_config_u32(&mut self, offset: u16) -> u32 {
+        self.read::<u32>(REG_CONFIG + offset)
+    }
+}
@@ -0,0 +1,263 @@
+//! Virtio 1.0 PCI interface: registers in memory BARs, located through
+//! vendor-specific PCI capabilities.
+use alloc::vec::Vec;
+use core::mem::size_of;
+
+use arch::pci;
+use memory::{MmioRegion, map_mmio};
+
+use super::{IsrRegister, Transport};
+use crate::{STATUS_RESET, VIRTIO_F_VERSION_1, Virtqueue};
+
+const PCI_CAP_ID_VENDOR: u8 = 0x09;
+
+// Layout of `struct virtio_pci_cap`, relative to the capability header.
+const CAP_CFG_TYPE: u8 = 0x00;
+const CAP_BAR: u8 = 0x04;
+const CAP_OFFSET: u8 = 0x08;
+const CAP_LENGTH: u8 = 0x0c;
+/// `notify_off_multiplier`, only present in the notify capability.
+const CAP_NOTIFY_MULTIPLIER: u8 = 0x10;
+const CAP_CFG_TYPE_SHIFT: u32 = 24;
+
+const CFG_TYPE_COMMON: u8 = 1;
+const CFG_TYPE_NOTIFY: u8 = 2;
+const CFG_TYPE_ISR: u8 = 3;
+const CFG_TYPE_DEVICE: u8 = 4;
+
+// Common configuration structure.
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0c;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_CONFIG_GENERATION: u64 = 0x15;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_ENABLE: u64 = 0x1c;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+/// Feature bits are read and written 32 at a time.
+const FEATURE_WORDS: u32 = 2;
+const FEATURE_WORD_BITS: u32 = 32;
+const QUEUE_ENABLED: u16 = 1;
+/// Queue notifications are 16-bit writes of the queue index.
+const NOTIFY_WIDTH: u64 = size_of::<u16>() as u64;
+
+/// Polls of the status register while waiting for a reset to finish.
+const RESET_POLL_LIMIT: usize = 1_000_000;
+/// Attempts at reading a 64-bit configuration field before settling for a
+/// possibly torn value from a device that keeps changing it.
+const CONFIG_GENERATION_RETRIES: usize = 16;
+
+/// Where one of the configuration structures lives.
+#[derive(Clone, Copy)]
+struct CapabilityLocation {
+    bar: u8,
+    offset: u32,
+    length: u32,
+}
+
+pub struct ModernTransport {
+    common: MmioRegion,
+    notify: MmioRegion,
+    notify_multiplier: u32,
+    /// `queue_notify_off` of each queue set up so far, by queue index.
+    notify_offsets: Vec<Option<u16>>,
+    isr: MmioRegion,
+    device: MmioRegion,
+}
+
+impl ModernTransport {
+    /// Find and map the configuration structures of PCI function
+    /// `bus:device.function`. The caller must have enabled memory decoding
+    /// and bus mastering.
+    pub fn from_pci(bus: u8, device: u8, function: u8) -> Result<Self, &'static str> {
+        let mut common: Option<CapabilityLocation> = None;
+        let mut notify: Option<(CapabilityLocation, u32)> = None;
+        let mut isr: Option<CapabilityLocation> = None;
+        let mut device_cfg: Option<CapabilityLocation> = None;
+
+        for capability in pci::capabilities(bus, device, function) {
+            if capability.id != PCI_CAP_ID_VENDOR {
+                continue;
+            }
+            let read =
+                |field: u8| pci::read_config(bus, device, function, capability.offset + field);
+            let location = CapabilityLocation {
+                bar: read(CAP_BAR) as u8,
+                offset: read(CAP_OFFSET),
+                length: read(CAP_LENGTH),
+            };
+            // The first capability of each type is the preferred one.
+            match (read(CAP_CFG_TYPE) >> CAP_CFG_TYPE_SHIFT) as u8 {
+                CFG_TYPE_COMMON => {
+                    common.get_or_insert(location);
+                }
+                CFG_TYPE_NOTIFY => {
+                    notify.get_or_insert((location, read(CAP_NOTIFY_MULTIPLIER)));
+                }
+                CFG_TYPE_ISR => {
+                    isr.get_or_insert(location);
+                }
+                CFG_TYPE_DEVICE => {
+                    device_cfg.get_or_insert(location);
+                }
+                _ => {}
+            }
+        }
+
+        let common = common.ok_or("virtio: no common configuration capability")?;
+        let (notify, notify_multiplier) = notify.ok_or("virtio: no notify capability")?;
+        let isr = isr.ok_or("virtio: no ISR capability")?;
+        let device_cfg = device_cfg.ok_or("virtio: no device configuration capability")?;
+        let map = |location: CapabilityLocation| map_structure(bus, device, function, location);
+        Ok(Self {
+            common: map(common)?,
+            notify: map(notify)?,
+            notify_multiplier,
+            notify_offsets: Vec::new(),
+            isr: map(isr)?,
+            device: map(device_cfg)?,
+        })
+    }
+
+    /// 64-bit fields of the common configuration are written as two 32-bit
+    /// halves, which every device must accept.
+    fn write_common_u64(&self, offset: u64, value: u64) {
+        self.common.write::<u32>(offset, value as u32);
+        self.common.write::<u32>(offset + 4, (value >> 32) as u32);
+    }
+}
+
+/// Map the part of a BAR described by a virtio capability.
+fn map_structure(
+    bus: u8,
+    device: u8,
+    function: u8,
+    location: CapabilityLocation,
+) -> Result<MmioRegion, &'static str> {
+    let bar = pci::read_bar(bus, device, function, location.bar)
+        .filter(|bar| bar.is_mmio())
+        .ok_or("virtio: capability points at a missing or I/O BAR")?;
+    if bar
+        .size
+        .is_some_and(|size| location.offset as u64 + location.length as u64 > size)
+    {
+        return Err("virtio: capability extends past its BAR");
+    }
+    map_mmio(bar.base + location.offset as u64, location.length as u64)
+        .map_err(|_| "virtio: cannot map capability")
+}
+
+impl Transport for ModernTransport {
+    fn device_features(&mut self) -> u64 {
+        (0..FEATURE_WORDS).fold(0, |features, word| {
+            self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, word);
+            let bits: u32 = self.common.read::<u32>(COMMON_DEVICE_FEATURE);
+            features | (bits as u64) << (word * FEATURE_WORD_BITS)
+        })
+    }
+
+    fn set_driver_features(&mut self, features: u64) {
+        for word in 0..FEATURE_WORDS {
+            self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, word);
+            self.common.write::<u32>(
+                COMMON_DRIVER_FEATURE,
+                (features >> (word * FEATURE_WORD_BITS)) as u32,
+            );
+        }
+    }
+
+    fn required_features(&self) -> u64 {
+        VIRTIO_F_VERSION_1
+    }
+
+    fn requires_features_ok(&self) -> bool {
+        true
+    }
+
+    fn status(&mut self) -> u8 {
+        self.common.read::<u8>(COMMON_DEVICE_STATUS)
+    }
+
+    /// A reset is only complete once the device reads back 0.
+    fn set_status(&mut self, status: u8) {
+        self.common.write::<u8>(COMMON_DEVICE_STATUS, status);
+        if status == STATUS_RESET {
+            for _ in 0..RESET_POLL_LIMIT {
+                if self.common.read::<u8>(COMMON_DEVICE_STATUS) == STATUS_RESET {
+                    break;
+                }
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    fn max_queue_size(&mut self, index: u16) -> u16 {
+        self.common.write::<u16>(COMMON_QUEUE_SELECT, index);
+        self.common.read::<u16>(COMMON_QUEUE_SIZE)
+    }
+
+    fn supports_queue_resize(&self) -> bool {
+        true
+    }
+
+    fn setup_queue(&mut self, index: u16, queue: &Virtqueue) -> Result<(), &'static str> {
+        self.common.write::<u16>(COMMON_QUEUE_SELECT, index);
+        if queue.size() > self.common.read::<u16>(COMMON_QUEUE_SIZE) {
+            return Err("virtio: queue larger than the device allows");
+        }
+        self.common.write::<u16>(COMMON_QUEUE_SIZE, queue.size());
+        self.write_common_u64(COMMON_QUEUE_DESC, queue.desc_paddr());
+        self.write_common_u64(COMMON_QUEUE_DRIVER, queue.avail_paddr());
+        self.write_common_u64(COMMON_QUEUE_DEVICE, queue.used_paddr());
+        let notify_off: u16 = self.common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF);
+        if notify_off as u64 * self.notify_multiplier as u64 + NOTIFY_WIDTH > self.notify.size() {
+            return Err("virtio: queue notify address outside the notify region");
+        }
+        self.common.write::<u16>(COMMON_QUEUE_ENABLE, QUEUE_ENABLED);
+
+        if self.notify_offsets.len() <= index as usize {
+            self.notify_offsets.resize(index as usize + 1, None);
+        }
+        self.notify_offsets[index as usize] = Some(notify_off);
+        Ok(())
+    }
+
+    fn notify(&mut self, index: u16) {
+        if let Some(Some(notify_off)) = self.notify_offsets.get(index as usize) {
+            let offset: u64 = *notify_off as u64 * self.notify_multiplier as u64;
+            self.notify.write::<u16>(offset, index);
+        }
+    }
+
+    fn isr(&self) -> IsrRegister {
+        IsrRegister::Mmio(self.isr.virt_addr().as_u64())
+    }
+
+    fn read_config_u32(&mut self, offset: u16) -> u32 {
+        self.device.read::<u32>(offset as u64)
+    }
+
+    /// Retry until the configuration generation is unchanged, so both halves
+    /// come from the same version of the configuration. Gives up after
+    /// `CONFIG_GENERATION_RETRIES` attempts and returns the last read.
+    fn read_config_u64(&mut self, offset: u16) -> u64 {
+        let mut value: u64 = 0;
+        for _ in 0..CONFIG_GENERATION_RETRIES {
+            let generation: u8 = self.common.read::<u8>(COMMON_CONFIG_GENERATION);
+            let low: u32 = self.read_config_u32(offset);
+            let high: u32 = self.read_config_u32(offset + 4);
+            value = ((high as u64) << 32) | low as u64;
+            if self.common.read::<u8>(COMMON_CONFIG_GENERATION) == generation {
+                return value;
+            }
+        }
+        console::serial_println!("virtio: config generation kept changing at {:#x}", offset);
+        value
+    }
+}
@@ -0,0 +1,262 @@
+//! Split virtqueues.
+//!
+//! The queue is laid out the way the legacy interface requires (descriptor
+//! table and available ring, then the used ring on the next 4 KiB
+//! boundary) so the same memory works with every transport. Free
+//! descriptors are chained through their `next` fields starting at
+//! `free_head`.
+use core::mem::size_of;
+use core::ptr;
+use core::sync::atomic::{Ordering, fence};
+
+use memory::align_up_usize;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const DESC_CHAIN_END: u16 = 0;
+const DESC_FLAGS_NONE: u16 = 0;
+const FREE_LIST_HEAD: u16 = 0;
+
+/// The legacy interface places the used ring at the next multiple of this
+/// after the available ring.
+const LEGACY_QUEUE_ALIGN: usize = 4096;
+
+const AVAIL_RING_ENTRY_SIZE: usize = size_of::<u16>();
+const AVAIL_USED_EVENT_SIZE: usize = size_of::<u16>();
+const USED_EVENT_SIZE: usize = size_of::<u16>();
+
+const INITIAL_USED_IDX: u16 = 0;
+const IDX_INCREMENT: u16 = 1;
+const ZERO_FILL: u8 = 0;
+
+#[repr(C, align(16))]
+struct VirtqDesc {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct VirtqAvailHeader {
+    flags: u16,
+    idx: u16,
+}
+
+#[repr(C)]
+struct VirtqUsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct VirtqUsedHeader {
+    flags: u16,
+    idx: u16,
+}
+
+/// One physically contiguous buffer in a descriptor chain.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: u64,
+    pub len: u32,
+    /// The device writes the buffer rather than reading it.
+    pub device_writable: bool,
+}
+
+/// A chain the device has finished with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsedChain {
+    /// First descriptor of the chain, as returned by `add_chain`.
+    pub head: u16,
+    /// Bytes the device wrote into the chain's writable buffers.
+    pub len: u32,
+}
+
+pub struct Virtqueue {
+    size: u16,
+    paddr: u64,
+    pages: usize,
+    desc: *mut VirtqDesc,
+    avail: *mut VirtqAvailHeader,
+    used: *mut VirtqUsedHeader,
+    free_head: u16,
+    num_free: u16,
+    last_used_idx: u16,
+}
+
+// The pointers only refer to the queue's own frames.
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    /// Allocate and zero a queue of `size` entries, reached through the
+    /// physical memory window at `phys_offset`.
+    pub fn new(size: u16, phys_offset: u64) -> Result<Self, &'static str> {
+        let desc_size = size_of::<VirtqDesc>() * size as usize;
+        let avail_size = size_of::<VirtqAvailHeader>()
+            + (AVAIL_RING_ENTRY_SIZE * size as usize)
+            + AVAIL_USED_EVENT_SIZE;
+        let used_offset = align_up_usize(desc_size + avail_size, LEGACY_QUEUE_ALIGN);
+        let used_size = size_of::<VirtqUsedHeader>()
+            + (size_of::<VirtqUsedElem>() * size as usize)
+            + USED_EVENT_SIZE;
+        let pages = align_up_usize(used_offset + used_size, memory::PAGE_SIZE as usize)
+            / memory::PAGE_SIZE as usize;
+
+        let paddr = memory::alloc_frames(pages, memory::PAGE_SIZE).ok_or("virtio: no frames")?;
+        let vaddr = phys_offset + paddr;
+        unsafe {
+            ptr::write_bytes(
+                vaddr as *mut u8,
+                ZERO_FILL,
+                pages * memory::PAGE_SIZE as usize,
+            )
+        };
+
+        let desc = vaddr as *mut VirtqDesc;
+        for index in 0..size {
+            unsafe { (*desc.add(index as usize)).next = index.wrapping_add(1) };
+        }
+        Ok(Self {
+            size,
+            paddr,
+            pages,
+            desc,
+            avail: (vaddr + desc_size as u64) as *mut VirtqAvailHeader,
+            used: (vaddr + used_offset as u64) as *mut VirtqUsedHeader,
+            free_head: FREE_LIST_HEAD,
+            num_free: size,
+            last_used_idx: INITIAL_USED_IDX,
+        })
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Descriptors not in any chain.
+    pub fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    pub fn desc_paddr(&self) -> u64 {
+        self.paddr
+    }
+
+    pub fn avail_paddr(&self) -> u64 {
+        self.paddr + (self.avail as u64 - self.desc as u64)
+    }
+
+    pub fn used_paddr(&self) -> u64 {
+        self.paddr + (self.used as u64 - self.desc as u64)
+    }
+
+    /// Link `buffers`, in order, into one descriptor chain and make it
+    /// available to the device. Returns the head descriptor, which
+    /// `pop_used` reports when the device is done. The caller notifies the
+    /// device through its transport.
+    pub fn add_chain(&mut self, buffers: &[Buffer]) -> Result<u16, &'static str> {
+        if buffers.is_empty() {
+            return Err("virtio: empty descriptor chain");
+        }
+        if buffers.len() > self.num_free as usize {
+            return Err("virtio: queue full");
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (position, buffer) in buffers.iter().enumerate() {
+            let free_next = unsafe { (*self.desc.add(index as usize)).next };
+            let last = position + 1 == buffers.len();
+            let flags = if last {
+                DESC_FLAGS_NONE
+            } else {
+                VIRTQ_DESC_F_NEXT
+            } | if buffer.device_writable {
+                VIRTQ_DESC_F_WRITE
+            } else {
+                DESC_FLAGS_NONE
+            };
+            let desc = VirtqDesc {
+                addr: buffer.addr,
+                len: buffer.len,
+                flags,
+                next: if last { DESC_CHAIN_END } else { free_next },
+            };
+            unsafe { ptr::write_volatile(self.desc.add(index as usize), desc) };
+            if last {
+                self.free_head = free_next;
+            } else {
+                index = free_next;
+            }
+        }
+        self.num_free -= buffers.len() as u16;
+        self.push_avail(head);
+        Ok(head)
+    }
+
+    /// The device has added used entries we have not consumed yet.
+    pub fn has_used(&self) -> bool {
+        unsafe { ptr::read_volatile(&(*self.used).idx) != self.last_used_idx }
+    }
+
+    /// Consume the next used entry and return its descriptors to the free
+    /// list.
+    pub fn pop_used(&mut self) -> Option<UsedChain> {
+        if !self.has_used() {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let ring_index = (self.last_used_idx % self.size) as usize;
+        let elem = unsafe {
+            let elem_ptr = (self.used as *mut u8)
+                .add(size_of::<VirtqUsedHeader>() + ring_index * size_of::<VirtqUsedElem>())
+                as *const VirtqUsedElem;
+            ptr::read_volatile(elem_ptr)
+        };
+        self.last_used_idx = self.last_used_idx.wrapping_add(IDX_INCREMENT);
+        let head = elem.id as u16;
+        self.free_chain(head);
+        Some(UsedChain {
+            head,
+            len: elem.len,
+        })
+    }
+
+    /// Return the chain starting at `head` to the free list.
+    fn free_chain(&mut self, head: u16) {
+        let mut index = head;
+        loop {
+            let desc = unsafe { &mut *self.desc.add(index as usize) };
+            let next = desc.next;
+            let has_next = desc.flags & VIRTQ_DESC_F_NEXT != 0;
+            desc.flags = DESC_FLAGS_NONE;
+            desc.next = self.free_head;
+            self.free_head = index;
+            self.num_free += 1;
+            if !has_next {
+                break;
+            }
+            index = next;
+        }
+    }
+
+    /// Make the chain starting at `head` available to the device.
+    fn push_avail(&mut self, head: u16) {
+        unsafe {
+            let avail = &mut *self.avail;
+            let ring_index = (avail.idx % self.size) as usize;
+            let ring_ptr = (self.avail as *mut u8)
+                .add(size_of::<VirtqAvailHeader>() + ring_index * AVAIL_RING_ENTRY_SIZE)
+                as *mut u16;
+            ptr::write_volatile(ring_ptr, head);
+            fence(Ordering::SeqCst);
+            ptr::write_volatile(&mut avail.idx, avail.idx.wrapping_add(IDX_INCREMENT));
+        }
+    }
+}
+
+impl Drop for Virtqueue {
+    fn drop(&mut self) {
+        let _ = memory::free_frames(self.paddr, self.pages);
+    }
+}
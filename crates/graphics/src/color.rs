@@ -29,4 +29,13 @@ impl Color {
             b: 0x60,
         }
     }
+
+    // パニック画面の背景：暗い赤
+    pub fn panic_red() -> Color {
+        Color {
+            r: 0x80,
+            g: 0x10,
+            b: 0x10,
+        }
+    }
 }
@@ -4,28 +4,41 @@
 
 extern crate alloc;
 
-use alloc::vec::Vec;
-use arch::{idt, interrupts, pci};
+use alloc::{boxed::Box, vec::Vec};
+use arch::{
+    clock, gdt, idt, interrupts,
+    pci::{self, BarKind, PciDevice},
+    rtc, timer,
+};
 use bootloader_api::{
     BootInfo, BootloaderConfig,
     config::Mapping,
     entry_point,
     info::{FrameBuffer, MemoryRegionKind as BlKind, MemoryRegions},
 };
-use console::{console::TextConsole, console_trait::Console, serial, serial_println};
-use graphics::{color::Color, frame_buffer::BeyondFramebuffer};
-use memory::{MemRegion, MemRegionKind, paging};
+use console::{serial, serial_println};
+use graphics::frame_buffer::BeyondFramebuffer;
+use memory::{
+    MemRegion, MemRegionKind,
+    paging::{self, CachePolicy, PageProtection, vmm},
+};
 use shell::Shell;
-mod virtio_blk;
+use virtio::{
+    Transport,
+    blk::VirtioBlk,
+    transport::{LegacyTransport, ModernTransport},
+};
 use x86_64::{VirtAddr, instructions::interrupts as cpu_int};
 
 // IDs and sizes from PCI/Virtio specs.
-const VIRTIO_VENDOR_ID: u16 = 0x1af4;
-const VIRTIO_BLK_DEVICE_ID_LEGACY: u16 = 0x1001;
 const PCI_BAR_COUNT: u8 = 6;
 const VIRTIO_LEGACY_BAR_INDEX: u8 = 0;
-const SECTOR_SIZE_BYTES: usize = 512;
+/// Block device names for virtio disks: vda, vdb, ...
+const VIRTIO_BLK_PREFIX: &str = "vd";
 const BOOT_SECTOR_LBA: u64 = 0;
+/// Sectors read at boot to exercise requests in flight together.
+const BULK_READ_SECTORS: u64 = 64;
+const KERNEL_STACK_SIZE: u64 = 128 * 1024;
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
     let mut config = BootloaderConfig::new_default();
@@ -42,6 +55,24 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     // Early serial init to diagnose boot hangs before framebuffer setup.
     serial::init_serial();
     serial_println!("kernel_main: start");
+    load_symbols(boot_info);
+
+    memory::init_frame_allocator(convert_regions(&boot_info.memory_regions));
+    init_heap(boot_info.physical_memory_offset.into_option());
+
+    // Leave the bootloader stack for one with a guard page below it, so an
+    // overflow faults instead of silently corrupting memory.
+    let stack = match vmm::allocate_stack(KERNEL_STACK_SIZE, "kernel stack") {
+        Ok(stack) => stack,
+        Err(e) => panic!("kernel stack allocation failed: {:?}", e),
+    };
+    let boot_info: *mut BootInfo = boot_info;
+    unsafe { arch::stack::switch_stack(stack.top, kernel_start, boot_info as u64) }
+}
+
+/// Rest of the kernel entry, running on the guarded kernel stack.
+extern "C" fn kernel_start(boot_info: u64) -> ! {
+    let boot_info: &'static mut BootInfo = unsafe { &mut *(boot_info as *mut BootInfo) };
 
     let regions: &MemoryRegions = &boot_info.memory_regions;
     let frame_buffer: &mut FrameBuffer = boot_info.framebuffer.as_mut().expect("No FrameBudffer!!");
@@ -50,16 +81,29 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     match BeyondFramebuffer::from_frame_buffer(frame_buffer) {
         Some(mut frame_buffer) => {
             serial_println!("kernel_main: framebuffer ok");
+            gdt::init();
             idt::init_idt();
+            init_acpi(boot_info.rsdp_addr.into_option(), phys_offset);
             interrupts::init_interrupts();
+            timer::init();
+            serial_println!("rtc: {}", rtc::init());
+            if let Some(tsc) = clock::init() {
+                serial_println!(
+                    "clock: TSC {} kHz via {:?}, invariant={}",
+                    tsc.frequency_hz / 1000,
+                    tsc.source,
+                    tsc.invariant
+                );
+            }
             cpu_int::enable();
-            init_heap(phys_offset, regions);
-            let regions_for_allocator = convert_regions(regions);
-            memory::init_frame_allocator(regions_for_allocator.clone().leak());
+            remap_framebuffer(&mut frame_buffer);
+            // The shell and the crash reporter both draw on the global console.
+            console::console::init_console(Box::leak(Box::new(frame_buffer)));
+            let regions_for_allocator: Vec<MemRegion> = convert_regions(regions).collect();
 
             serial_println!("PCI scan:");
             pci::scan(|dev| {
-                if dev.vendor_id == VIRTIO_VENDOR_ID {
+                if dev.vendor_id == virtio::VIRTIO_VENDOR_ID {
                     serial_println!(
                         "pci {:02x}:{:02x}.{:x} vendor={:04x} device={:04x} class={:02x} subclass={:02x} prog_if={:02x} (virtio)",
                         dev.bus,
@@ -84,36 +128,10 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
                             );
                         }
                     }
-                    if dev.device_id == VIRTIO_BLK_DEVICE_ID_LEGACY
-                        && let Some(bar) = pci::read_bar(
-                            dev.bus,
-                            dev.device,
-                            dev.function,
-                            VIRTIO_LEGACY_BAR_INDEX,
-                        )
-                        && bar.kind == arch::pci::BarKind::Io
-                    {
-                        pci::enable_io_bus_master(dev.bus, dev.device, dev.function);
+                    if let Some(transport) = virtio_blk_transport(&dev) {
                         if let Some(offset) = phys_offset {
-                            match virtio_blk::init_legacy(bar.base as u16, offset) {
-                                Ok(mut blk) => {
-                                    serial_println!(
-                                        "virtio-blk capacity: {} sectors",
-                                        blk.capacity_sectors()
-                                    );
-                                    if blk
-                                        .read_sector(BOOT_SECTOR_LBA, &mut [0u8; SECTOR_SIZE_BYTES])
-                                        .is_ok()
-                                    {
-                                        serial_println!("virtio-blk read sector 0 ok");
-                                    } else {
-                                        serial_println!("virtio-blk read sector 0 failed");
-                                    }
-                                }
-                                Err(e) => {
-                                    serial_println!("virtio-blk init failed: {}", e);
-                                }
-                            }
+                            let irq = pci::interrupt_line(dev.bus, dev.device, dev.function);
+                            start_virtio_blk(transport, irq, offset);
                         } else {
                             serial_println!("virtio-blk: no physical memory offset");
                         }
@@ -134,7 +152,7 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
             });
 
             Shell::new(
-                TextConsole::new(&mut frame_buffer, Color::white(), Color::black()),
+                console::console::global_console().expect("console not initialized"),
                 regions_for_allocator,
                 phys_offset.expect("No physical memory offset"),
             )
@@ -146,47 +164,194 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     };
 }
 
-fn init_heap(phys_offset: Option<u64>, regions: &MemoryRegions) {
-    if let Some(offset) = phys_offset {
-        let mut mapper = unsafe { paging::init(VirtAddr::new(offset)) };
+/// Transport for a virtio-blk PCI function: the I/O port BAR of a legacy
+/// device, or the memory BARs a virtio 1.0 device describes in its
+/// capabilities. Enables the decoding the transport needs.
+fn virtio_blk_transport(dev: &PciDevice) -> Option<Box<dyn Transport>> {
+    match dev.device_id {
+        virtio::blk::DEVICE_ID_LEGACY => {
+            let bar = pci::read_bar(dev.bus, dev.device, dev.function, VIRTIO_LEGACY_BAR_INDEX)
+                .filter(|bar| bar.kind == BarKind::Io)?;
+            pci::enable_io_bus_master(dev.bus, dev.device, dev.function);
+            Some(Box::new(LegacyTransport::new(bar.base as u16)))
+        }
+        virtio::blk::DEVICE_ID_MODERN => {
+            pci::enable_memory_bus_master(dev.bus, dev.device, dev.function);
+            match ModernTransport::from_pci(dev.bus, dev.device, dev.function) {
+                Ok(transport) => Some(Box::new(transport)),
+                Err(e) => {
+                    serial_println!("virtio-blk init failed: {}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Bring up a virtio-blk device, exercise it and register it as a block
+/// device.
+fn start_virtio_blk(transport: Box<dyn Transport>, irq: Option<u8>, phys_offset: u64) {
+    match VirtioBlk::new(transport, irq, phys_offset) {
+        Ok(mut blk) => {
+            serial_println!(
+                "virtio-blk capacity: {} sectors, irq {:?}",
+                blk.capacity_sectors(),
+                blk.irq()
+            );
+            if blk
+                .read_sector(BOOT_SECTOR_LBA, &mut [0u8; virtio::blk::SECTOR_SIZE])
+                .is_ok()
+            {
+                serial_println!("virtio-blk read sector 0 ok");
+            } else {
+                serial_println!("virtio-blk read sector 0 failed");
+            }
+            read_bulk(&mut blk);
+            match block::register(VIRTIO_BLK_PREFIX, blk) {
+                Ok(name) => {
+                    serial_println!("virtio-blk registered as {}", name);
+                }
+                Err(e) => {
+                    serial_println!("virtio-blk register failed: {:?}", e);
+                }
+            }
+        }
+        Err(e) => {
+            serial_println!("virtio-blk init failed: {}", e);
+        }
+    }
+}
+
+/// Read the first sectors of the disk with many requests in flight and log
+/// how long it took.
+fn read_bulk(blk: &mut VirtioBlk) {
+    let sectors: u64 = BULK_READ_SECTORS.min(blk.capacity_sectors());
+    let mut buffer: Vec<u8> = alloc::vec![0u8; sectors as usize * virtio::blk::SECTOR_SIZE];
+    let start: u64 = clock::now_ns();
+    match blk.read_blocks(BOOT_SECTOR_LBA, &mut buffer) {
+        Ok(()) => {
+            serial_println!(
+                "virtio-blk read {} sectors in {} us",
+                sectors,
+                (clock::now_ns() - start) / 1000
+            );
+        }
+        Err(e) => {
+            serial_println!("virtio-blk bulk read failed: {}", e);
+        }
+    }
+}
+
+/// Install the symbol table os-runner passes as the ramdisk, so backtraces
+/// are symbolized.
+fn load_symbols(boot_info: &BootInfo) {
+    let Some(addr) = boot_info.ramdisk_addr.into_option() else {
+        serial_println!("symbols: no ramdisk, backtraces will not be symbolized");
+        return;
+    };
+    let data: &'static [u8] =
+        unsafe { core::slice::from_raw_parts(addr as *const u8, boot_info.ramdisk_len as usize) };
+    match arch::symbols::init(data, boot_info.kernel_image_offset) {
+        Ok(count) => {
+            serial_println!("symbols: {} loaded", count);
+        }
+        Err(e) => {
+            serial_println!("symbols: invalid table: {:?}", e);
+        }
+    }
+}
 
-        let iter = regions.iter().map(|region| MemRegion {
-            start: region.start,
-            end: region.end,
-            kind: match region.kind {
-                BlKind::Usable => MemRegionKind::Usable,
-                _ => MemRegionKind::Reserved,
-            },
-        });
+/// Parse the ACPI tables; the interrupt controller setup reads the MADT.
+fn init_acpi(rsdp_addr: Option<u64>, phys_offset: Option<u64>) {
+    let (Some(rsdp_addr), Some(phys_offset)) = (rsdp_addr, phys_offset) else {
+        serial_println!("acpi: no RSDP from the bootloader");
+        return;
+    };
+    match acpi::init(rsdp_addr, phys_offset) {
+        Ok(tables) => {
+            serial_println!(
+                "acpi: revision {}, {} tables",
+                tables.revision,
+                tables.headers.len()
+            );
+        }
+        Err(e) => {
+            serial_println!("acpi: init failed: {:?}", e);
+        }
+    }
+}
 
-        let mut frame_allocator = paging::BootInfoFrameAllocator::new(iter);
+fn init_heap(phys_offset: Option<u64>) {
+    if let Some(offset) = phys_offset {
+        unsafe { paging::init(VirtAddr::new(offset)) };
 
-        if let Err(e) = memory::init_heap(&mut mapper, &mut frame_allocator) {
+        if let Err(e) = memory::init_heap() {
             console::serial_println!("heap init failed: {:?}", e);
             panic!("heap init failed");
         }
     }
 }
 
-fn convert_regions(regions: &MemoryRegions) -> Vec<MemRegion> {
-    let converted = regions.iter().map(|region| MemRegion {
+/// Move the framebuffer into a write-through VMM mapping.
+///
+/// The range is physically contiguous, so `map_physical` can use huge pages
+/// for it. The bootloader mapping is kept as is if anything fails.
+fn remap_framebuffer(frame_buffer: &mut BeyondFramebuffer) {
+    let virt = VirtAddr::from_ptr(frame_buffer.buf.as_ptr());
+    let phys = match paging::with_mapper(|mapper| paging::translate(virt, mapper)) {
+        Some(Ok(translation)) => translation.phys,
+        Some(Err(e)) => {
+            serial_println!("framebuffer: translate failed: {:?}", e);
+            return;
+        }
+        None => return,
+    };
+
+    let len: usize = frame_buffer.buf.len();
+    let page_offset: u64 = phys.as_u64() % memory::PAGE_SIZE;
+    let protection = PageProtection {
+        cache: CachePolicy::WriteThrough,
+        ..PageProtection::KERNEL_DATA
+    };
+    match vmm::map_physical(
+        phys.align_down(memory::PAGE_SIZE),
+        page_offset + len as u64,
+        protection,
+        "framebuffer",
+    ) {
+        Ok(start) => {
+            let ptr: *mut u8 = (start + page_offset).as_mut_ptr();
+            frame_buffer.buf = unsafe { core::slice::from_raw_parts_mut(ptr, len) };
+            serial_println!(
+                "framebuffer: phys=0x{:x} remapped to 0x{:x}",
+                phys.as_u64(),
+                start.as_u64() + page_offset
+            );
+        }
+        Err(e) => {
+            serial_println!("framebuffer: remap failed: {:?}", e);
+        }
+    }
+}
+
+fn convert_regions(regions: &MemoryRegions) -> impl Iterator<Item = MemRegion> + '_ {
+    regions.iter().map(|region| MemRegion {
         start: region.start,
         end: region.end,
         kind: match region.kind {
             BlKind::Usable => MemRegionKind::Usable,
             _ => MemRegionKind::Reserved,
         },
-    });
-
-    converted.collect()
+    })
 }
 
 #[cfg(not(test))]
 use core::panic::PanicInfo;
 #[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    console::serial_println!("panic: {}", _info);
+fn panic(info: &PanicInfo) -> ! {
+    arch::crash::report_panic(info);
     loop {}
 }
 
@@ -1,5 +1,20 @@
 use crate::{MemRegion, MemRegionKind, PAGE_SIZE, align_down, align_up};
 
+/// Highest physical address (exclusive) tracked by the bitmap allocator.
+///
+/// Usable memory above this limit is ignored and counted in
+/// `FrameStats::ignored_frames`.
+pub const MAX_PHYS_ADDR: u64 = 4 * 1024 * 1024 * 1024; // 4 GiB
+
+const MAX_FRAMES: usize = (MAX_PHYS_ADDR / PAGE_SIZE) as usize;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+const BITMAP_WORDS: usize = MAX_FRAMES / BITS_PER_WORD;
+const WORD_ALL_USED: u64 = 0;
+/// Physical frame 0 is never handed out so that a null address stays invalid.
+const FIRST_ALLOCATABLE_FRAME: usize = 1;
+/// Number of usable regions whose usage is tracked individually.
+pub const MAX_TRACKED_REGIONS: usize = 32;
+
 /// Physical frame allocator interface.
 pub trait FrameAllocator {
     /// Allocate one 4 KiB aligned physical frame.
@@ -7,56 +22,510 @@ pub trait FrameAllocator {
     /// Returns the starting physical address on success, or `None` if no
     /// usable frame is available.
     fn alloc_frame(&mut self) -> Option<u64>; // 物理アドレス(4KiB aligned)
+
+    /// Return one frame previously handed out by `alloc_frame`.
+    fn free_frame(&mut self, addr: u64) -> Result<(), FrameError>;
+
+    /// Allocate `count` physically contiguous frames.
+    ///
+    /// The first frame is aligned to `align` bytes (a power of two, at least
+    /// `PAGE_SIZE`). Returns the physical address of the first frame.
+    fn alloc_contiguous(&mut self, count: usize, align: u64) -> Option<u64>;
+
+    /// Return `count` contiguous frames starting at `addr`.
+    fn free_contiguous(&mut self, addr: u64, count: usize) -> Result<(), FrameError>;
+
+    /// Snapshot of the allocator counters.
+    fn stats(&self) -> FrameStats;
+}
+
+/// Errors reported when frames are returned to an allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The address is not 4 KiB aligned.
+    Unaligned,
+    /// The address is outside the memory managed by the allocator.
+    OutOfRange,
+    /// The frame is already free (double free) or was never usable.
+    NotAllocated,
+}
+
+/// Frame counters reported by `FrameAllocator::stats`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub used_frames: usize,
+    pub free_frames: usize,
+    /// Usable frames above `MAX_PHYS_ADDR` that the bitmap cannot track.
+    pub ignored_frames: usize,
+}
+
+/// Frame usage of one usable memory region.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RegionUsage {
+    pub start: u64,
+    pub end: u64,
+    pub total_frames: usize,
+    pub used_frames: usize,
+}
+
+impl RegionUsage {
+    fn contains_frame(&self, index: usize) -> bool {
+        let addr: u64 = index as u64 * PAGE_SIZE;
+        self.start <= addr && addr < self.end
+    }
 }
 
-/// Simple bump allocator over usable memory regions.
-pub struct BumpFrameAllocator<I>
-where
-    I: Iterator<Item = MemRegion>,
-{
-    regions: I,
-    current: Option<MemRegion>,
-    next_addr: u64,
+/// Copy of the per-region counters, taken while the allocator is locked.
+#[derive(Debug, Clone, Copy)]
+pub struct RegionUsageTable {
+    entries: [RegionUsage; MAX_TRACKED_REGIONS],
+    len: usize,
 }
 
-impl<I: Iterator<Item = MemRegion>> BumpFrameAllocator<I> {
-    /// Create a bump allocator from an iterator of memory regions.
-    pub fn new(regions: I) -> Self {
+impl RegionUsageTable {
+    const fn new() -> Self {
         Self {
-            regions,
-            current: None,
-            next_addr: 0,
+            entries: [RegionUsage {
+                start: 0,
+                end: 0,
+                total_frames: 0,
+                used_frames: 0,
+            }; MAX_TRACKED_REGIONS],
+            len: 0,
         }
     }
+
+    /// Iterate over the tracked regions in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = &RegionUsage> {
+        self.entries[..self.len].iter()
+    }
+
+    fn push(&mut self, usage: RegionUsage) -> bool {
+        match self.entries.get_mut(self.len) {
+            Some(slot) => {
+                *slot = usage;
+                self.len += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn find_mut(&mut self, index: usize) -> Option<&mut RegionUsage> {
+        self.entries[..self.len]
+            .iter_mut()
+            .find(|region| region.contains_frame(index))
+    }
 }
 
-impl<I: Iterator<Item = MemRegion>> FrameAllocator for BumpFrameAllocator<I> {
-    fn alloc_frame(&mut self) -> Option<u64> {
-        loop {
-            if let Some(region) = &self.current
-                && self.next_addr < region.end {
-                    let addr: u64 = self.next_addr;
-                    self.next_addr += PAGE_SIZE;
+/// Bitmap allocator over usable memory regions.
+///
+/// Each bit represents one 4 KiB frame below `MAX_PHYS_ADDR`; a set bit
+/// means the frame is free. Frames that are not part of a usable region are
+/// simply never marked free, so they can neither be allocated nor freed.
+pub struct BitmapFrameAllocator {
+    bitmap: [u64; BITMAP_WORDS],
+    /// `usable[i]` mirrors the bits that were set by `add_region`.
+    usable: [u64; BITMAP_WORDS],
+    /// One past the highest usable frame index (bounds every scan).
+    frame_limit: usize,
+    /// Word index where the next single-frame search starts.
+    next_word: usize,
+    total_frames: usize,
+    used_frames: usize,
+    ignored_frames: usize,
+    /// Per-region counters; regions beyond `MAX_TRACKED_REGIONS` are only
+    /// reflected in the totals.
+    regions: RegionUsageTable,
+}
 
-                    return Some(addr);
-                }
+impl Default for BitmapFrameAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-            let mut next: MemRegion = self.regions.next()?;
-            if next.kind != MemRegionKind::Usable {
-                continue;
+impl BitmapFrameAllocator {
+    /// Create an allocator that manages no memory yet.
+    ///
+    /// The bitmap is large, so this is meant to initialize a `static` in place
+    /// rather than to build a value on the stack.
+    pub const fn new() -> Self {
+        Self {
+            bitmap: [WORD_ALL_USED; BITMAP_WORDS],
+            usable: [WORD_ALL_USED; BITMAP_WORDS],
+            frame_limit: 0,
+            next_word: 0,
+            total_frames: 0,
+            used_frames: 0,
+            ignored_frames: 0,
+            regions: RegionUsageTable::new(),
+        }
+    }
+
+    /// Forget every region and mark all frames unavailable.
+    pub fn reset(&mut self) {
+        self.bitmap.fill(WORD_ALL_USED);
+        self.usable.fill(WORD_ALL_USED);
+        self.frame_limit = 0;
+        self.next_word = 0;
+        self.total_frames = 0;
+        self.used_frames = 0;
+        self.ignored_frames = 0;
+        self.regions = RegionUsageTable::new();
+    }
+
+    /// Make the frames of a usable region available for allocation.
+    ///
+    /// Non-usable regions are ignored. Partial frames at either end and
+    /// anything above `MAX_PHYS_ADDR` are skipped; the latter are counted
+    /// as ignored.
+    pub fn add_region(&mut self, region: MemRegion) {
+        if region.kind != MemRegionKind::Usable {
+            return;
+        }
+
+        let ignored_start: u64 = align_up(region.start.max(MAX_PHYS_ADDR), PAGE_SIZE);
+        let ignored_end: u64 = align_down(region.end, PAGE_SIZE);
+        if ignored_start < ignored_end {
+            self.ignored_frames += ((ignored_end - ignored_start) / PAGE_SIZE) as usize;
+        }
+
+        let start: u64 = align_up(region.start, PAGE_SIZE);
+        let end: u64 = align_down(region.end.min(MAX_PHYS_ADDR), PAGE_SIZE);
+        if start >= end {
+            return;
+        }
+
+        let first: usize = ((start / PAGE_SIZE) as usize).max(FIRST_ALLOCATABLE_FRAME);
+        let last: usize = (end / PAGE_SIZE) as usize;
+        let mut added: usize = 0;
+        for index in first..last {
+            if !self.is_usable(index) {
+                self.set_usable(index);
+                self.set_free(index);
+                added += 1;
             }
+        }
+        self.total_frames += added;
+        self.frame_limit = self.frame_limit.max(last);
+        self.regions.push(RegionUsage {
+            start,
+            end,
+            total_frames: added,
+            used_frames: 0,
+        });
+    }
+
+    /// Per-region usage counters.
+    pub fn region_usage(&self) -> RegionUsageTable {
+        self.regions
+    }
+
+    /// Returns true if the frame at `addr` is currently allocated.
+    pub fn is_allocated(&self, addr: u64) -> bool {
+        match self.frame_index(addr) {
+            Ok(index) => self.is_usable(index) && !self.is_free(index),
+            Err(_) => false,
+        }
+    }
+
+    fn frame_index(&self, addr: u64) -> Result<usize, FrameError> {
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(FrameError::Unaligned);
+        }
+        let index: usize = (addr / PAGE_SIZE) as usize;
+        if index >= self.frame_limit {
+            return Err(FrameError::OutOfRange);
+        }
+        Ok(index)
+    }
+
+    fn word_limit(&self) -> usize {
+        self.frame_limit.div_ceil(BITS_PER_WORD)
+    }
+
+    fn is_free(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn is_usable(&self, index: usize) -> bool {
+        self.usable[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_free(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn set_used(&mut self, index: usize) {
+        self.bitmap[index / BITS_PER_WORD] &= !(1 << (index % BITS_PER_WORD));
+    }
+
+    fn set_usable(&mut self, index: usize) {
+        self.usable[index / BITS_PER_WORD] |= 1 << (index % BITS_PER_WORD);
+    }
+
+    fn claim(&mut self, index: usize) {
+        self.set_used(index);
+        self.used_frames += 1;
+        if let Some(region) = self.regions.find_mut(index) {
+            region.used_frames += 1;
+        }
+    }
+
+    fn release(&mut self, index: usize) -> Result<(), FrameError> {
+        if !self.is_usable(index) || self.is_free(index) {
+            return Err(FrameError::NotAllocated);
+        }
+        self.set_free(index);
+        self.used_frames -= 1;
+        if let Some(region) = self.regions.find_mut(index) {
+            region.used_frames -= 1;
+        }
+        self.next_word = self.next_word.min(index / BITS_PER_WORD);
+        Ok(())
+    }
+}
 
-            let start: u64 = align_up(next.start, PAGE_SIZE);
-            let end: u64 = align_down(next.end, PAGE_SIZE);
-            if start >= end {
+impl FrameAllocator for BitmapFrameAllocator {
+    fn alloc_frame(&mut self) -> Option<u64> {
+        let words: usize = self.word_limit();
+        for word_index in self.next_word..words {
+            let word: u64 = self.bitmap[word_index];
+            if word == WORD_ALL_USED {
                 continue;
             }
 
-            next.start = start;
-            next.end = end;
+            let index: usize = word_index * BITS_PER_WORD + word.trailing_zeros() as usize;
+            self.claim(index);
+            self.next_word = word_index;
+            return Some(index as u64 * PAGE_SIZE);
+        }
+
+        self.next_word = words;
+        None
+    }
+
+    fn free_frame(&mut self, addr: u64) -> Result<(), FrameError> {
+        let index: usize = self.frame_index(addr)?;
+        self.release(index)
+    }
+
+    fn alloc_contiguous(&mut self, count: usize, align: u64) -> Option<u64> {
+        if count == 0 {
+            return None;
+        }
+        debug_assert!(align.is_power_of_two());
+        let align_frames: usize = (align.max(PAGE_SIZE) / PAGE_SIZE) as usize;
+
+        let mut start: usize = FIRST_ALLOCATABLE_FRAME.next_multiple_of(align_frames);
+        while start.checked_add(count)? <= self.frame_limit {
+            // Find the first frame in the candidate run that is not free.
+            match (start..start + count).find(|&index| !self.is_free(index)) {
+                Some(busy) => {
+                    start = (busy + 1).next_multiple_of(align_frames);
+                }
+                None => {
+                    for index in start..start + count {
+                        self.claim(index);
+                    }
+                    return Some(start as u64 * PAGE_SIZE);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn free_contiguous(&mut self, addr: u64, count: usize) -> Result<(), FrameError> {
+        let first: usize = self.frame_index(addr)?;
+        let last: usize = first.checked_add(count).ok_or(FrameError::OutOfRange)?;
+        if last > self.frame_limit {
+            return Err(FrameError::OutOfRange);
+        }
+        // Validate the whole run first so a bad call leaves the bitmap untouched.
+        if (first..last).any(|index| !self.is_usable(index) || self.is_free(index)) {
+            return Err(FrameError::NotAllocated);
+        }
+        for index in first..last {
+            self.release(index)?;
+        }
+        Ok(())
+    }
+
+    fn stats(&self) -> FrameStats {
+        FrameStats {
+            total_frames: self.total_frames,
+            used_frames: self.used_frames,
+            free_frames: self.total_frames - self.used_frames,
+            ignored_frames: self.ignored_frames,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate alloc;
+
+    use alloc::{boxed::Box, vec::Vec};
+
+    use crate::{
+        BitmapFrameAllocator, FrameAllocator, FrameError, MAX_PHYS_ADDR, MemRegion, MemRegionKind,
+        PAGE_SIZE,
+    };
+
+    fn region(start: u64, end: u64, kind: MemRegionKind) -> MemRegion {
+        MemRegion { start, end, kind }
+    }
+
+    /// An allocator over the usable frames `[start, end)`. Boxed because the
+    /// bitmaps are too large for comfort on a test thread's stack.
+    fn allocator(start: u64, end: u64) -> Box<BitmapFrameAllocator> {
+        let mut allocator: Box<BitmapFrameAllocator> = Box::default();
+        allocator.add_region(region(start, end, MemRegionKind::Usable));
+        allocator
+    }
+
+    #[test]
+    fn freed_frames_are_reused() {
+        let mut frames: Box<BitmapFrameAllocator> = allocator(0x1000, 0x5000);
+        let first: u64 = frames.alloc_frame().unwrap();
+        let second: u64 = frames.alloc_frame().unwrap();
+        assert_eq!((first, second), (0x1000, 0x2000));
+        assert!(frames.is_allocated(first));
+
+        frames.free_frame(first).unwrap();
+        assert!(!frames.is_allocated(first));
+        assert_eq!(frames.alloc_frame(), Some(first));
+        assert_eq!(frames.stats().used_frames, 2);
+    }
+
+    #[test]
+    fn runs_out_of_frames() {
+        let mut frames: Box<BitmapFrameAllocator> = allocator(0x1000, 0x3000);
+        assert!(frames.alloc_frame().is_some());
+        assert!(frames.alloc_frame().is_some());
+        assert_eq!(frames.alloc_frame(), None);
+        assert_eq!(frames.stats().free_frames, 0);
+    }
+
+    #[test]
+    fn double_free_is_rejected() {
+        let mut frames: Box<BitmapFrameAllocator> = allocator(0x1000, 0x5000);
+        let frame: u64 = frames.alloc_frame().unwrap();
+        frames.free_frame(frame).unwrap();
+        assert_eq!(frames.free_frame(frame), Err(FrameError::NotAllocated));
+        assert_eq!(frames.free_frame(frame + 1), Err(FrameError::Unaligned));
+        assert_eq!(frames.free_frame(0x10_0000), Err(FrameError::OutOfRange));
+        assert_eq!(frames.stats().used_frames, 0);
+    }
+
+    #[test]
+    fn contiguous_runs_honor_alignment() {
+        let mut frames: Box<BitmapFrameAllocator> = allocator(0x1000, 0x40_0000);
+        let run: u64 = frames.alloc_contiguous(4, 0x10_0000).unwrap();
+        assert_eq!(run, 0x10_0000);
+        // Frame 0 is never handed out, so a page-aligned run starts at 0x1000.
+        assert_eq!(frames.alloc_contiguous(2, PAGE_SIZE), Some(0x1000));
+        assert_eq!(frames.alloc_contiguous(4, 0x10_0000), Some(0x20_0000));
+        assert_eq!(frames.alloc_contiguous(0, PAGE_SIZE), None);
+        assert_eq!(frames.alloc_contiguous(0x400, PAGE_SIZE), None);
+        assert_eq!(frames.stats().used_frames, 10);
+    }
+
+    #[test]
+    fn contiguous_runs_skip_allocated_frames() {
+        let mut frames: Box<BitmapFrameAllocator> = allocator(0x1000, 0x8000);
+        frames.alloc_frame().unwrap();
+        let blocker: u64 = frames.alloc_frame().unwrap();
+        frames.free_frame(0x1000).unwrap();
+        // 0x1000 is free again but the run cannot cross `blocker` at 0x2000.
+        assert_eq!(
+            frames.alloc_contiguous(3, PAGE_SIZE),
+            Some(blocker + PAGE_SIZE)
+        );
+    }
+
+    #[test]
+    fn free_contiguous_checks_the_whole_run() {
+        let mut frames: Box<BitmapFrameAllocator> = allocator(0x1000, 0x10000);
+        let run: u64 = frames.alloc_contiguous(4, PAGE_SIZE).unwrap();
+
+        // One frame past the run is free, so nothing is released.
+        assert_eq!(
+            frames.free_contiguous(run, 5),
+            Err(FrameError::NotAllocated)
+        );
+        assert_eq!(frames.stats().used_frames, 4);
+        assert_eq!(
+            frames.free_contiguous(run, usize::MAX),
+            Err(FrameError::OutOfRange)
+        );
+
+        frames.free_contiguous(run, 4).unwrap();
+        assert_eq!(frames.stats().used_frames, 0);
+        assert_eq!(
+            frames.free_contiguous(run, 4),
+            Err(FrameError::NotAllocated)
+        );
+    }
+
+    #[test]
+    fn only_usable_regions_are_handed_out() {
+        let mut frames: Box<BitmapFrameAllocator> = Box::default();
+        frames.add_region(region(0x0, 0x3000, MemRegionKind::Usable));
+        frames.add_region(region(0x3000, 0x5000, MemRegionKind::Reserved));
+        frames.add_region(region(0x5000, 0x6000, MemRegionKind::Other));
+        // Partial frames at the ends of a region are skipped.
+        frames.add_region(region(0x6800, 0x9800, MemRegionKind::Usable));
 
-            self.next_addr = next.start;
-            self.current = Some(next);
+        // Frame 0 is never usable; 0x1000-0x3000 and 0x7000-0x9000 are.
+        assert_eq!(frames.stats().total_frames, 4);
+        let mut handed_out: [u64; 4] = [0; 4];
+        for frame in handed_out.iter_mut() {
+            *frame = frames.alloc_frame().unwrap();
         }
+        assert_eq!(handed_out, [0x1000, 0x2000, 0x7000, 0x8000]);
+        assert_eq!(frames.alloc_frame(), None);
+        assert_eq!(frames.free_frame(0x3000), Err(FrameError::NotAllocated));
+        assert_eq!(frames.free_frame(0x0), Err(FrameError::NotAllocated));
+    }
+
+    #[test]
+    fn regions_track_their_own_usage() {
+        let mut frames: Box<BitmapFrameAllocator> = Box::default();
+        frames.add_region(region(0x1000, 0x3000, MemRegionKind::Usable));
+        frames.add_region(region(0x10_0000, 0x10_4000, MemRegionKind::Usable));
+        // Overlapping regions do not count frames twice.
+        frames.add_region(region(0x10_2000, 0x10_6000, MemRegionKind::Usable));
+        frames.alloc_frame().unwrap();
+        frames.alloc_frame().unwrap();
+        frames.alloc_frame().unwrap();
+
+        let counts: Vec<(usize, usize)> = frames
+            .region_usage()
+            .iter()
+            .map(|region| (region.total_frames, region.used_frames))
+            .collect();
+        assert_eq!(counts, [(2, 2), (4, 1), (2, 0)]);
+        assert_eq!(frames.stats().total_frames, 8);
+    }
+
+    #[test]
+    fn memory_above_the_bitmap_is_ignored() {
+        let mut frames: Box<BitmapFrameAllocator> = Box::default();
+        frames.add_region(region(
+            MAX_PHYS_ADDR - 2 * PAGE_SIZE,
+            MAX_PHYS_ADDR + 3 * PAGE_SIZE,
+            MemRegionKind::Usable,
+        ));
+        assert_eq!(frames.stats().total_frames, 2);
+        assert_eq!(frames.stats().ignored_frames, 3);
+
+        frames.reset();
+        assert_eq!(frames.stats().total_frames, 0);
+        assert_eq!(frames.stats().ignored_frames, 0);
+        assert_eq!(frames.alloc_frame(), None);
     }
 }
@@ -1,127 +1,531 @@
 use core::alloc::{GlobalAlloc, Layout};
+use core::mem::{align_of, size_of};
 use core::ptr::null_mut;
-use core::sync::atomic::{AtomicUsize, Ordering};
 
-use x86_64::{
-    VirtAddr,
-    structures::paging::{
-        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
-    },
-};
+use console::serial_try_println;
+use spin::Mutex;
+use x86_64::{VirtAddr, instructions::interrupts, structures::paging::OffsetPageTable};
 
-/// Virtual start address of the heap region.
-pub const HEAP_VIRT_START: usize = 0x_4444_4444_0000;
+use crate::paging::{PageProtection, PagingError, VmmError};
+
+/// Virtual address space reserved for the heap; growth never goes past it.
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024; // 1 GiB
 /// Initial heap size mapped at startup.
 pub const HEAP_INITIAL_SIZE: usize = 1024 * 1024; // 1 MiB
+/// Default ceiling for automatic heap growth (see `set_heap_limit`).
+pub const HEAP_DEFAULT_LIMIT: usize = 64 * 1024 * 1024; // 64 MiB
+/// Minimum amount mapped by one automatic growth step.
+const HEAP_GROW_STEP: usize = 256 * 1024;
+
+/// Every block size and address is a multiple of this, so a free remainder
+/// is always either empty or large enough to hold a `FreeBlock` header.
+const MIN_BLOCK_SIZE: usize = size_of::<FreeBlock>();
+const MIN_BLOCK_ALIGN: usize = align_of::<FreeBlock>();
+
+/// Global allocator instance used by `alloc` types like Box/Vec. Host builds
+/// (unit tests of dependent crates) keep the system allocator.
+#[cfg_attr(target_os = "none", global_allocator)]
+static GLOBAL_ALLOCATOR: LockedHeap = LockedHeap::new();
+
+/// Heap accounting reported by `heap_stats`.
+///
+/// Byte counts use the sizes requested by callers, not the rounded block
+/// sizes the free list works with.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    /// Currently mapped heap size.
+    pub heap_size: usize,
+    /// Bytes sitting in the free list.
+    pub free_bytes: usize,
+    /// Total bytes handed out since boot.
+    pub bytes_allocated: u64,
+    /// Total bytes returned since boot.
+    pub bytes_freed: u64,
+    /// Bytes currently allocated.
+    pub live_bytes: usize,
+    /// Highest value `live_bytes` has reached.
+    pub peak_bytes: usize,
+    /// Number of allocations not yet freed.
+    pub live_allocations: usize,
+}
+
+/// Header written at the start of every free block.
+#[repr(C, align(16))]
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// First-fit allocator over an address-ordered free list.
+///
+/// Freed blocks are merged with their free neighbours, so memory returned by
+/// `dealloc` can be reused by larger allocations later.
+struct LinkedListHeap {
+    head: *mut FreeBlock,
+    heap_start: usize,
+    heap_end: usize,
+    /// Maximum heap size automatic growth may reach (at most `HEAP_MAX_SIZE`).
+    limit: usize,
+    stats: HeapStats,
+}
+
+// The raw pointers only ever point into the heap region, which is guarded by
+// the surrounding mutex.
+unsafe impl Send for LinkedListHeap {}
+
+struct LockedHeap(Mutex<LinkedListHeap>);
+
+impl LockedHeap {
+    const fn new() -> Self {
+        Self(Mutex::new(LinkedListHeap::new()))
+    }
+
+    /// Run `f` with the heap locked and interrupts disabled, so an interrupt
+    /// handler that allocates cannot deadlock on a lock held by the code it
+    /// interrupted.
+    fn with_heap<R>(&self, f: impl FnOnce(&mut LinkedListHeap) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.0.lock()))
+    }
+}
+
+impl LinkedListHeap {
+    const fn new() -> Self {
+        Self {
+            head: null_mut(),
+            heap_start: 0,
+            heap_end: 0,
+            limit: HEAP_DEFAULT_LIMIT,
+            stats: HeapStats {
+                heap_size: 0,
+                free_bytes: 0,
+                bytes_allocated: 0,
+                bytes_freed: 0,
+                live_bytes: 0,
+                peak_bytes: 0,
+                live_allocations: 0,
+            },
+        }
+    }
+
+    fn record_alloc(&mut self, layout: Layout) {
+        let stats = &mut self.stats;
+        stats.bytes_allocated += layout.size() as u64;
+        stats.live_bytes += layout.size();
+        stats.peak_bytes = stats.peak_bytes.max(stats.live_bytes);
+        stats.live_allocations += 1;
+    }
+
+    fn record_dealloc(&mut self, layout: Layout) {
+        let stats = &mut self.stats;
+        stats.bytes_freed += layout.size() as u64;
+        stats.live_bytes -= layout.size();
+        stats.live_allocations -= 1;
+    }
+
+    fn free_bytes(&self) -> usize {
+        let mut total = 0;
+        let mut block = self.head;
+        while !block.is_null() {
+            unsafe {
+                total += (*block).size;
+                block = (*block).next;
+            }
+        }
+        total
+    }
+
+    /// Round a layout up to the block granularity used by the free list.
+    fn block_layout(layout: Layout) -> (usize, usize) {
+        let size = crate::align_up_usize(layout.size().max(MIN_BLOCK_SIZE), MIN_BLOCK_SIZE);
+        let align = layout.align().max(MIN_BLOCK_ALIGN);
+        (size, align)
+    }
+
+    /// Take a fitting block out of the free list, splitting off any unused
+    /// space before and after the allocation.
+    unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::block_layout(layout);
+        let mut link: *mut *mut FreeBlock = &mut self.head;
+
+        unsafe {
+            while !(*link).is_null() {
+                let block = *link;
+                let block_start = block as usize;
+                let block_end = block_start + (*block).size;
+
+                let alloc_start = crate::align_up_usize(block_start, align);
+                let alloc_end = match alloc_start.checked_add(size) {
+                    Some(end) => end,
+                    None => return null_mut(),
+                };
+
+                if alloc_end <= block_end {
+                    let mut rest = (*block).next;
+                    if alloc_end < block_end {
+                        let tail = alloc_end as *mut FreeBlock;
+                        tail.write(FreeBlock {
+                            size: block_end - alloc_end,
+                            next: rest,
+                        });
+                        rest = tail;
+                    }
+                    if alloc_start > block_start {
+                        (*block).size = alloc_start - block_start;
+                        (*block).next = rest;
+                        rest = block;
+                    }
+                    *link = rest;
+                    return alloc_start as *mut u8;
+                }
+
+                link = &mut (*block).next;
+            }
+        }
+
+        null_mut()
+    }
 
-/// Global allocator instance used by `alloc` types like Box/Vec.
-#[global_allocator]
-static GLOBAL_ALLOCATOR: BumpAllocator = BumpAllocator;
+    /// Insert `[addr, addr + size)` into the free list, merging it with the
+    /// previous and next free blocks when they are adjacent.
+    ///
+    /// # Safety
+    /// The range must be mapped, unused, and aligned to `MIN_BLOCK_ALIGN` with
+    /// a size that is a multiple of `MIN_BLOCK_SIZE`.
+    unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
+        if size == 0 {
+            return;
+        }
+
+        unsafe {
+            let mut prev: *mut FreeBlock = null_mut();
+            let mut next = self.head;
+            while !next.is_null() && (next as usize) < addr {
+                prev = next;
+                next = (*next).next;
+            }
 
-/// Heap start address (set by `init_heap`).
-static HEAP_START: AtomicUsize = AtomicUsize::new(0);
-/// Heap end address (set by `init_heap` or `grow_heap`).
-static HEAP_END: AtomicUsize = AtomicUsize::new(0);
-/// Next allocation pointer (bump cursor).
-static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let node = addr as *mut FreeBlock;
+            node.write(FreeBlock { size, next });
+            if prev.is_null() {
+                self.head = node;
+            } else {
+                (*prev).next = node;
+            }
 
-/// Simple bump allocator with no deallocation.
-struct BumpAllocator;
+            if !next.is_null() && addr + size == next as usize {
+                (*node).size += (*next).size;
+                (*node).next = (*next).next;
+            }
+
+            if !prev.is_null() && prev as usize + (*prev).size == addr {
+                (*prev).size += (*node).size;
+                (*prev).next = (*node).next;
+            }
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::block_layout(layout);
+        unsafe { self.add_free_region(ptr as usize, size) };
+    }
+
+    /// Map enough pages after the heap end to satisfy `layout`, staying
+    /// within `limit`. Returns true if any memory was added.
+    ///
+    /// Runs with the heap locked, so it must not allocate: the mapper and the
+    /// global frame allocator only touch page tables and the frame bitmap.
+    /// The caller may hold the kernel mapper or the serial port (an
+    /// allocation while formatting a `serial_println!`), so both are only
+    /// tried: growth fails if the mapper is busy, and logging is dropped if
+    /// the port is.
+    fn grow_for(&mut self, layout: Layout) -> bool {
+        if self.heap_start == 0 {
+            return false;
+        }
+
+        let (size, align) = Self::block_layout(layout);
+        let page_size = crate::PAGE_SIZE as usize;
+        let Some(needed) = size.checked_add(align) else {
+            return false;
+        };
+        let wanted = crate::align_up_usize(needed.max(HEAP_GROW_STEP), page_size);
+        let available =
+            self.limit.saturating_sub(self.heap_end - self.heap_start) & !(page_size - 1);
+        let grow = wanted.min(available);
+        if grow == 0 {
+            serial_try_println!(
+                "heap: limit of {} KiB reached (request {} bytes)",
+                self.limit / 1024,
+                layout.size()
+            );
+            return false;
+        }
+
+        let old_end = self.heap_end;
+        let Some(result) =
+            crate::paging::try_with_mapper(|mapper| map_heap_range(old_end, grow, mapper))
+        else {
+            serial_try_println!("heap: cannot grow while the kernel mapper is held");
+            return false;
+        };
+        let mapped = match result {
+            Ok(()) => grow,
+            Err((mapped, e)) => {
+                serial_try_println!("heap: growth stopped after {} KiB: {:?}", mapped / 1024, e);
+                mapped
+            }
+        };
+        if mapped == 0 {
+            return false;
+        }
+
+        self.heap_end = old_end + mapped;
+        unsafe { self.add_free_region(old_end, mapped) };
+        serial_try_println!(
+            "heap: grew by {} KiB to {} KiB",
+            mapped / 1024,
+            (self.heap_end - self.heap_start) / 1024
+        );
+        true
+    }
+}
 
 /// Initialize the heap by mapping an initial range of pages.
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    map_heap_range(HEAP_VIRT_START, HEAP_INITIAL_SIZE, mapper, frame_allocator)?;
-
-    HEAP_START.store(HEAP_VIRT_START, Ordering::SeqCst);
-    HEAP_END.store(HEAP_VIRT_START + HEAP_INITIAL_SIZE, Ordering::SeqCst);
-    NEXT.store(HEAP_VIRT_START, Ordering::SeqCst);
+///
+/// The heap's address range is reserved from the kernel VMM and mapped
+/// through the kernel mapper, so `paging::init` must have been called first.
+pub fn init_heap() -> Result<(), VmmError> {
+    let start = crate::paging::vmm::reserve_range(HEAP_MAX_SIZE as u64, "heap")?.as_u64() as usize;
+    crate::paging::with_mapper(|mapper| map_heap_range(start, HEAP_INITIAL_SIZE, mapper))
+        .ok_or(VmmError::NoMapper)?
+        .map_err(|(_, e)| e)?;
+
+    GLOBAL_ALLOCATOR.with_heap(|heap| {
+        heap.heap_start = start;
+        heap.heap_end = start + HEAP_INITIAL_SIZE;
+        unsafe { heap.add_free_region(start, HEAP_INITIAL_SIZE) };
+    });
     Ok(())
 }
 
 /// Grow the heap by mapping additional pages after the current end.
-pub fn grow_heap(
-    additional_bytes: usize,
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+///
+/// Fails with `NotReserved` before `init_heap` and with `OutOfVirtualSpace`
+/// when the heap would exceed `HEAP_MAX_SIZE`.
+pub fn grow_heap(additional_bytes: usize) -> Result<(), VmmError> {
     if additional_bytes == 0 {
         return Ok(());
     }
 
-    let old_end = HEAP_END.load(Ordering::SeqCst);
+    let (heap_start, old_end) = GLOBAL_ALLOCATOR.with_heap(|heap| (heap.heap_start, heap.heap_end));
+    if heap_start == 0 {
+        return Err(VmmError::NotReserved);
+    }
     let size = crate::align_up_usize(additional_bytes, crate::PAGE_SIZE as usize);
     let new_end = old_end
         .checked_add(size)
-        .ok_or(MapToError::FrameAllocationFailed)?;
+        .filter(|end| *end - heap_start <= HEAP_MAX_SIZE)
+        .ok_or(VmmError::OutOfVirtualSpace)?;
 
-    map_heap_range(old_end, size, mapper, frame_allocator)?;
-    HEAP_END.store(new_end, Ordering::SeqCst);
-    Ok(())
+    let result = crate::paging::with_mapper(|mapper| map_heap_range(old_end, size, mapper))
+        .ok_or(VmmError::NoMapper)?;
+    // Keep whatever was mapped before a failure so those pages are not lost.
+    let mapped = match result {
+        Ok(()) => size,
+        Err((mapped, _)) => mapped,
+    };
+    GLOBAL_ALLOCATOR.with_heap(|heap| {
+        heap.heap_end = old_end + mapped;
+        unsafe { heap.add_free_region(old_end, mapped) };
+    });
+    debug_assert!(result.is_err() || old_end + mapped == new_end);
+    result.map_err(|(_, e)| e.into())
 }
 
-unsafe impl GlobalAlloc for BumpAllocator {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let start: usize = HEAP_START.load(Ordering::Acquire);
-
-        if start == 0 {
-            return null_mut();
-        }
-
-        let end: usize = HEAP_END.load(Ordering::Acquire);
+/// Snapshot of the heap accounting counters.
+pub fn heap_stats() -> HeapStats {
+    GLOBAL_ALLOCATOR.with_heap(|heap| HeapStats {
+        heap_size: heap.heap_end - heap.heap_start,
+        free_bytes: heap.free_bytes(),
+        ..heap.stats
+    })
+}
 
-        loop {
-            let current: usize = NEXT.load(Ordering::Relaxed);
-            let alloc_start: usize = crate::align_up_usize(current, layout.align());
-            let alloc_end: usize = match alloc_start.checked_add(layout.size()) {
-                Some(v) => v,
-                None => return null_mut(),
-            };
+/// Set the ceiling for automatic heap growth, in bytes.
+///
+/// Allocations that cannot be satisfied within this size fail instead of
+/// mapping more pages. Does not shrink an already larger heap, and is capped
+/// at `HEAP_MAX_SIZE`.
+pub fn set_heap_limit(max_bytes: usize) {
+    GLOBAL_ALLOCATOR.with_heap(|heap| heap.limit = max_bytes.min(HEAP_MAX_SIZE));
+}
 
-            if alloc_end > end {
-                return null_mut();
+unsafe impl GlobalAlloc for LockedHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_heap(|heap| unsafe {
+            let mut ptr = heap.allocate(layout);
+            if ptr.is_null() && heap.grow_for(layout) {
+                ptr = heap.allocate(layout);
             }
-
-            if NEXT
-                .compare_exchange(current, alloc_end, Ordering::AcqRel, Ordering::Relaxed)
-                .is_ok()
-            {
-                return alloc_start as *mut u8;
+            if !ptr.is_null() {
+                heap.record_alloc(layout);
             }
-        }
+            ptr
+        })
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with_heap(|heap| unsafe {
+            heap.deallocate(ptr, layout);
+            heap.record_dealloc(layout);
+        })
+    }
 }
 
+/// Map fresh frames for `[start, start + size)`.
+///
+/// Large, 2 MiB aligned stretches are mapped with huge pages. On failure the
+/// error carries the number of bytes mapped before it, so the caller can
+/// still hand that prefix to the heap.
 fn map_heap_range(
     start: usize,
     size: usize,
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let start = crate::align_up_usize(start, crate::PAGE_SIZE as usize);
-    let end_unaligned = start
-        .checked_add(size)
-        .ok_or(MapToError::FrameAllocationFailed)?;
-    let end = crate::align_up_usize(end_unaligned, crate::PAGE_SIZE as usize);
+    mapper: &mut OffsetPageTable<'static>,
+) -> Result<(), (usize, PagingError)> {
+    crate::paging::map_fresh_range(
+        VirtAddr::new(start as u64),
+        size as u64,
+        PageProtection::KERNEL_DATA,
+        mapper,
+    )
+    .map_err(|(mapped, e)| (mapped as usize, e))
+}
 
-    if start >= end {
-        return Ok(());
+#[cfg(test)]
+mod tests {
+    use core::alloc::Layout;
+
+    use super::{FreeBlock, LinkedListHeap};
+
+    const ARENA_SIZE: usize = 16 * 1024;
+
+    /// Backing memory for a heap under test.
+    #[repr(C, align(4096))]
+    struct Arena([u8; ARENA_SIZE]);
+
+    fn heap(arena: &mut Arena) -> LinkedListHeap {
+        let start: usize = arena.0.as_mut_ptr() as usize;
+        let mut heap: LinkedListHeap = LinkedListHeap::new();
+        heap.heap_start = start;
+        heap.heap_end = start + ARENA_SIZE;
+        unsafe { heap.add_free_region(start, ARENA_SIZE) };
+        heap
     }
 
-    let start_page = Page::containing_address(VirtAddr::new(start as u64));
-    let end_page = Page::containing_address(VirtAddr::new((end - 1) as u64));
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    fn layout(size: usize, align: usize) -> Layout {
+        Layout::from_size_align(size, align).unwrap()
+    }
 
-    for page in Page::range_inclusive(start_page, end_page) {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    fn alloc(heap: &mut LinkedListHeap, size: usize, align: usize) -> usize {
+        let ptr: *mut u8 = unsafe { heap.allocate(layout(size, align)) };
+        assert!(!ptr.is_null());
+        ptr as usize
     }
 
-    Ok(())
+    fn free(heap: &mut LinkedListHeap, addr: usize, size: usize, align: usize) {
+        unsafe { heap.deallocate(addr as *mut u8, layout(size, align)) };
+    }
+
+    /// Number of blocks in the free list.
+    fn free_blocks(heap: &LinkedListHeap) -> usize {
+        let mut count: usize = 0;
+        let mut block: *mut FreeBlock = heap.head;
+        while !block.is_null() {
+            count += 1;
+            block = unsafe { (*block).next };
+        }
+        count
+    }
+
+    #[test]
+    fn freed_space_is_reused() {
+        let mut arena: Arena = Arena([0; ARENA_SIZE]);
+        let mut heap: LinkedListHeap = heap(&mut arena);
+        let first: usize = alloc(&mut heap, 64, 8);
+        let second: usize = alloc(&mut heap, 64, 8);
+        assert_eq!(second, first + 64);
+
+        free(&mut heap, first, 64, 8);
+        assert_eq!(alloc(&mut heap, 48, 8), first);
+        // The block was split: the last 16 bytes of the hole stay free.
+        assert_eq!(heap.free_bytes(), ARENA_SIZE - 64 - 48);
+        assert_eq!(free_blocks(&heap), 2);
+    }
+
+    #[test]
+    fn adjacent_frees_merge() {
+        let mut arena: Arena = Arena([0; ARENA_SIZE]);
+        let mut heap: LinkedListHeap = heap(&mut arena);
+        let a: usize = alloc(&mut heap, 256, 8);
+        let b: usize = alloc(&mut heap, 256, 8);
+        let c: usize = alloc(&mut heap, 256, 8);
+
+        free(&mut heap, a, 256, 8);
+        free(&mut heap, c, 256, 8);
+        // `c` merged with the free tail; `a` is a separate hole.
+        assert_eq!(free_blocks(&heap), 2);
+
+        free(&mut heap, b, 256, 8);
+        assert_eq!(free_blocks(&heap), 1);
+        assert_eq!(heap.free_bytes(), ARENA_SIZE);
+        // The merged block serves an allocation spanning all three.
+        assert_eq!(alloc(&mut heap, 768, 8), a);
+    }
+
+    #[test]
+    fn first_fit_picks_the_lowest_hole() {
+        let mut arena: Arena = Arena([0; ARENA_SIZE]);
+        let mut heap: LinkedListHeap = heap(&mut arena);
+        let small: usize = alloc(&mut heap, 64, 8);
+        let _guard: usize = alloc(&mut heap, 64, 8);
+        let large: usize = alloc(&mut heap, 512, 8);
+        let _guard: usize = alloc(&mut heap, 64, 8);
+        free(&mut heap, small, 64, 8);
+        free(&mut heap, large, 512, 8);
+
+        assert_eq!(alloc(&mut heap, 32, 8), small);
+        assert_eq!(alloc(&mut heap, 128, 8), large);
+    }
+
+    #[test]
+    fn alignment_padding_stays_free() {
+        let mut arena: Arena = Arena([0; ARENA_SIZE]);
+        let mut heap: LinkedListHeap = heap(&mut arena);
+        let start: usize = alloc(&mut heap, 16, 8);
+        let aligned: usize = alloc(&mut heap, 64, 1024);
+        assert_eq!(aligned % 1024, 0);
+        assert_eq!(aligned, start + 1024);
+
+        // The gap in front of the aligned block is still usable.
+        let padding: usize = alloc(&mut heap, 512, 8);
+        assert!(start < padding && padding + 512 <= aligned);
+
+        free(&mut heap, padding, 512, 8);
+        free(&mut heap, aligned, 64, 1024);
+        free(&mut heap, start, 16, 8);
+        assert_eq!(free_blocks(&heap), 1);
+        assert_eq!(heap.free_bytes(), ARENA_SIZE);
+    }
+
+    #[test]
+    fn exhaustion_returns_null() {
+        let mut arena: Arena = Arena([0; ARENA_SIZE]);
+        let mut heap: LinkedListHeap = heap(&mut arena);
+        let all: usize = alloc(&mut heap, ARENA_SIZE, 8);
+        assert!(unsafe { heap.allocate(layout(16, 8)) }.is_null());
+
+        free(&mut heap, all, ARENA_SIZE, 8);
+        assert!(unsafe { heap.allocate(layout(ARENA_SIZE + 16, 8)) }.is_null());
+        assert_eq!(heap.free_bytes(), ARENA_SIZE);
+    }
 }
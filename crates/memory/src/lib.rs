@@ -1,18 +1,30 @@
 #![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_main)]
 
-use crate::frame::FrameAllocator;
+use console::serial_println;
 use spin::Mutex;
 
 mod frame;
 mod heap;
+pub mod mmio;
 pub mod paging;
 
 /// 4 KiB page size used by the memory subsystem.
 pub const PAGE_SIZE: u64 = 4096;
 
+/// Physical frame allocator interface and its bitmap implementation.
+pub use frame::{
+    BitmapFrameAllocator, FrameAllocator, FrameError, FrameStats, MAX_PHYS_ADDR,
+    MAX_TRACKED_REGIONS, RegionUsage, RegionUsageTable,
+};
 /// Initialize the global heap allocator backing store.
-pub use heap::{HEAP_INITIAL_SIZE, HEAP_VIRT_START, grow_heap, init_heap};
+pub use heap::{
+    HEAP_DEFAULT_LIMIT, HEAP_INITIAL_SIZE, HEAP_MAX_SIZE, HeapStats, grow_heap, heap_stats,
+    init_heap, set_heap_limit,
+};
+
+/// Uncached register mappings for memory-mapped devices.
+pub use mmio::{MmioRegion, MmioValue, Register, map_mmio};
 
 /// Memory region description provided by the bootloader.
 #[derive(Debug, Clone, Copy)]
@@ -30,28 +42,7 @@ pub enum MemRegionKind {
     Other,
 }
 
-struct MemRegionIter {
-    regions: &'static [MemRegion],
-    index: usize,
-}
-
-impl MemRegionIter {
-    fn new(regions: &'static [MemRegion]) -> Self {
-        Self { regions, index: 0 }
-    }
-}
-
-impl Iterator for MemRegionIter {
-    type Item = MemRegion;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let region = self.regions.get(self.index)?;
-        self.index += 1;
-        Some(*region)
-    }
-}
-
-static FRAME_ALLOCATOR: Mutex<Option<frame::BumpFrameAllocator<MemRegionIter>>> = Mutex::new(None);
+static FRAME_ALLOCATOR: Mutex<BitmapFrameAllocator> = Mutex::new(BitmapFrameAllocator::new());
 
 /// Generic address range [start, end) used for range checks.
 #[derive(Clone, Copy, Debug)]
@@ -88,16 +79,119 @@ pub fn align_down(x: u64, a: u64) -> u64 {
     x & !(a - 1)
 }
 
-/// Initialize the global frame allocator from a memory region slice.
-pub fn init_frame_allocator(regions: &'static [MemRegion]) {
-    let mut allocator = FRAME_ALLOCATOR.lock();
-    *allocator = Some(frame::BumpFrameAllocator::new(MemRegionIter::new(regions)));
+/// Initialize the global frame allocator from the bootloader memory regions.
+///
+/// Must run before anything allocates frames (including `init_heap`), since
+/// every usable frame starts out free.
+pub fn init_frame_allocator<I>(regions: I)
+where
+    I: IntoIterator<Item = MemRegion>,
+{
+    let ignored_frames: usize = {
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        allocator.reset();
+        for region in regions {
+            allocator.add_region(region);
+        }
+        allocator.stats().ignored_frames
+    };
+    if ignored_frames > 0 {
+        serial_println!(
+            "frames: ignoring {} MiB of usable memory above 0x{:x}",
+            ignored_frames as u64 * PAGE_SIZE / (1024 * 1024),
+            MAX_PHYS_ADDR
+        );
+    }
 }
 
 /// Allocate one physical frame from the global allocator.
 pub fn alloc_frame() -> Option<u64> {
-    let mut allocator = FRAME_ALLOCATOR.lock();
-    allocator.as_mut()?.alloc_frame()
+    FRAME_ALLOCATOR.lock().alloc_frame()
+}
+
+/// Return one physical frame to the global allocator.
+pub fn free_frame(addr: u64) -> Result<(), FrameError> {
+    FRAME_ALLOCATOR.lock().free_frame(addr)
+}
+
+/// Allocate `count` physically contiguous frames (e.g. for DMA buffers).
+///
+/// The returned address is aligned to `align` bytes.
+pub fn alloc_frames(count: usize, align: u64) -> Option<u64> {
+    FRAME_ALLOCATOR.lock().alloc_contiguous(count, align)
+}
+
+/// Return `count` contiguous frames starting at `addr`.
+pub fn free_frames(addr: u64, count: usize) -> Result<(), FrameError> {
+    FRAME_ALLOCATOR.lock().free_contiguous(addr, count)
+}
+
+/// Total/used/free frame counters of the global allocator.
+pub fn frame_stats() -> FrameStats {
+    FRAME_ALLOCATOR.lock().stats()
+}
+
+/// Per-region frame usage of the global allocator.
+pub fn frame_region_usage() -> RegionUsageTable {
+    FRAME_ALLOCATOR.lock().region_usage()
+}
+
+/// Dump heap and frame allocator accounting to the provided console.
+pub fn dump_memory_usage(console: &mut impl core::fmt::Write) {
+    // Take both snapshots before writing so no allocator lock is held while
+    // the console runs.
+    let heap: HeapStats = heap_stats();
+    let frames: FrameStats = frame_stats();
+    let regions: RegionUsageTable = frame_region_usage();
+
+    writeln!(console, "== heap ==").ok();
+    writeln!(
+        console,
+        "size: {} KiB free: {} KiB",
+        heap.heap_size / 1024,
+        heap.free_bytes / 1024
+    )
+    .ok();
+    writeln!(
+        console,
+        "live: {} bytes in {} allocations (peak {} bytes)",
+        heap.live_bytes, heap.live_allocations, heap.peak_bytes
+    )
+    .ok();
+    writeln!(
+        console,
+        "allocated: {} bytes freed: {} bytes",
+        heap.bytes_allocated, heap.bytes_freed
+    )
+    .ok();
+
+    writeln!(console, "== frames ==").ok();
+    writeln!(
+        console,
+        "total: {} used: {} free: {} ({} KiB free)",
+        frames.total_frames,
+        frames.used_frames,
+        frames.free_frames,
+        frames.free_frames as u64 * PAGE_SIZE / 1024
+    )
+    .ok();
+    if frames.ignored_frames > 0 {
+        writeln!(
+            console,
+            "ignored: {} KiB above 0x{:x}",
+            frames.ignored_frames as u64 * PAGE_SIZE / 1024,
+            MAX_PHYS_ADDR
+        )
+        .ok();
+    }
+    for region in regions.iter() {
+        writeln!(
+            console,
+            "0x{:016x} - 0x{:016x} used {}/{}",
+            region.start, region.end, region.used_frames, region.total_frames
+        )
+        .ok();
+    }
 }
 
 /// Dump the bootloader memory map to the provided console.
@@ -0,0 +1,144 @@
+//! Uncached mappings of device registers (PCI memory BARs, APIC, ...).
+//!
+//! `map_mmio` reserves kernel virtual space through the VMM, maps the
+//! physical range with caching disabled and hands back an `MmioRegion`
+//! whose accessors always use volatile loads and stores.
+use core::marker::PhantomData;
+use core::mem::size_of;
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::PAGE_SIZE;
+use crate::paging::{PageProtection, VmmError, vmm};
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// Register widths that can be accessed through an `MmioRegion`.
+pub trait MmioValue: sealed::Sealed + Copy {}
+
+macro_rules! mmio_value {
+    ($($ty:ty),*) => {
+        $(
+            impl sealed::Sealed for $ty {}
+            impl MmioValue for $ty {}
+        )*
+    };
+}
+
+mmio_value!(u8, u16, u32, u64);
+
+/// A mapped device register window.
+///
+/// Offsets are relative to the physical address passed to `map_mmio`. The
+/// mapping is released when the region is dropped.
+pub struct MmioRegion {
+    /// Virtual address of `phys`, including its offset into the first page.
+    base: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    /// Start of the VMM reservation (page aligned).
+    mapping: VirtAddr,
+}
+
+impl MmioRegion {
+    /// Physical address the region starts at.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    /// Virtual address the region starts at.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.base
+    }
+
+    /// Size of the region in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Volatile read of the register at `offset`.
+    ///
+    /// Panics if the access is misaligned or leaves the region.
+    pub fn read<T: MmioValue>(&self, offset: u64) -> T {
+        unsafe { self.ptr::<T>(offset).read_volatile() }
+    }
+
+    /// Volatile write of `value` to the register at `offset`.
+    ///
+    /// Panics if the access is misaligned or leaves the region.
+    pub fn write<T: MmioValue>(&self, offset: u64, value: T) {
+        unsafe { self.ptr::<T>(offset).write_volatile(value) }
+    }
+
+    /// Typed handle for the register at `offset`.
+    pub fn register<T: MmioValue>(&self, offset: u64) -> Register<'_, T> {
+        Register {
+            ptr: self.ptr::<T>(offset),
+            _region: PhantomData,
+        }
+    }
+
+    fn ptr<T: MmioValue>(&self, offset: u64) -> *mut T {
+        let width = size_of::<T>() as u64;
+        assert!(
+            offset.is_multiple_of(width) && offset.saturating_add(width) <= self.size,
+            "mmio access of {} bytes at offset 0x{:x} outside region of 0x{:x} bytes",
+            width,
+            offset,
+            self.size
+        );
+        (self.base + offset).as_mut_ptr()
+    }
+}
+
+impl Drop for MmioRegion {
+    fn drop(&mut self) {
+        let _ = vmm::release(self.mapping);
+    }
+}
+
+/// One register inside an `MmioRegion`.
+#[derive(Clone, Copy)]
+pub struct Register<'a, T: MmioValue> {
+    ptr: *mut T,
+    _region: PhantomData<&'a MmioRegion>,
+}
+
+impl<T: MmioValue> Register<'_, T> {
+    /// Volatile read of the register.
+    pub fn read(&self) -> T {
+        unsafe { self.ptr.read_volatile() }
+    }
+
+    /// Volatile write of the register.
+    pub fn write(&self, value: T) {
+        unsafe { self.ptr.write_volatile(value) }
+    }
+
+    /// Read the register, apply `f` and write the result back.
+    pub fn modify(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+/// Map `[phys, phys + size)` uncached into kernel virtual space.
+///
+/// `phys` does not need to be page aligned; the surrounding pages are mapped
+/// and offsets into the returned region stay relative to `phys`.
+pub fn map_mmio(phys: u64, size: u64) -> Result<MmioRegion, VmmError> {
+    if size == 0 {
+        return Err(VmmError::EmptyRange);
+    }
+    let page_start = PhysAddr::new(phys).align_down(PAGE_SIZE);
+    let offset = phys - page_start.as_u64();
+    let mapping = vmm::map_physical(page_start, offset + size, PageProtection::MMIO, "mmio")?;
+
+    Ok(MmioRegion {
+        base: mapping + offset,
+        phys: PhysAddr::new(phys),
+        size,
+        mapping,
+    })
+}
@@ -1,26 +1,231 @@
+use core::arch::x86_64::__cpuid;
+
+use spin::{Mutex, Once};
 use x86_64::{
     PhysAddr, VirtAddr,
-    registers::control::Cr3,
+    instructions::interrupts,
+    registers::{
+        control::Cr3,
+        model_specific::{Efer, EferFlags},
+    },
     structures::paging::{
-        FrameAllocator, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
-        mapper::MapToError,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+        mapper::{FlagUpdateError, MapToError, TranslateResult, UnmapError},
     },
 };
 
-use crate::{MemRegion, MemRegionKind, PAGE_SIZE, align_down, align_up};
+use crate::PAGE_SIZE;
+
+const CPUID_EXTENDED_MAX: u32 = 0x8000_0000;
+const CPUID_EXTENDED_FEATURES: u32 = 0x8000_0001;
+/// EDX bit of `CPUID_EXTENDED_FEATURES` advertising 1 GiB pages.
+const CPUID_PDPE1GB: u32 = 1 << 26;
+/// 4 KiB frames backing one 2 MiB page.
+const HUGE_FRAME_COUNT: usize = (Size2MiB::SIZE / PAGE_SIZE) as usize;
+
+pub mod vmm;
+
+pub use vmm::{KernelStack, VmBacking, VmRegion, VmmError};
+
+/// Errors reported by the page mapping helpers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    /// No frame was available for an intermediate page table.
+    FrameAllocationFailed,
+    /// The page is already mapped to the given physical frame.
+    PageAlreadyMapped(PhysAddr),
+    /// The page is not mapped.
+    PageNotMapped,
+    /// A higher level entry maps a huge page that covers the page.
+    ParentEntryHugePage,
+    /// A page table entry points to an invalid physical address.
+    InvalidFrameAddress(PhysAddr),
+    /// An address or size is not a multiple of the page size.
+    Unaligned,
+    /// The range is empty or wraps around the address space.
+    InvalidRange,
+}
+
+impl<S: PageSize> From<MapToError<S>> for PagingError {
+    fn from(e: MapToError<S>) -> Self {
+        match e {
+            MapToError::FrameAllocationFailed => PagingError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+            MapToError::PageAlreadyMapped(frame) => {
+                PagingError::PageAlreadyMapped(frame.start_address())
+            }
+        }
+    }
+}
+
+impl From<UnmapError> for PagingError {
+    fn from(e: UnmapError) -> Self {
+        match e {
+            UnmapError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+            UnmapError::PageNotMapped => PagingError::PageNotMapped,
+            UnmapError::InvalidFrameAddress(addr) => PagingError::InvalidFrameAddress(addr),
+        }
+    }
+}
+
+impl From<FlagUpdateError> for PagingError {
+    fn from(e: FlagUpdateError) -> Self {
+        match e {
+            FlagUpdateError::PageNotMapped => PagingError::PageNotMapped,
+            FlagUpdateError::ParentEntryHugePage => PagingError::ParentEntryHugePage,
+        }
+    }
+}
+
+/// Caching mode of a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Normal write-back caching for RAM.
+    WriteBack,
+    /// Writes go straight to memory; reads may be cached (e.g. framebuffers).
+    WriteThrough,
+    /// Caching disabled, as device registers (MMIO) require.
+    Uncached,
+}
+
+/// Access rights and caching of a mapping, turned into page table flags by
+/// `map_range` and `protect_range`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageProtection {
+    pub writable: bool,
+    pub executable: bool,
+    pub user: bool,
+    pub cache: CachePolicy,
+}
+
+impl PageProtection {
+    /// Kernel read/write data.
+    pub const KERNEL_DATA: PageProtection = PageProtection {
+        writable: true,
+        executable: false,
+        user: false,
+        cache: CachePolicy::WriteBack,
+    };
+    /// Kernel read-only data.
+    pub const KERNEL_READ_ONLY: PageProtection = PageProtection {
+        writable: false,
+        ..Self::KERNEL_DATA
+    };
+    /// Kernel code: read-only and executable.
+    pub const KERNEL_CODE: PageProtection = PageProtection {
+        writable: false,
+        executable: true,
+        ..Self::KERNEL_DATA
+    };
+    /// Device registers: read/write, never executable, uncached.
+    pub const MMIO: PageProtection = PageProtection {
+        cache: CachePolicy::Uncached,
+        ..Self::KERNEL_DATA
+    };
+    /// User read/write data.
+    pub const USER_DATA: PageProtection = PageProtection {
+        user: true,
+        ..Self::KERNEL_DATA
+    };
+
+    /// Page table flags for a present page with this protection.
+    ///
+    /// `NO_EXECUTE` is only set when the CPU has it enabled (EFER.NXE), since
+    /// the bit is reserved otherwise.
+    pub fn flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        if self.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.user {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        if !self.executable && no_execute_enabled() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        match self.cache {
+            CachePolicy::WriteBack => {}
+            CachePolicy::WriteThrough => flags |= PageTableFlags::WRITE_THROUGH,
+            CachePolicy::Uncached => {
+                flags |= PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_CACHE
+            }
+        }
+        flags
+    }
+
+    /// Flags intermediate page tables need so the page stays reachable.
+    fn table_flags(self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        if self.user {
+            flags |= PageTableFlags::USER_ACCESSIBLE;
+        }
+        flags
+    }
+}
+
+/// A mapped page as reported by `translate` and `unmap_page`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    /// Physical address the virtual address maps to.
+    pub phys: PhysAddr,
+    /// Flags of the page table entry that maps it.
+    pub flags: PageTableFlags,
+    /// Size of the page containing the address (4 KiB, 2 MiB or 1 GiB).
+    pub page_size: u64,
+}
+
+/// Physical memory offset recorded by the first call to `init`.
+static PHYSICAL_MEMORY_OFFSET: Once<VirtAddr> = Once::new();
+
+/// The one mapper over the active level 4 table, created by `init`.
+///
+/// A second `OffsetPageTable` over the same table would alias it, so every
+/// page table change goes through this lock (see `with_mapper`).
+static KERNEL_MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
 
-/// Initialize an `OffsetPageTable` using the current active level 4 page table.
+/// Set up the kernel mapper for the current active level 4 page table.
+///
+/// The first call also sets up the kernel virtual memory manager (`vmm`);
+/// later calls do nothing.
 ///
 /// # Safety
 /// The `physical_memory_offset` must map the complete physical memory, as provided
 /// by the bootloader config. Using the wrong offset will cause undefined behavior.
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
-    let level_4_table: &'static mut PageTable =
-        unsafe { active_level_4_table(physical_memory_offset) };
-    unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) }
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    KERNEL_MAPPER.call_once(|| {
+        let level_4_table: &'static mut PageTable =
+            unsafe { active_level_4_table(physical_memory_offset) };
+        let mut mapper = unsafe { OffsetPageTable::new(level_4_table, physical_memory_offset) };
+        vmm::init(&mut mapper);
+        PHYSICAL_MEMORY_OFFSET.call_once(|| physical_memory_offset);
+        Mutex::new(mapper)
+    });
+}
+
+/// Physical memory offset passed to `init`, if paging has been initialized.
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    PHYSICAL_MEMORY_OFFSET.get().copied()
+}
+
+/// Run `f` with the kernel mapper locked and interrupts disabled. Returns
+/// `None` before `init`.
+///
+/// `f` must not allocate or map memory itself: heap growth needs the mapper
+/// as well and fails rather than wait for it, and the VMM would deadlock.
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> Option<R> {
+    let mapper: &Mutex<OffsetPageTable<'static>> = KERNEL_MAPPER.get()?;
+    Some(interrupts::without_interrupts(|| f(&mut mapper.lock())))
+}
+
+/// Like `with_mapper`, but returns `None` instead of waiting when the mapper
+/// is already locked, for fault handlers and heap growth.
+pub fn try_with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> Option<R> {
+    let mapper: &Mutex<OffsetPageTable<'static>> = KERNEL_MAPPER.get()?;
+    interrupts::without_interrupts(|| mapper.try_lock().map(|mut mapper| f(&mut mapper)))
 }
 
-/// Frame allocator backed by the global `alloc_frame` bump allocator.
+/// Frame allocator backed by the global bitmap frame allocator.
 pub struct GlobalFrameAllocator;
 
 unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
@@ -30,89 +235,412 @@ unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
     }
 }
 
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        // Freeing a frame the allocator does not own is a caller bug; the
+        // bitmap is left untouched in that case.
+        let _ = crate::free_frame(frame.start_address().as_u64());
+    }
+}
+
 /// Map a single 4 KiB page to the specified physical frame.
 pub fn map_one_page(
     virt: VirtAddr,
     phys: PhysAddr,
     mapper: &mut OffsetPageTable<'static>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    use x86_64::structures::paging::Mapper;
+) -> Result<(), PagingError> {
+    let page = Page::<Size4KiB>::containing_address(virt);
+    let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let protection = PageProtection::KERNEL_DATA;
+    map_page(
+        page.start_address(),
+        frame.start_address(),
+        Size4KiB::SIZE,
+        protection,
+        mapper,
+        frame_allocator,
+    )
+}
+
+/// Map `[virt, virt + size)` onto `[phys, phys + size)`.
+///
+/// Both addresses must be page aligned; `size` is rounded up to whole pages.
+/// Wherever both addresses are suitably aligned, 2 MiB pages (and 1 GiB
+/// pages if the CPU supports them) are used instead of 4 KiB pages. If a
+/// page cannot be mapped, the pages mapped so far are unmapped again.
+pub fn map_range(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    protection: PageProtection,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), PagingError> {
+    let size = page_count(virt, size)? * PAGE_SIZE;
+    if !phys.is_aligned(PAGE_SIZE) {
+        return Err(PagingError::Unaligned);
+    }
+    let allow_1gib = gigabyte_pages_supported();
 
-    let page = Page::containing_address(virt);
-    let frame = PhysFrame::containing_address(phys);
-    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    let mut mapped = 0;
+    while mapped < size {
+        let result = map_largest_page(
+            virt + mapped,
+            phys + mapped,
+            size - mapped,
+            allow_1gib,
+            protection,
+            mapper,
+            frame_allocator,
+        );
+        match result {
+            Ok(page_size) => mapped += page_size,
+            Err(e) => {
+                if mapped > 0 {
+                    let _ = unmap_range(virt, mapped, mapper);
+                }
+                return Err(e);
+            }
+        }
+    }
     Ok(())
 }
 
-/// Returns a mutable reference to the active level 4 page table.
+/// Back `[virt, virt + size)` with freshly allocated frames.
 ///
-/// # Safety
-/// The caller must ensure that the returned reference is unique and the offset is correct.
-unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
-    let (level_4_table_frame, _) = Cr3::read();
-    let phys = level_4_table_frame.start_address();
-    let virt = physical_memory_offset + phys.as_u64();
-    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
-    unsafe { &mut *page_table_ptr }
+/// 2 MiB pages are used where `virt` is 2 MiB aligned and the frame allocator
+/// has a contiguous, aligned run of frames; 4 KiB pages otherwise. On failure
+/// the error carries the number of bytes mapped before it, and that prefix
+/// stays mapped so the caller can keep or unmap it.
+pub fn map_fresh_range(
+    virt: VirtAddr,
+    size: u64,
+    protection: PageProtection,
+    mapper: &mut OffsetPageTable<'static>,
+) -> Result<(), (u64, PagingError)> {
+    let size = page_count(virt, size).map_err(|e| (0, e))? * PAGE_SIZE;
+    let mut frame_allocator = GlobalFrameAllocator;
+
+    let mut mapped = 0;
+    while mapped < size {
+        let page = virt + mapped;
+        if page.is_aligned(Size2MiB::SIZE)
+            && size - mapped >= Size2MiB::SIZE
+            && let Some(phys) = crate::alloc_frames(HUGE_FRAME_COUNT, Size2MiB::SIZE)
+        {
+            let phys = PhysAddr::new(phys);
+            match map_page(
+                page,
+                phys,
+                Size2MiB::SIZE,
+                protection,
+                mapper,
+                &mut frame_allocator,
+            ) {
+                Ok(()) => {
+                    mapped += Size2MiB::SIZE;
+                    continue;
+                }
+                // E.g. the 2 MiB slot is already split into 4 KiB pages;
+                // fill it page by page instead.
+                Err(_) => {
+                    let _ = crate::free_frames(phys.as_u64(), HUGE_FRAME_COUNT);
+                }
+            }
+        }
+
+        let phys = crate::alloc_frame().ok_or((mapped, PagingError::FrameAllocationFailed))?;
+        let phys = PhysAddr::new(phys);
+        if let Err(e) = map_page(
+            page,
+            phys,
+            Size4KiB::SIZE,
+            protection,
+            mapper,
+            &mut frame_allocator,
+        ) {
+            let _ = crate::free_frame(phys.as_u64());
+            return Err((mapped, e));
+        }
+        mapped += Size4KiB::SIZE;
+    }
+    Ok(())
 }
 
-/// Frame allocator that hands out usable 4 KiB frames from the boot memory map.
-pub struct BootInfoFrameAllocator<I>
-where
-    I: Iterator<Item = MemRegion>,
-{
-    regions: I,
-    current: Option<MemRegion>,
-    next_addr: u64,
+/// Unmap the page containing `virt`, whatever its size.
+///
+/// Returns the physical start address, flags and size of the removed page.
+/// The frames themselves are not freed; that is up to their owner.
+pub fn unmap_page(
+    virt: VirtAddr,
+    mapper: &mut OffsetPageTable<'static>,
+) -> Result<Translation, PagingError> {
+    let translation = translate(virt, mapper)?;
+    let phys = match translation.page_size {
+        Size1GiB::SIZE => unmap_sized::<Size1GiB>(virt, mapper)?,
+        Size2MiB::SIZE => unmap_sized::<Size2MiB>(virt, mapper)?,
+        _ => unmap_sized::<Size4KiB>(virt, mapper)?,
+    };
+    Ok(Translation {
+        phys,
+        ..translation
+    })
 }
 
-impl<I: Iterator<Item = MemRegion>> BootInfoFrameAllocator<I> {
-    /// Create a new frame allocator from a memory region iterator.
-    pub fn new(regions: I) -> Self {
-        Self {
-            regions,
-            current: None,
-            next_addr: 0,
+/// Unmap every page in `[virt, virt + size)`.
+///
+/// Huge pages must lie entirely inside the range. Pages that are not mapped
+/// are skipped.
+pub fn unmap_range(
+    virt: VirtAddr,
+    size: u64,
+    mapper: &mut OffsetPageTable<'static>,
+) -> Result<(), PagingError> {
+    let size = page_count(virt, size)? * PAGE_SIZE;
+    let mut offset = 0;
+    while offset < size {
+        let addr = virt + offset;
+        match translate(addr, mapper) {
+            Ok(translation) => {
+                if !addr.is_aligned(translation.page_size) || size - offset < translation.page_size
+                {
+                    return Err(PagingError::ParentEntryHugePage);
+                }
+                unmap_page(addr, mapper)?;
+                offset += translation.page_size;
+            }
+            Err(PagingError::PageNotMapped) => offset += PAGE_SIZE,
+            Err(e) => return Err(e),
         }
     }
+    Ok(())
+}
 
-    fn next_usable_region(&mut self) -> Option<MemRegion> {
-        for mut region in self.regions.by_ref() {
-            if region.kind != MemRegionKind::Usable {
-                continue;
-            }
+/// Change the protection of every page in `[virt, virt + size)`.
+///
+/// All pages must already be mapped, and huge pages must lie entirely inside
+/// the range; this is checked before any entry is modified. Granting user
+/// access also marks the intermediate page tables user-accessible.
+pub fn protect_range(
+    virt: VirtAddr,
+    size: u64,
+    protection: PageProtection,
+    mapper: &mut OffsetPageTable<'static>,
+) -> Result<(), PagingError> {
+    let size = page_count(virt, size)? * PAGE_SIZE;
+    let mut offset = 0;
+    while offset < size {
+        let addr = virt + offset;
+        let translation = translate(addr, mapper)?;
+        if !addr.is_aligned(translation.page_size) || size - offset < translation.page_size {
+            return Err(PagingError::ParentEntryHugePage);
+        }
+        offset += translation.page_size;
+    }
 
-            let start = align_up(region.start, PAGE_SIZE);
-            let end = align_down(region.end, PAGE_SIZE);
-            if start >= end {
-                continue;
+    let flags = protection.flags();
+    let mut offset = 0;
+    while offset < size {
+        let addr = virt + offset;
+        if protection.user {
+            add_table_flags(mapper, addr, protection.table_flags())?;
+        }
+        let page_size = translate(addr, mapper)?.page_size;
+        unsafe {
+            match page_size {
+                Size1GiB::SIZE => mapper
+                    .update_flags(Page::<Size1GiB>::containing_address(addr), flags)?
+                    .flush(),
+                Size2MiB::SIZE => mapper
+                    .update_flags(Page::<Size2MiB>::containing_address(addr), flags)?
+                    .flush(),
+                _ => mapper
+                    .update_flags(Page::<Size4KiB>::containing_address(addr), flags)?
+                    .flush(),
             }
+        }
+        offset += page_size;
+    }
+    Ok(())
+}
+
+/// Look up the physical address and page flags that `virt` maps to.
+pub fn translate(
+    virt: VirtAddr,
+    mapper: &OffsetPageTable<'static>,
+) -> Result<Translation, PagingError> {
+    match mapper.translate(virt) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } => Ok(Translation {
+            phys: frame.start_address() + offset,
+            flags,
+            page_size: frame.size(),
+        }),
+        TranslateResult::NotMapped => Err(PagingError::PageNotMapped),
+        TranslateResult::InvalidFrameAddress(addr) => Err(PagingError::InvalidFrameAddress(addr)),
+    }
+}
 
-            region.start = start;
-            region.end = end;
-            return Some(region);
+/// Whether the CPU supports 1 GiB pages (CPUID 0x8000_0001, EDX bit 26).
+pub fn gigabyte_pages_supported() -> bool {
+    let max_extended = __cpuid(CPUID_EXTENDED_MAX).eax;
+    if max_extended < CPUID_EXTENDED_FEATURES {
+        return false;
+    }
+    __cpuid(CPUID_EXTENDED_FEATURES).edx & CPUID_PDPE1GB != 0
+}
+
+/// Map the largest page that fits at `virt`/`phys` and return its size.
+///
+/// A huge page whose slot is already split into smaller tables is retried
+/// with the next smaller size.
+fn map_largest_page(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    remaining: u64,
+    allow_1gib: bool,
+    protection: PageProtection,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<u64, PagingError> {
+    for page_size in [Size1GiB::SIZE, Size2MiB::SIZE] {
+        if (page_size == Size1GiB::SIZE && !allow_1gib)
+            || remaining < page_size
+            || !virt.is_aligned(page_size)
+            || !phys.is_aligned(page_size)
+        {
+            continue;
+        }
+        match map_page(virt, phys, page_size, protection, mapper, frame_allocator) {
+            Ok(()) => return Ok(page_size),
+            Err(PagingError::PageAlreadyMapped(_)) => continue,
+            Err(e) => return Err(e),
         }
-        None
     }
+    map_page(
+        virt,
+        phys,
+        Size4KiB::SIZE,
+        protection,
+        mapper,
+        frame_allocator,
+    )?;
+    Ok(Size4KiB::SIZE)
 }
 
-unsafe impl<I: Iterator<Item = MemRegion>> FrameAllocator<Size4KiB> for BootInfoFrameAllocator<I> {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        loop {
-            if let Some(region) = &self.current
-                && self.next_addr < region.end
-            {
-                let addr = self.next_addr;
-                self.next_addr += PAGE_SIZE;
-                let phys = PhysAddr::new(addr);
-                return Some(PhysFrame::containing_address(phys));
-            }
+/// Map one page of `page_size` bytes.
+fn map_page(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    page_size: u64,
+    protection: PageProtection,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), PagingError> {
+    match page_size {
+        Size1GiB::SIZE => map_sized::<Size1GiB>(virt, phys, protection, mapper, frame_allocator),
+        Size2MiB::SIZE => map_sized::<Size2MiB>(virt, phys, protection, mapper, frame_allocator),
+        _ => map_sized::<Size4KiB>(virt, phys, protection, mapper, frame_allocator),
+    }
+}
+
+fn map_sized<S: PageSize>(
+    virt: VirtAddr,
+    phys: PhysAddr,
+    protection: PageProtection,
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), PagingError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let page = Page::<S>::from_start_address(virt).map_err(|_| PagingError::Unaligned)?;
+    let frame = PhysFrame::<S>::from_start_address(phys).map_err(|_| PagingError::Unaligned)?;
+    unsafe {
+        mapper
+            .map_to_with_table_flags(
+                page,
+                frame,
+                protection.flags(),
+                protection.table_flags(),
+                frame_allocator,
+            )?
+            .flush()
+    };
+    Ok(())
+}
+
+fn unmap_sized<S: PageSize>(
+    virt: VirtAddr,
+    mapper: &mut OffsetPageTable<'static>,
+) -> Result<PhysAddr, PagingError>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let (frame, flush) = mapper.unmap(Page::<S>::containing_address(virt))?;
+    flush.flush();
+    Ok(frame.start_address())
+}
+
+/// Number of pages in `[virt, virt + size)`, checking alignment and bounds.
+fn page_count(virt: VirtAddr, size: u64) -> Result<u64, PagingError> {
+    if !virt.is_aligned(PAGE_SIZE) {
+        return Err(PagingError::Unaligned);
+    }
+    if size == 0 {
+        return Err(PagingError::InvalidRange);
+    }
+    let size = size
+        .checked_next_multiple_of(PAGE_SIZE)
+        .ok_or(PagingError::InvalidRange)?;
+    virt.as_u64()
+        .checked_add(size)
+        .ok_or(PagingError::InvalidRange)?;
+    Ok(size / PAGE_SIZE)
+}
 
-            let next = self.next_usable_region()?;
-            self.next_addr = next.start;
-            self.current = Some(next);
+/// OR `flags` into the page table entries above the page mapping `virt`.
+fn add_table_flags(
+    mapper: &mut OffsetPageTable<'static>,
+    virt: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<(), PagingError> {
+    let page = Page::<Size4KiB>::containing_address(virt);
+    let offset = mapper.phys_offset();
+    let mut table: &mut PageTable = mapper.level_4_table_mut();
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let entry = &mut table[index];
+        if entry.is_unused() {
+            return Err(PagingError::PageNotMapped);
+        }
+        // A huge page entry is the leaf itself; its flags are set by the caller.
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Ok(());
         }
+        entry.set_flags(entry.flags() | flags);
+        let next: *mut PageTable = (offset + entry.addr().as_u64()).as_mut_ptr();
+        table = unsafe { &mut *next };
     }
+    Ok(())
+}
+
+/// Whether the CPU honours the `NO_EXECUTE` page table bit.
+fn no_execute_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+/// Returns a mutable reference to the active level 4 page table.
+///
+/// # Safety
+/// The caller must ensure that the returned reference is unique and the offset is correct.
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+    unsafe { &mut *page_table_ptr }
 }
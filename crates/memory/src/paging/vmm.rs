@@ -0,0 +1,423 @@
+//! Kernel virtual memory manager.
+//!
+//! Hands out ranges of kernel virtual address space from a fixed window so
+//! callers no longer hard-code addresses. Each range is recorded as a
+//! reservation; ranges created by `allocate` also own their backing frames,
+//! which are returned to the frame allocator by `release`.
+use spin::Mutex;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{OffsetPageTable, PageSize, Size1GiB, Size2MiB},
+};
+
+use crate::{PAGE_SIZE, align_up};
+
+use super::{GlobalFrameAllocator, PageProtection, PagingError};
+
+/// First address of the window managed by the VMM (level 4 index 128).
+pub const KERNEL_VM_START: u64 = 0x_4000_0000_0000;
+/// End (exclusive) of the VMM window: the top of the lower canonical half.
+pub const KERNEL_VM_END: u64 = 0x_8000_0000_0000;
+/// Maximum number of simultaneous reservations.
+pub const MAX_VM_REGIONS: usize = 64;
+/// Unmapped space left below every kernel stack.
+pub const STACK_GUARD_SIZE: u64 = PAGE_SIZE;
+
+/// Bytes covered by one level 4 page table entry.
+const L4_ENTRY_SPAN: u64 = 1 << 39;
+const L4_INDEX_SHIFT: u32 = 39;
+
+/// Errors reported by the virtual memory manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmError {
+    /// No free virtual range of the requested size is left in the window.
+    OutOfVirtualSpace,
+    /// The reservation table is full.
+    TooManyRegions,
+    /// The requested range overlaps an existing reservation or leaves the window.
+    Overlap,
+    /// The address does not start a reservation.
+    NotReserved,
+    /// The size is zero.
+    EmptyRange,
+    /// Paging has not been initialized (`paging::init` was never called).
+    NoMapper,
+    /// Mapping or unmapping a page failed.
+    Paging(PagingError),
+}
+
+impl From<PagingError> for VmmError {
+    fn from(e: PagingError) -> Self {
+        VmmError::Paging(e)
+    }
+}
+
+/// What backs a reservation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmBacking {
+    /// Address space only; the owner maps pages itself (e.g. the heap).
+    Reserved,
+    /// Frames allocated by the VMM and freed again on release.
+    Owned,
+    /// Caller-provided physical range (e.g. MMIO); frames are not freed.
+    Physical,
+    /// Kernel stack: owned frames above an unmapped guard page.
+    Stack,
+}
+
+impl VmBacking {
+    /// Whether the frames behind the region are freed on release.
+    fn owns_frames(self) -> bool {
+        matches!(self, VmBacking::Owned | VmBacking::Stack)
+    }
+}
+
+/// A kernel stack created by `allocate_stack`.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelStack {
+    pub name: &'static str,
+    /// Lowest mapped address; the guard page lies directly below it.
+    pub bottom: VirtAddr,
+    /// Initial stack pointer (one past the highest mapped byte).
+    pub top: VirtAddr,
+}
+
+/// One reserved kernel virtual range `[start, end)`.
+#[derive(Debug, Clone, Copy)]
+pub struct VmRegion {
+    pub start: u64,
+    pub end: u64,
+    pub name: &'static str,
+    pub backing: VmBacking,
+    pub protection: PageProtection,
+}
+
+impl VmRegion {
+    const EMPTY: VmRegion = VmRegion {
+        start: 0,
+        end: 0,
+        name: "",
+        backing: VmBacking::Reserved,
+        protection: PageProtection::KERNEL_DATA,
+    };
+
+    /// Size of the range in bytes.
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+/// Address-ordered table of reservations inside `[window_start, window_end)`.
+pub struct VirtualMemoryManager {
+    regions: [VmRegion; MAX_VM_REGIONS],
+    len: usize,
+    window_start: u64,
+    window_end: u64,
+}
+
+impl VirtualMemoryManager {
+    /// Create a manager for `[window_start, window_end)` with no reservations.
+    pub const fn new(window_start: u64, window_end: u64) -> Self {
+        Self {
+            regions: [VmRegion::EMPTY; MAX_VM_REGIONS],
+            len: 0,
+            window_start,
+            window_end,
+        }
+    }
+
+    /// Reservations in address order.
+    pub fn regions(&self) -> &[VmRegion] {
+        &self.regions[..self.len]
+    }
+
+    /// Reserve the fixed range `[start, start + size)`.
+    pub fn reserve_at(&mut self, region: VmRegion) -> Result<(), VmmError> {
+        if region.start >= region.end {
+            return Err(VmmError::EmptyRange);
+        }
+        if region.start < self.window_start || region.end > self.window_end {
+            return Err(VmmError::Overlap);
+        }
+        if self.len == MAX_VM_REGIONS {
+            return Err(VmmError::TooManyRegions);
+        }
+
+        let index = self.regions().partition_point(|r| r.end <= region.start);
+        if let Some(next) = self.regions().get(index)
+            && next.start < region.end
+        {
+            return Err(VmmError::Overlap);
+        }
+
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = region;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Reserve the lowest free range of `size` bytes (rounded up to pages).
+    ///
+    /// Ranges of at least 2 MiB (1 GiB) start on a 2 MiB (1 GiB) boundary so
+    /// they can be mapped with huge pages.
+    pub fn reserve_any(
+        &mut self,
+        size: u64,
+        name: &'static str,
+        backing: VmBacking,
+        protection: PageProtection,
+    ) -> Result<VirtAddr, VmmError> {
+        if size == 0 {
+            return Err(VmmError::EmptyRange);
+        }
+        let size = align_up(size, PAGE_SIZE);
+        let align = reservation_align(size);
+
+        let mut candidate = align_up(self.window_start, align);
+        for region in self.regions() {
+            if region.start.saturating_sub(candidate) >= size {
+                break;
+            }
+            candidate = candidate.max(align_up(region.end, align));
+        }
+        let end = candidate
+            .checked_add(size)
+            .filter(|end| *end <= self.window_end)
+            .ok_or(VmmError::OutOfVirtualSpace)?;
+
+        self.reserve_at(VmRegion {
+            start: candidate,
+            end,
+            name,
+            backing,
+            protection,
+        })?;
+        Ok(VirtAddr::new(candidate))
+    }
+
+    /// Remove the reservation starting at `start` and return it.
+    pub fn remove(&mut self, start: VirtAddr) -> Result<VmRegion, VmmError> {
+        let start = start.as_u64();
+        let index = self
+            .regions()
+            .iter()
+            .position(|r| r.start == start)
+            .ok_or(VmmError::NotReserved)?;
+        let region = self.regions[index];
+        self.regions.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+        Ok(region)
+    }
+
+    /// The reservation containing `addr`, if any.
+    pub fn find(&self, addr: VirtAddr) -> Option<VmRegion> {
+        let addr = addr.as_u64();
+        self.regions()
+            .iter()
+            .find(|r| r.start <= addr && addr < r.end)
+            .copied()
+    }
+
+    /// Reserve every level 4 slot in the window that is already populated,
+    /// e.g. by bootloader mappings, so it is never handed out again.
+    fn reserve_populated_slots(&mut self, mapper: &mut OffsetPageTable<'static>) {
+        let first = (self.window_start >> L4_INDEX_SHIFT) as usize;
+        let last = (self.window_end >> L4_INDEX_SHIFT) as usize;
+        for index in first..last {
+            if mapper.level_4_table()[index].is_unused() {
+                continue;
+            }
+            let start = (index as u64) << L4_INDEX_SHIFT;
+            let _ = self.reserve_at(VmRegion {
+                start,
+                end: start + L4_ENTRY_SPAN,
+                name: "boot",
+                backing: VmBacking::Reserved,
+                protection: PageProtection::KERNEL_DATA,
+            });
+        }
+    }
+}
+
+static KERNEL_VMM: Mutex<VirtualMemoryManager> =
+    Mutex::new(VirtualMemoryManager::new(KERNEL_VM_START, KERNEL_VM_END));
+
+/// Run `f` with the kernel mapper locked (see `paging::with_mapper`).
+///
+/// Callers reserve and release ranges inside `f`, so the mapper is always
+/// locked before the VMM.
+fn with_mapper<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>) -> Result<R, VmmError>,
+) -> Result<R, VmmError> {
+    super::with_mapper(f).unwrap_or(Err(VmmError::NoMapper))
+}
+
+/// Set up the kernel VMM for the active page table. Called by `paging::init`.
+pub(super) fn init(mapper: &mut OffsetPageTable<'static>) {
+    KERNEL_VMM.lock().reserve_populated_slots(mapper);
+}
+
+/// Reserve the fixed range `[start, start + size)` without mapping it.
+pub fn reserve(start: VirtAddr, size: u64, name: &'static str) -> Result<(), VmmError> {
+    KERNEL_VMM.lock().reserve_at(VmRegion {
+        start: start.as_u64(),
+        end: start.as_u64().saturating_add(align_up(size, PAGE_SIZE)),
+        name,
+        backing: VmBacking::Reserved,
+        protection: PageProtection::KERNEL_DATA,
+    })
+}
+
+/// Reserve a fresh range of `size` bytes without mapping it.
+///
+/// The owner maps pages inside the range itself, e.g. the heap as it grows.
+pub fn reserve_range(size: u64, name: &'static str) -> Result<VirtAddr, VmmError> {
+    KERNEL_VMM
+        .lock()
+        .reserve_any(size, name, VmBacking::Reserved, PageProtection::KERNEL_DATA)
+}
+
+/// Reserve a fresh range and back it with newly allocated frames.
+///
+/// The frames are freed by `release`.
+pub fn allocate(
+    size: u64,
+    protection: PageProtection,
+    name: &'static str,
+) -> Result<VirtAddr, VmmError> {
+    with_mapper(|mapper| {
+        let start = KERNEL_VMM
+            .lock()
+            .reserve_any(size, name, VmBacking::Owned, protection)?;
+
+        if let Err((mapped, e)) = super::map_fresh_range(start, size, protection, mapper) {
+            unmap_pages(mapper, start, mapped, VmBacking::Owned);
+            let _ = KERNEL_VMM.lock().remove(start);
+            return Err(e.into());
+        }
+
+        Ok(start)
+    })
+}
+
+/// Reserve a fresh range and map it onto `[phys, phys + size)`.
+///
+/// `phys` must be page aligned. The physical frames stay owned by the caller.
+pub fn map_physical(
+    phys: PhysAddr,
+    size: u64,
+    protection: PageProtection,
+    name: &'static str,
+) -> Result<VirtAddr, VmmError> {
+    with_mapper(|mapper| {
+        let start = KERNEL_VMM
+            .lock()
+            .reserve_any(size, name, VmBacking::Physical, protection)?;
+        let mut frame_allocator = GlobalFrameAllocator;
+
+        if let Err(e) =
+            super::map_range(start, phys, size, protection, mapper, &mut frame_allocator)
+        {
+            let _ = KERNEL_VMM.lock().remove(start);
+            return Err(e.into());
+        }
+
+        Ok(start)
+    })
+}
+
+/// Allocate a kernel stack of `size` bytes with an unmapped guard page below.
+///
+/// Running off the bottom of the stack faults on the guard page, which
+/// `stack_guard_hit` recognizes.
+pub fn allocate_stack(size: u64, name: &'static str) -> Result<KernelStack, VmmError> {
+    if size == 0 {
+        return Err(VmmError::EmptyRange);
+    }
+    let size = align_up(size, PAGE_SIZE);
+    with_mapper(|mapper| {
+        let start = KERNEL_VMM.lock().reserve_any(
+            STACK_GUARD_SIZE + size,
+            name,
+            VmBacking::Stack,
+            PageProtection::KERNEL_DATA,
+        )?;
+        let bottom = start + STACK_GUARD_SIZE;
+
+        if let Err((mapped, e)) =
+            super::map_fresh_range(bottom, size, PageProtection::KERNEL_DATA, mapper)
+        {
+            unmap_pages(mapper, bottom, mapped, VmBacking::Stack);
+            let _ = KERNEL_VMM.lock().remove(start);
+            return Err(e.into());
+        }
+
+        Ok(KernelStack {
+            name,
+            bottom,
+            top: bottom + size,
+        })
+    })
+}
+
+/// Name of the kernel stack whose guard page contains `addr`, if any.
+///
+/// Meant for fault handlers: the VMM lock is only tried, so this returns
+/// `None` instead of deadlocking if the fault hit while it was held.
+pub fn stack_guard_hit(addr: VirtAddr) -> Option<&'static str> {
+    let region = KERNEL_VMM.try_lock()?.find(addr)?;
+    let guard_end = region.start + STACK_GUARD_SIZE;
+    (region.backing == VmBacking::Stack && addr.as_u64() < guard_end).then_some(region.name)
+}
+
+/// Unmap the reservation starting at `start` and drop it.
+///
+/// Frames are returned to the frame allocator when the VMM allocated them.
+pub fn release(start: VirtAddr) -> Result<(), VmmError> {
+    with_mapper(|mapper| {
+        let region = KERNEL_VMM.lock().remove(start)?;
+        if region.backing != VmBacking::Reserved {
+            unmap_pages(mapper, start, region.size(), region.backing);
+        }
+        Ok(())
+    })
+}
+
+/// The reservation containing `addr`, if any.
+pub fn find_region(addr: VirtAddr) -> Option<VmRegion> {
+    KERNEL_VMM.lock().find(addr)
+}
+
+/// Alignment for a reservation of `size` bytes: the largest page size that
+/// fits into it.
+fn reservation_align(size: u64) -> u64 {
+    if size >= Size1GiB::SIZE {
+        Size1GiB::SIZE
+    } else if size >= Size2MiB::SIZE {
+        Size2MiB::SIZE
+    } else {
+        PAGE_SIZE
+    }
+}
+
+/// Unmap the pages in the first `size` bytes at `start`, freeing owned frames.
+fn unmap_pages(
+    mapper: &mut OffsetPageTable<'static>,
+    start: VirtAddr,
+    size: u64,
+    backing: VmBacking,
+) {
+    let mut offset = 0;
+    while offset < size {
+        match super::unmap_page(start + offset, mapper) {
+            Ok(page) => {
+                if backing.owns_frames() {
+                    let frames = (page.page_size / PAGE_SIZE) as usize;
+                    let _ = crate::free_frames(page.phys.as_u64(), frames);
+                }
+                offset += page.page_size;
+            }
+            Err(_) => offset += PAGE_SIZE,
+        }
+    }
+}
@@ -3,6 +3,8 @@ use std::path::PathBuf;
 use anyhow::Context;
 use bootloader::BiosBoot;
 
+mod symbols;
+
 fn main() -> anyhow::Result<()> {
     // このバイナリはビルド済みカーネルELFからBIOS起動用のディスクイメージを生成する。
     // 以降ではプロジェクトルートを起点にパスを組み立てる。
@@ -32,9 +34,21 @@ fn main() -> anyhow::Result<()> {
     //   既存のファイルがあれば上書きされる。
     let out_path: PathBuf = root.join("target").join("bios.img");
 
-    // ③ BIOS用ディスクイメージを作成
+    // ③ バックトレース用のシンボル表を生成
+    //   ramdiskとしてカーネルに渡し、arch::symbols が読み込む。
+    let symbols_path: PathBuf = root.join("target").join("kernel.sym");
+    let symbol_count = symbols::write_symbol_table(&kernel_path, &symbols_path)
+        .context("failed to generate kernel symbol table")?;
+    println!(
+        "Wrote {} symbols to {}",
+        symbol_count,
+        symbols_path.display()
+    );
+
+    // ④ BIOS用ディスクイメージを作成
     //   bootloaderクレートがBIOSブート可能な形式に変換してくれる。
     BiosBoot::new(&kernel_path)
+        .set_ramdisk(&symbols_path)
         .create_disk_image(&out_path)
         .with_context(|| format!("failed to create disk image at {}", out_path.display()))?;
 
@@ -0,0 +1,253 @@
+//! カーネルELFのシンボルテーブルから、クラッシュ時のバックトレース用の
+//! シンボル表（`arch::symbols` が読む形式）を生成する。
+//!
+//! 形式（リトルエンディアン）:
+//! - ヘッダ: magic `BSYM`, version (u32), シンボル数 (u32), 予約 (u32)
+//! - エントリ: { address: u64, size: u64, name_offset: u32, name_len: u32 } をアドレス順に
+//! - 名前の文字列（オフセットはエントリ配列の末尾から数える）
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, bail};
+
+const MAGIC: &[u8; 4] = b"BSYM";
+const VERSION: u32 = 1;
+
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+const SYMBOL_ENTRY_SIZE: usize = 24;
+
+struct Symbol {
+    addr: u64,
+    size: u64,
+    name: String,
+}
+
+/// `kernel` のシンボル表を `out` に書き出し、シンボル数を返す。
+pub fn write_symbol_table(kernel: &Path, out: &Path) -> anyhow::Result<usize> {
+    let elf: Vec<u8> =
+        fs::read(kernel).with_context(|| format!("failed to read {}", kernel.display()))?;
+    let mut symbols: Vec<Symbol> = read_functions(&elf)?;
+    symbols.sort_by_key(|symbol| symbol.addr);
+    symbols.dedup_by_key(|symbol| symbol.addr);
+
+    let mut entries: Vec<u8> = Vec::new();
+    let mut names: Vec<u8> = Vec::new();
+    for symbol in &symbols {
+        entries.extend_from_slice(&symbol.addr.to_le_bytes());
+        entries.extend_from_slice(&symbol.size.to_le_bytes());
+        entries.extend_from_slice(&(names.len() as u32).to_le_bytes());
+        entries.extend_from_slice(&(symbol.name.len() as u32).to_le_bytes());
+        names.extend_from_slice(symbol.name.as_bytes());
+    }
+
+    let mut table: Vec<u8> = Vec::new();
+    table.extend_from_slice(MAGIC);
+    table.extend_from_slice(&VERSION.to_le_bytes());
+    table.extend_from_slice(&(symbols.len() as u32).to_le_bytes());
+    table.extend_from_slice(&0u32.to_le_bytes());
+    table.extend_from_slice(&entries);
+    table.extend_from_slice(&names);
+    fs::write(out, table).with_context(|| format!("failed to write {}", out.display()))?;
+
+    Ok(symbols.len())
+}
+
+/// ELF64 の `.symtab` から関数シンボルを取り出す。
+fn read_functions(elf: &[u8]) -> anyhow::Result<Vec<Symbol>> {
+    if elf.get(..4) != Some(b"\x7fELF".as_slice()) || elf.get(4) != Some(&2) {
+        bail!("kernel is not an ELF64 file");
+    }
+    let section_offset = read_u64(elf, 0x28)? as usize;
+    let section_size = read_u16(elf, 0x3a)? as usize;
+    let section_count = read_u16(elf, 0x3c)? as usize;
+
+    let section = |index: usize| -> anyhow::Result<(u32, usize, usize, usize)> {
+        let header = section_offset + index * section_size;
+        Ok((
+            read_u32(elf, header + 0x04)?,
+            read_u64(elf, header + 0x18)? as usize,
+            read_u64(elf, header + 0x20)? as usize,
+            read_u32(elf, header + 0x28)? as usize,
+        ))
+    };
+
+    let mut symbols: Vec<Symbol> = Vec::new();
+    for index in 0..section_count {
+        let (kind, offset, size, link) = section(index)?;
+        if kind != SHT_SYMTAB {
+            continue;
+        }
+        // sh_link は対応する文字列テーブルのセクション番号
+        let (_, strtab_offset, strtab_size, _) = section(link)?;
+        let strtab = elf
+            .get(strtab_offset..strtab_offset + strtab_size)
+            .context("string table out of bounds")?;
+
+        for entry in (offset..offset + size).step_by(SYMBOL_ENTRY_SIZE) {
+            let info = *elf.get(entry + 4).context("symbol out of bounds")?;
+            let addr = read_u64(elf, entry + 8)?;
+            if info & 0xf != STT_FUNC || addr == 0 {
+                continue;
+            }
+            let name_offset = read_u32(elf, entry)? as usize;
+            let raw_name = strtab
+                .get(name_offset..)
+                .and_then(|rest| rest.split(|&b| b == 0).next())
+                .context("symbol name out of bounds")?;
+            symbols.push(Symbol {
+                addr,
+                size: read_u64(elf, entry + 16)?,
+                name: demangle(&String::from_utf8_lossy(raw_name)),
+            });
+        }
+    }
+    Ok(symbols)
+}
+
+/// Rust の legacy マングリング（`_ZN...E`）を `a::b::c` 形式に戻す。
+/// 末尾のハッシュ（`h` + 16桁）は落とす。それ以外の名前はそのまま返す。
+fn demangle(name: &str) -> String {
+    // LTO などで付く `.llvm.1234` は名前の一部ではない
+    let mangled = name.split(".llvm.").next().unwrap_or(name);
+    let Some(mut rest) = mangled
+        .strip_prefix("_ZN")
+        .and_then(|rest| rest.strip_suffix('E'))
+    else {
+        return name.to_string();
+    };
+
+    let mut parts: Vec<String> = Vec::new();
+    while !rest.is_empty() {
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let Ok(len) = rest[..digits].parse::<usize>() else {
+            return name.to_string();
+        };
+        let Some(part) = rest.get(digits..digits + len) else {
+            return name.to_string();
+        };
+        rest = &rest[digits + len..];
+        let is_hash = rest.is_empty()
+            && part.len() == 17
+            && part.starts_with('h')
+            && part[1..].chars().all(|c| c.is_ascii_hexdigit());
+        if !is_hash {
+            parts.push(unescape(part));
+        }
+    }
+    parts.join("::")
+}
+
+/// legacy マングリングのエスケープ（`$LT$` など）を元の文字に戻す。
+fn unescape(part: &str) -> String {
+    // `$` で始まる要素には先頭に `_` が付けられている
+    let part = part
+        .strip_prefix('_')
+        .filter(|rest| rest.starts_with('$'))
+        .unwrap_or(part);
+    let mut out = String::new();
+    let mut rest = part;
+    while let Some(c) = rest.chars().next() {
+        if c == '$'
+            && let Some(end) = rest[1..].find('$')
+        {
+            let escape = &rest[1..end + 1];
+            let decoded = match escape {
+                "SP" => Some('@'),
+                "BP" => Some('*'),
+                "RF" => Some('&'),
+                "LT" => Some('<'),
+                "GT" => Some('>'),
+                "LP" => Some('('),
+                "RP" => Some(')'),
+                "C" => Some(','),
+                _ => escape
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32),
+            };
+            if let Some(decoded) = decoded {
+                out.push(decoded);
+                rest = &rest[end + 2..];
+                continue;
+            }
+        }
+        if rest.starts_with("..") {
+            out.push_str("::");
+            rest = &rest[2..];
+            continue;
+        }
+        out.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    out
+}
+
+fn read_u16(data: &[u8], offset: usize) -> anyhow::Result<u16> {
+    let bytes = data.get(offset..offset + 2).context("ELF truncated")?;
+    Ok(u16::from_le_bytes(bytes.try_into()?))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    let bytes = data.get(offset..offset + 4).context("ELF truncated")?;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> anyhow::Result<u64> {
+    let bytes = data.get(offset..offset + 8).context("ELF truncated")?;
+    Ok(u64::from_le_bytes(bytes.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::demangle;
+
+    #[test]
+    fn strips_the_hash() {
+        assert_eq!(
+            demangle("_ZN4core9panicking5panic17h0123456789abcdefE"),
+            "core::panicking::panic"
+        );
+    }
+
+    #[test]
+    fn keeps_a_hash_like_element_that_is_not_last() {
+        assert_eq!(
+            demangle("_ZN17h0123456789abcdef3fooE"),
+            "h0123456789abcdef::foo"
+        );
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(
+            demangle(
+                "_ZN48_$LT$kernel..Foo$u20$as$u20$core..fmt..Debug$GT$3fmt17h00000000000000ffE"
+            ),
+            "<kernel::Foo as core::fmt::Debug>::fmt"
+        );
+        assert_eq!(
+            demangle(
+                "_ZN67_$LT$alloc..vec..Vec$LT$u8$GT$$u20$as$u20$core..ops..drop..Drop$GT$4drop17h1111111111111111E"
+            ),
+            "<alloc::vec::Vec<u8> as core::ops::drop::Drop>::drop"
+        );
+        assert_eq!(demangle("_ZN3foo16_$RF$$BP$$C$$SP$E"), "foo::&*,@");
+    }
+
+    #[test]
+    fn ignores_the_llvm_suffix() {
+        assert_eq!(
+            demangle("_ZN6kernel4main17habcdefabcdefabcdE.llvm.1234567"),
+            "kernel::main"
+        );
+    }
+
+    #[test]
+    fn leaves_other_names_alone() {
+        assert_eq!(demangle("_start"), "_start");
+        assert_eq!(demangle("_ZN3fooE_"), "_ZN3fooE_");
+        // A length that runs past the end is not a valid mangled name.
+        assert_eq!(demangle("_ZN9fooE"), "_ZN9fooE");
+    }
+}
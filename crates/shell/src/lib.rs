@@ -6,11 +6,15 @@ extern crate alloc;
 use alloc::vec::Vec;
 use console::console_trait::ConsoleOut;
 use core::arch::asm;
-use memory::MemRegion;
+use memory::{
+    MemRegion,
+    paging::{PageProtection, vmm},
+};
 use meta::VERSION;
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::PhysAddr;
 
 pub mod mem;
+pub mod platform;
 
 pub struct ShellCommands;
 impl ShellCommands {
@@ -22,7 +26,7 @@ impl ShellCommands {
     }
 }
 
-const MAP_TEST_VIRT: u64 = 0x_5555_5555_0000;
+const MAP_TEST_VALUE: u64 = 0x1122_3344_5566_7788;
 
 pub struct Shell<C: ConsoleOut + core::fmt::Write> {
     regions: Vec<MemRegion>,
@@ -92,79 +96,150 @@ impl<C: ConsoleOut + core::fmt::Write> Shell<C> {
     }
 
     fn execute_line(&mut self) {
-        let bytes: &[u8] = &self.input_buffer[..self.length];
-        if let Ok(line) = str::from_utf8(bytes) {
-            match line {
-                "hello" => {
-                    writeln!(self.console, "welcome to BeyondOS\n").unwrap();
-                }
-                "help" => {
-                    writeln!(self.console, "Show Help\n").unwrap();
-                    writeln!(self.console, "hello: to greet to OS").unwrap();
-                    writeln!(self.console, "version: to show version of Beyond OS").unwrap();
-                    writeln!(self.console, "mem: to show memory map").unwrap();
-                    writeln!(
-                        self.console,
-                        "alloctest(at): to test allocator and show next adderess"
-                    )
-                    .unwrap();
-                    writeln!(
-                        self.console,
-                        "maptest(mt): map one page and write a test value"
-                    )
-                    .unwrap();
-                }
-                "version" => {
-                    writeln!(self.console, "{}", VERSION).unwrap();
-                }
-                "mem" => {
-                    mem::show_memory_map(&mut self.console, self.regions.iter().copied());
+        // Copy the line out so commands may use `self` mutably.
+        let buffer: [u8; 128] = self.input_buffer;
+        if let Ok(line) = str::from_utf8(&buffer[..self.length]) {
+            self.execute(line);
+        }
+    }
+
+    fn execute(&mut self, line: &str) {
+        if let Some(command) = line.strip_prefix("bench ") {
+            let start: u64 = platform::clock_ns();
+            self.execute(command);
+            platform::show_elapsed(&mut self.console, command, platform::clock_ns() - start);
+            return;
+        }
+        match line {
+            "hello" => {
+                writeln!(self.console, "welcome to BeyondOS\n").unwrap();
+            }
+            "help" => {
+                writeln!(self.console, "Show Help\n").unwrap();
+                writeln!(self.console, "hello: to greet to OS").unwrap();
+                writeln!(self.console, "version: to show version of Beyond OS").unwrap();
+                writeln!(self.console, "mem: to show memory map").unwrap();
+                writeln!(self.console, "meminfo: to show heap and frame usage").unwrap();
+                writeln!(
+                    self.console,
+                    "alloctest(at): to allocate and free one frame and show its address"
+                )
+                .unwrap();
+                writeln!(
+                    self.console,
+                    "maptest(mt): map one frame at a fresh address and write a test value"
+                )
+                .unwrap();
+                writeln!(self.console, "acpi: to list ACPI tables").unwrap();
+                writeln!(self.console, "lsblk: to list block devices").unwrap();
+                writeln!(self.console, "uptime: to show time since boot").unwrap();
+                writeln!(self.console, "date: to show the current date and time").unwrap();
+                writeln!(self.console, "bench <command>: to time a command").unwrap();
+                writeln!(self.console, "shutdown: to power off").unwrap();
+                writeln!(self.console, "reboot: to restart").unwrap();
+                writeln!(
+                    self.console,
+                    "qemu-exit: to quit QEMU through isa-debug-exit"
+                )
+                .unwrap();
+            }
+            "version" => {
+                writeln!(self.console, "{}", VERSION).unwrap();
+            }
+            "mem" => {
+                mem::show_memory_map(&mut self.console, self.regions.iter().copied());
+            }
+            "meminfo" => {
+                mem::show_memory_usage(&mut self.console);
+            }
+            "acpi" => {
+                platform::show_acpi_tables(&mut self.console);
+            }
+            "lsblk" => {
+                platform::show_block_devices(&mut self.console);
+            }
+            "uptime" => {
+                platform::show_uptime(&mut self.console);
+            }
+            "date" => {
+                platform::show_date(&mut self.console);
+            }
+            "shutdown" => {
+                writeln!(self.console, "shutting down...").unwrap();
+                platform::shutdown();
+            }
+            "reboot" => {
+                writeln!(self.console, "rebooting...").unwrap();
+                platform::reboot();
+            }
+            "qemu-exit" => {
+                platform::qemu_exit();
+                writeln!(self.console, "qemu-exit: isa-debug-exit device not present").unwrap();
+            }
+            "alloctest" | "at" => match mem::alloc_frame() {
+                Some(addr) => {
+                    writeln!(self.console, "0x{:016x}", addr).unwrap();
+                    if let Err(e) = mem::free_frame(addr) {
+                        writeln!(self.console, "alloctest: free_frame failed: {:?}", e).unwrap();
+                    }
                 }
-                "alloctest" | "at" => {
-                    let addr = mem::alloc_frame();
-                    writeln!(self.console, "{}", addr.unwrap()).unwrap();
+                None => {
+                    writeln!(self.console, "alloctest: alloc_frame failed").unwrap();
                 }
-                "maptest" | "mt" => {
-                    let phys = match mem::alloc_frame() {
-                        Some(addr) => addr,
-                        None => {
-                            writeln!(self.console, "maptest: alloc_frame failed").unwrap();
-                            return;
-                        }
-                    };
-                    let mut mapper =
-                        unsafe { memory::paging::init(VirtAddr::new(self.phys_offset)) };
-                    let mut frame_allocator = memory::paging::GlobalFrameAllocator;
-                    match memory::paging::map_one_page(
-                        VirtAddr::new(MAP_TEST_VIRT),
-                        PhysAddr::new(phys),
-                        &mut mapper,
-                        &mut frame_allocator,
-                    ) {
-                        Ok(()) => {
-                            unsafe {
-                                core::ptr::write_volatile(
-                                    MAP_TEST_VIRT as *mut u64,
-                                    0x1122_3344_5566_7788,
-                                );
-                            }
+            },
+            "maptest" | "mt" => {
+                let phys = match mem::alloc_frame() {
+                    Some(addr) => addr,
+                    None => {
+                        writeln!(self.console, "maptest: alloc_frame failed").unwrap();
+                        return;
+                    }
+                };
+                match vmm::map_physical(
+                    PhysAddr::new(phys),
+                    memory::PAGE_SIZE,
+                    PageProtection::KERNEL_DATA,
+                    "maptest",
+                ) {
+                    Ok(virt) => {
+                        // Read the value back through the physical memory window to
+                        // check that the new mapping really points at the frame.
+                        let readback = unsafe {
+                            core::ptr::write_volatile(virt.as_mut_ptr::<u64>(), MAP_TEST_VALUE);
+                            core::ptr::read_volatile((self.phys_offset + phys) as *const u64)
+                        };
+                        if readback == MAP_TEST_VALUE {
                             writeln!(
                                 self.console,
                                 "maptest ok virt=0x{:016x} phys=0x{:016x}",
-                                MAP_TEST_VIRT, phys
+                                virt.as_u64(),
+                                phys
+                            )
+                            .unwrap();
+                        } else {
+                            writeln!(
+                                self.console,
+                                "maptest failed: readback mismatch virt=0x{:016x} phys=0x{:016x} read=0x{:016x}",
+                                virt.as_u64(),
+                                phys,
+                                readback
                             )
                             .unwrap();
                         }
-                        Err(e) => {
-                            writeln!(self.console, "maptest failed: {:?}", e).unwrap();
+                        if let Err(e) = vmm::release(virt) {
+                            writeln!(self.console, "maptest: release failed: {:?}", e).unwrap();
                         }
                     }
+                    Err(e) => {
+                        writeln!(self.console, "maptest failed: {:?}", e).unwrap();
+                    }
                 }
-                "mkdir" => {}
-                _ => {
-                    writeln!(self.console, "unknown command: {}", line).unwrap();
-                }
+                let _ = mem::free_frame(phys);
+            }
+            "mkdir" => {}
+            _ => {
+                writeln!(self.console, "unknown command: {}", line).unwrap();
             }
-        };
+        }
     }
 }
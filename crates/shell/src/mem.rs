@@ -1,6 +1,6 @@
 use console::console_trait::ConsoleOut;
 use core::fmt::Write;
-use memory::MemRegion;
+use memory::{FrameError, MemRegion};
 
 pub fn show_memory_map<C, I>(console: &mut C, regions: I)
 where
@@ -10,6 +10,17 @@ where
     memory::dump_memory_map(regions, console);
 }
 
+pub fn show_memory_usage<C>(console: &mut C)
+where
+    C: ConsoleOut + Write,
+{
+    memory::dump_memory_usage(console);
+}
+
 pub fn alloc_frame() -> Option<u64> {
     memory::alloc_frame()
 }
+
+pub fn free_frame(addr: u64) -> Result<(), FrameError> {
+    memory::free_frame(addr)
+}
@@ -0,0 +1,76 @@
+use arch::power::{self, QemuExitCode};
+use arch::{clock, rtc, timer};
+use console::console_trait::ConsoleOut;
+use core::fmt::Write;
+
+pub fn show_acpi_tables<C>(console: &mut C)
+where
+    C: ConsoleOut + Write,
+{
+    acpi::dump_tables(console);
+}
+
+pub fn show_block_devices<C>(console: &mut C)
+where
+    C: ConsoleOut + Write,
+{
+    block::dump_devices(console);
+}
+
+pub fn shutdown() -> ! {
+    power::shutdown()
+}
+
+pub fn reboot() -> ! {
+    power::reboot()
+}
+
+/// Leave QEMU with a success status; returns if not running under QEMU
+/// with `isa-debug-exit`.
+pub fn qemu_exit() {
+    power::qemu_exit(QemuExitCode::Success);
+}
+
+pub fn show_uptime<C>(console: &mut C)
+where
+    C: ConsoleOut + Write,
+{
+    let ms: u64 = timer::uptime_ms();
+    let seconds: u64 = ms / 1000;
+    writeln!(
+        console,
+        "up {}:{:02}:{:02}.{:03} ({} ticks at {} Hz)",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        ms % 1000,
+        timer::ticks(),
+        timer::TICK_HZ
+    )
+    .ok();
+}
+
+pub fn show_date<C>(console: &mut C)
+where
+    C: ConsoleOut + Write,
+{
+    writeln!(console, "{} UTC", rtc::now()).ok();
+}
+
+pub fn clock_ns() -> u64 {
+    clock::now_ns()
+}
+
+pub fn show_elapsed<C>(console: &mut C, command: &str, elapsed_ns: u64)
+where
+    C: ConsoleOut + Write,
+{
+    writeln!(
+        console,
+        "bench: {} took {}.{:03} us",
+        command,
+        elapsed_ns / 1000,
+        elapsed_ns % 1000
+    )
+    .ok();
+}